@@ -0,0 +1,37 @@
+//! Answers `/echo text` with the text, and `/ping` with `pong`.
+//!
+//!     cargo run -p rschat-bot --example echo
+//!
+//! Connects to `RSCHAT_SERVER`, `127.0.0.1:3333` by default, with a new
+//! identity each run.
+
+use std::env;
+use std::process::exit;
+
+use rschat_bot::{Bot, Context};
+
+#[tokio::main]
+async fn main() {
+    let addr = env::var("RSCHAT_SERVER")
+        .unwrap_or_else(|_| "127.0.0.1:3333".to_string());
+
+    let bot = Bot::builder()
+        .name("echo")
+        .command("echo", "repeat the text", |ctx: Context| async move {
+            ctx.reply(&ctx.args).await?;
+            Ok(())
+        })
+        .command("ping", "check the bot is up", |ctx: Context| async move {
+            ctx.reply("pong").await?;
+            Ok(())
+        })
+        .connect(&addr)
+        .await
+        .unwrap_or_else(|err| {
+            eprintln!("{addr}: {err}");
+            exit(1);
+        });
+
+    println!("echo bot is {}", bot.client().public_key());
+    bot.run().await;
+}
@@ -0,0 +1,178 @@
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use rschat_client::{
+    Client, ClientBuilder, Error, Event, Events, KeyPair, Message, Protocol,
+};
+use tracing::{info, warn};
+
+use crate::command;
+
+type Reply = Pin<Box<dyn Future<Output = Result<(), Error>> + Send>>;
+type Handler = Arc<dyn Fn(Context) -> Reply + Send + Sync>;
+
+struct Command {
+    description: String,
+    handler: Handler,
+}
+
+/// A command sent to the bot, handed to its handler.
+pub struct Context {
+    pub client: Client,
+    /// The message carrying the command.
+    pub message: Message,
+    pub command: String,
+    /// The rest of the message after the command name, trimmed.
+    pub args: String,
+}
+
+impl Context {
+    /// Answer where the command came from: the sender alone for a direct
+    /// message, the group chat otherwise. Returns the message id.
+    pub async fn reply(&self, text: &str) -> Result<String, Error> {
+        if self.message.direct {
+            self.client.send_direct(&self.message.sender, text).await
+        } else {
+            self.client.send_group(text).await
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct BotBuilder {
+    client: ClientBuilder,
+    commands: BTreeMap<String, Command>,
+}
+
+impl BotBuilder {
+    /// Identity to log in with, a new one if unset.
+    pub fn keys(mut self, keys: KeyPair) -> Self {
+        self.client = self.client.keys(keys);
+        self
+    }
+
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.client = self.client.name(name);
+        self
+    }
+
+    pub fn device_id(mut self, device_id: impl Into<String>) -> Self {
+        self.client = self.client.device_id(device_id);
+        self
+    }
+
+    pub fn protocol(mut self, protocol: Protocol) -> Self {
+        self.client = self.client.protocol(protocol);
+        self
+    }
+
+    /// Run `handler` for `/name args`. `/help` lists the commands with
+    /// their `description`, unless a `help` command is registered.
+    pub fn command<F, Fut>(
+        mut self,
+        name: impl Into<String>,
+        description: impl Into<String>,
+        handler: F,
+    ) -> Self
+    where
+        F: Fn(Context) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), Error>> + Send + 'static,
+    {
+        let handler: Handler = Arc::new(move |ctx| Box::pin(handler(ctx)));
+        self.commands.insert(
+            name.into(),
+            Command {
+                description: description.into(),
+                handler,
+            },
+        );
+        self
+    }
+
+    /// Connect to the server at `addr` and log in. Commands are handled
+    /// once the bot runs.
+    pub async fn connect(self, addr: &str) -> Result<Bot, Error> {
+        let (client, events) = self.client.connect(addr).await?;
+        Ok(Bot {
+            client,
+            events,
+            commands: Arc::new(self.commands),
+        })
+    }
+}
+
+/// A logged in bot.
+pub struct Bot {
+    client: Client,
+    events: Events,
+    commands: Arc<BTreeMap<String, Command>>,
+}
+
+impl Bot {
+    pub fn builder() -> BotBuilder {
+        BotBuilder::default()
+    }
+
+    /// The bot's connection, to post without being asked, e.g. deploy
+    /// notices.
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    /// Handle commands until the connection closes. Each runs in a task
+    /// of its own, so a slow handler doesn't hold up the others.
+    pub async fn run(mut self) {
+        while let Some(event) = self.events.next().await {
+            match event {
+                Event::Message(message) => self.dispatch(message),
+                Event::Error { request, message } => {
+                    warn!(%request, %message, "request refused");
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn dispatch(&self, message: Message) {
+        let Some((name, args)) = command::parse(&message.text) else {
+            return;
+        };
+        let ctx = Context {
+            client: self.client.clone(),
+            command: name.to_string(),
+            args: args.to_string(),
+            message,
+        };
+        info!(command = %ctx.command, sender = %ctx.message.sender, "command");
+
+        let commands = self.commands.clone();
+        tokio::spawn(async move {
+            let command = ctx.command.clone();
+            let handled = match commands.get(&command) {
+                Some(registered) => (registered.handler)(ctx).await,
+                None if command == "help" => help(&ctx, &commands).await,
+                None => ctx
+                    .reply(&format!("Unknown command /{command}, try /help."))
+                    .await
+                    .map(drop),
+            };
+            if let Err(err) = handled {
+                warn!(%command, %err, "command failed");
+            }
+        });
+    }
+}
+
+async fn help(
+    ctx: &Context,
+    commands: &BTreeMap<String, Command>,
+) -> Result<(), Error> {
+    let mut lines: Vec<String> = commands
+        .iter()
+        .map(|(name, command)| format!("/{name}: {}", command.description))
+        .collect();
+    lines.push("/help: list the commands".to_string());
+    ctx.reply(&lines.join("\n")).await.map(drop)
+}
@@ -0,0 +1,29 @@
+/// Split `/name args` into the command name and its arguments, trimmed.
+/// Returns `None` for text that isn't a command.
+pub fn parse(text: &str) -> Option<(&str, &str)> {
+    let text = text.trim_start().strip_prefix('/')?;
+    let (name, args) =
+        text.split_once(char::is_whitespace).unwrap_or((text, ""));
+    if name.is_empty() {
+        return None;
+    }
+    Some((name, args.trim()))
+}
+
+#[cfg(test)]
+mod command_tests {
+    use crate::command::parse;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            parse("/echo hello  world "),
+            Some(("echo", "hello  world"))
+        );
+        assert_eq!(parse("  /ping"), Some(("ping", "")));
+        assert_eq!(parse("/deploy\tapi"), Some(("deploy", "api")));
+        assert_eq!(parse("/"), None);
+        assert_eq!(parse("/ echo"), None);
+        assert_eq!(parse("echo /x"), None);
+    }
+}
@@ -0,0 +1,11 @@
+//! Bots for the chat: log in with a keypair, and answer `/command args`
+//! messages with async handlers. Replies are encrypted back to the sender,
+//! or to the group chat if the command was sent there.
+
+mod bot;
+mod command;
+
+pub use rschat_client::{Client, Error, KeyPair, Message, Protocol};
+
+pub use crate::bot::{Bot, BotBuilder, Context};
+pub use crate::command::parse;
@@ -0,0 +1,149 @@
+//! Bots driven by clients, each test against its own in-process server.
+
+use std::time::Duration;
+
+use rschat_bot::{Bot, Context, Error};
+use rschat_client::{Client, Event, Events, Message};
+use tokio::net::TcpListener;
+use tokio::time::timeout;
+use wetsocks::Server;
+
+async fn start() -> (Server, String) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let server = Server::builder().build();
+    let serving = server.clone();
+    tokio::spawn(async move { serving.serve(listener).await });
+    (server, addr)
+}
+
+/// Start an echo bot, returning its public key.
+async fn echo_bot(addr: &str) -> String {
+    let bot = Bot::builder()
+        .name("echo")
+        .command("echo", "repeat the text", |ctx: Context| async move {
+            ctx.reply(&ctx.args).await?;
+            Ok(())
+        })
+        .command("fail", "always fails", |_| async { Err(Error::Closed) })
+        .connect(addr)
+        .await
+        .unwrap();
+    let public_key = bot.client().public_key();
+    tokio::spawn(bot.run());
+    public_key
+}
+
+async fn connect(addr: &str, name: &str) -> (Client, Events) {
+    Client::builder().name(name).connect(addr).await.unwrap()
+}
+
+async fn message(events: &mut Events) -> Message {
+    timeout(Duration::from_secs(5), async {
+        loop {
+            match events.next().await.expect("connection closed") {
+                Event::Message(msg) => return msg,
+                _ => continue,
+            }
+        }
+    })
+    .await
+    .expect("timed out waiting for a message")
+}
+
+async fn joined(events: &mut Events, public_key: &str) {
+    timeout(Duration::from_secs(5), async {
+        loop {
+            match events.next().await.expect("connection closed") {
+                Event::UserJoined(peer) if peer.public_key == public_key => {
+                    return;
+                }
+                _ => continue,
+            }
+        }
+    })
+    .await
+    .expect("timed out waiting for the user")
+}
+
+#[tokio::test]
+async fn test_direct_command() {
+    let (_server, addr) = start().await;
+    let bot = echo_bot(&addr).await;
+    let (alice, mut alice_events) = connect(&addr, "alice").await;
+
+    alice.send_direct(&bot, "/echo  hello bot ").await.unwrap();
+    let reply = message(&mut alice_events).await;
+    assert_eq!(reply.sender, bot);
+    assert_eq!(reply.text, "hello bot");
+    assert!(reply.direct);
+
+    // Plain text isn't a command and gets no answer.
+    alice.send_direct(&bot, "hello").await.unwrap();
+    alice.send_direct(&bot, "/nope").await.unwrap();
+    let reply = message(&mut alice_events).await;
+    assert_eq!(reply.text, "Unknown command /nope, try /help.");
+}
+
+#[tokio::test]
+async fn test_group_command() {
+    let (_server, addr) = start().await;
+    let bot = echo_bot(&addr).await;
+    let (alice, mut alice_events) = connect(&addr, "alice").await;
+    let (_bob, mut bob_events) = connect(&addr, "bob").await;
+    joined(&mut alice_events, &bot).await;
+    joined(&mut bob_events, &bot).await;
+
+    alice.send_group("/echo hi all").await.unwrap();
+
+    // Bob gets Alice's message, then the bot's reply.
+    assert_eq!(message(&mut bob_events).await.text, "/echo hi all");
+    for events in [&mut alice_events, &mut bob_events] {
+        let reply = message(events).await;
+        assert_eq!(reply.sender, bot);
+        assert_eq!(reply.text, "hi all");
+        assert!(!reply.direct);
+    }
+}
+
+#[tokio::test]
+async fn test_help_and_failing_command() {
+    let (_server, addr) = start().await;
+    let bot = echo_bot(&addr).await;
+    let (alice, mut alice_events) = connect(&addr, "alice").await;
+
+    // A failing handler leaves the bot running.
+    alice.send_direct(&bot, "/fail").await.unwrap();
+    alice.send_direct(&bot, "/help").await.unwrap();
+    let reply = message(&mut alice_events).await;
+    assert_eq!(
+        reply.text,
+        "/echo: repeat the text\n/fail: always fails\n/help: list the commands"
+    );
+}
+
+#[tokio::test]
+async fn test_unprompted_post() {
+    let (_server, addr) = start().await;
+    let (_alice, mut alice_events) = connect(&addr, "alice").await;
+
+    let bot = Bot::builder().name("deploys").connect(&addr).await.unwrap();
+    let client = bot.client().clone();
+    let public_key = client.public_key();
+    tokio::spawn(bot.run());
+
+    // The bot learns of Alice when it logs in.
+    joined(&mut alice_events, &public_key).await;
+    timeout(Duration::from_secs(5), async {
+        while client.roster().is_empty() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+
+    client.send_group("api deployed").await.unwrap();
+    let notice = message(&mut alice_events).await;
+    assert_eq!(notice.sender, public_key);
+    assert_eq!(notice.text, "api deployed");
+}
@@ -0,0 +1,544 @@
+use std::collections::HashMap;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex as SyncMutex};
+use std::task::{Context, Poll};
+
+use bytes::BytesMut;
+use futures_core::Stream;
+use rschat_crypto::{Ciphertext, KeyPair, PublicKey};
+use rschat_protocol::ws::frame;
+use rschat_protocol::ws::message::{Message as WsMessage, Reader};
+use rschat_protocol::{
+    Outbound, Payload, PresenceStatus, Protocol, User, now_millis,
+};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::sync::{Mutex, mpsc, oneshot};
+use tracing::warn;
+
+use crate::{Error, handshake};
+
+/// Largest frame and message accepted from the server.
+const MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+
+pub(crate) fn random_bytes<const N: usize>() -> [u8; N] {
+    let mut bytes = [0u8; N];
+    getrandom::getrandom(&mut bytes).expect("no randomness available");
+    bytes
+}
+
+/// Another user online, as announced by `new_user`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Peer {
+    pub public_key: String,
+    pub name: String,
+    pub handle: Option<String>,
+    pub status: PresenceStatus,
+}
+
+impl From<User> for Peer {
+    fn from(user: User) -> Self {
+        Peer {
+            public_key: user.id,
+            name: user.name,
+            handle: user.handle,
+            status: user.status,
+        }
+    }
+}
+
+/// A message for us, decrypted.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Message {
+    /// Public key of the sender.
+    pub sender: String,
+    pub text: String,
+    /// Sent to us alone, rather than to the group chat.
+    pub direct: bool,
+    /// Came through `send_sealed`, without the server learning the sender.
+    pub sealed: bool,
+    pub message_id: String,
+    pub received_at: u64,
+    pub sent_at: Option<u64>,
+}
+
+pub enum Event {
+    /// A user came online, or was online when we logged in.
+    UserJoined(Peer),
+    UserLeft {
+        public_key: String,
+    },
+    Message(Message),
+    /// A request of kind `request` was refused.
+    Error {
+        request: String,
+        message: String,
+    },
+    /// Anything else the server sent, including messages we couldn't
+    /// decrypt.
+    Other(Payload),
+}
+
+/// What the server sends, in order. Ends once the connection closed.
+pub struct Events {
+    rx: mpsc::UnboundedReceiver<Event>,
+}
+
+impl Events {
+    pub async fn next(&mut self) -> Option<Event> {
+        self.rx.recv().await
+    }
+}
+
+impl Stream for Events {
+    type Item = Event;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Event>> {
+        self.rx.poll_recv(cx)
+    }
+}
+
+pub struct ClientBuilder {
+    keys: Option<KeyPair>,
+    name: String,
+    device_id: Option<String>,
+    protocol: Protocol,
+}
+
+impl Default for ClientBuilder {
+    fn default() -> Self {
+        ClientBuilder {
+            keys: None,
+            name: String::new(),
+            device_id: None,
+            protocol: Protocol::LEGACY,
+        }
+    }
+}
+
+impl ClientBuilder {
+    /// Identity to log in with, a new one if unset.
+    pub fn keys(mut self, keys: KeyPair) -> Self {
+        self.keys = Some(keys);
+        self
+    }
+
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = name.into();
+        self
+    }
+
+    /// Id of this device, a random one if unset. Logging in again with the
+    /// same id replaces the older connection.
+    pub fn device_id(mut self, device_id: impl Into<String>) -> Self {
+        self.device_id = Some(device_id.into());
+        self
+    }
+
+    pub fn protocol(mut self, protocol: Protocol) -> Self {
+        self.protocol = protocol;
+        self
+    }
+
+    /// Connect to the server at `addr`, e.g. `127.0.0.1:3333`, and log in.
+    /// Returns once the server accepted `first`.
+    pub async fn connect(self, addr: &str) -> Result<(Client, Events), Error> {
+        let mut stream = TcpStream::connect(addr).await?;
+        let buf = handshake::open(&mut stream, addr, self.protocol).await?;
+        let (read, write) = stream.into_split();
+
+        let inner = Arc::new(Inner {
+            addr: addr.to_string(),
+            keys: self.keys.unwrap_or_else(KeyPair::generate),
+            name: self.name,
+            device_id: self
+                .device_id
+                .unwrap_or_else(|| hex::encode(random_bytes::<16>())),
+            protocol: self.protocol,
+            writer: Mutex::new(write),
+            closed: AtomicBool::new(false),
+            roster: SyncMutex::default(),
+        });
+
+        let (tx, rx) = mpsc::unbounded_channel();
+        let (ready_tx, ready) = oneshot::channel();
+        tokio::spawn(read_loop(inner.clone(), read, buf, tx, ready_tx));
+
+        ready.await.map_err(|_| Error::Closed)??;
+
+        Ok((Client { inner }, Events { rx }))
+    }
+}
+
+struct Inner {
+    addr: String,
+    keys: KeyPair,
+    name: String,
+    device_id: String,
+    protocol: Protocol,
+    writer: Mutex<OwnedWriteHalf>,
+    closed: AtomicBool,
+    /// Users online, keyed by public key.
+    roster: SyncMutex<HashMap<String, Peer>>,
+}
+
+/// A masked frame, as clients must send them.
+fn masked(opcode: u8, data: &[u8]) -> BytesMut {
+    let mut buf = BytesMut::with_capacity(data.len() + 14);
+    frame::set_masked(&mut buf, opcode, data, random_bytes());
+    buf
+}
+
+/// `payload` as a frame in `protocol`'s encoding.
+fn encode(protocol: Protocol, payload: &Payload) -> Result<BytesMut, Error> {
+    let value = serde_json::to_value(payload).map_err(io::Error::from)?;
+    Ok(match protocol.encode(&value) {
+        Outbound::Text(text) => masked(frame::OPCODE_TEXT, text.as_bytes()),
+        Outbound::Binary(data) => masked(frame::OPCODE_BINARY, &data),
+        _ => unreachable!("payloads encode to text or binary frames"),
+    })
+}
+
+impl Inner {
+    async fn write(&self, buf: &[u8]) -> Result<(), Error> {
+        if self.closed.load(Ordering::Relaxed) {
+            return Err(Error::Closed);
+        }
+
+        let mut writer = self.writer.lock().await;
+        writer.write_all(buf).await?;
+        Ok(())
+    }
+
+    async fn send(&self, payload: &Payload) -> Result<(), Error> {
+        self.write(&encode(self.protocol, payload)?).await
+    }
+
+    async fn close(&self, code: u16) -> Result<(), Error> {
+        self.write(&masked(frame::OPCODE_CLOSE, &code.to_be_bytes()))
+            .await?;
+        self.closed.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn roster(&self) -> std::sync::MutexGuard<'_, HashMap<String, Peer>> {
+        self.roster.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// A logged in connection. Clones share it.
+#[derive(Clone)]
+pub struct Client {
+    inner: Arc<Inner>,
+}
+
+impl Client {
+    pub fn builder() -> ClientBuilder {
+        ClientBuilder::default()
+    }
+
+    pub fn keys(&self) -> &KeyPair {
+        &self.inner.keys
+    }
+
+    /// Our public key as hex, our user id on the server.
+    pub fn public_key(&self) -> String {
+        self.inner.keys.public_key().to_hex()
+    }
+
+    pub fn device_id(&self) -> &str {
+        &self.inner.device_id
+    }
+
+    /// Users online we may message, sorted by name.
+    pub fn roster(&self) -> Vec<Peer> {
+        let mut peers: Vec<Peer> =
+            self.inner.roster().values().cloned().collect();
+        peers.sort_by(|a, b| {
+            a.name.cmp(&b.name).then(a.public_key.cmp(&b.public_key))
+        });
+        peers
+    }
+
+    pub fn peer(&self, public_key: &str) -> Option<Peer> {
+        self.inner.roster().get(public_key).cloned()
+    }
+
+    /// Send a request as is.
+    pub async fn send(&self, payload: &Payload) -> Result<(), Error> {
+        self.inner.send(payload).await
+    }
+
+    /// Send `text` to `recipient` alone. Returns the message id.
+    pub async fn send_direct(
+        &self,
+        recipient: &str,
+        text: &str,
+    ) -> Result<String, Error> {
+        let message_id = hex::encode(random_bytes::<16>());
+        self.send_encrypted(recipient, Some(recipient), text, &message_id)
+            .await?;
+        Ok(message_id)
+    }
+
+    /// Send `text` to the group chat, a copy encrypted for every user in
+    /// the roster. Returns the message id, the same for every copy.
+    pub async fn send_group(&self, text: &str) -> Result<String, Error> {
+        let message_id = hex::encode(random_bytes::<16>());
+        for peer in self.roster() {
+            self.send_encrypted(&peer.public_key, None, text, &message_id)
+                .await?;
+        }
+        Ok(message_id)
+    }
+
+    /// Send `text` to `recipient` without the server learning who sent
+    /// it: sealed with our key inside and sent over a connection of its
+    /// own that never logs in, with the `access_key` the recipient handed
+    /// us. Returns the message id.
+    pub async fn send_sealed(
+        &self,
+        recipient: &str,
+        access_key: &str,
+        text: &str,
+    ) -> Result<String, Error> {
+        let key = PublicKey::from_hex(recipient)?;
+        let sealed = rschat_crypto::seal(text, &self.inner.keys, &key)?;
+        let message_id = hex::encode(random_bytes::<16>());
+
+        let addr = &self.inner.addr;
+        let mut stream = TcpStream::connect(addr).await?;
+        handshake::open(&mut stream, addr, self.inner.protocol).await?;
+
+        let mut buf = encode(
+            self.inner.protocol,
+            &Payload::SendSealed {
+                recipient: recipient.to_string(),
+                device_id: None,
+                delivery_token: access_key.to_string(),
+                payload: sealed.to_hex(),
+                message_id: Some(message_id.clone()),
+            },
+        )?;
+        buf.extend_from_slice(&masked(
+            frame::OPCODE_CLOSE,
+            &frame::CLOSE_NORMAL.to_be_bytes(),
+        ));
+        stream.write_all(&buf).await?;
+
+        // The server hangs up once it handled the request.
+        let mut rest = Vec::new();
+        stream.read_to_end(&mut rest).await?;
+        Ok(message_id)
+    }
+
+    async fn send_encrypted(
+        &self,
+        recipient: &str,
+        group_id: Option<&str>,
+        text: &str,
+        message_id: &str,
+    ) -> Result<(), Error> {
+        let key = PublicKey::from_hex(recipient)?;
+        let ciphertext = rschat_crypto::encrypt(text.as_bytes(), &key)?;
+
+        self.send(&Payload::SendMessage {
+            recipient: recipient.to_string(),
+            device_id: None,
+            payload: ciphertext.to_hex(),
+            group_id: group_id.map(str::to_string),
+            sent_at: Some(now_millis()),
+            message_id: Some(message_id.to_string()),
+        })
+        .await
+    }
+
+    /// Say goodbye to the server. `Events` ends once it answered.
+    pub async fn close(&self) -> Result<(), Error> {
+        self.inner.close(frame::CLOSE_NORMAL).await
+    }
+}
+
+async fn read_loop(
+    inner: Arc<Inner>,
+    mut read: OwnedReadHalf,
+    mut buf: BytesMut,
+    tx: mpsc::UnboundedSender<Event>,
+    ready: oneshot::Sender<Result<(), Error>>,
+) {
+    let mut reader = Reader::new(MAX_MESSAGE_SIZE, MAX_MESSAGE_SIZE);
+    let mut ready = Some(ready);
+
+    loop {
+        loop {
+            let data = match reader.next(&mut buf) {
+                Ok(Some(WsMessage::Text(text))) => text.into_bytes(),
+                Ok(Some(WsMessage::Binary(data))) => data,
+                Ok(Some(WsMessage::Ping(data))) => {
+                    let _ =
+                        inner.write(&masked(frame::OPCODE_PONG, &data)).await;
+                    continue;
+                }
+                Ok(Some(WsMessage::Pong)) => continue,
+                Ok(Some(WsMessage::Close(_))) => {
+                    let _ = inner.close(frame::CLOSE_NORMAL).await;
+                    return;
+                }
+                Ok(None) => break,
+                Err(err) => {
+                    warn!(?err, "bad frame from server");
+                    let _ = inner.close(err.close_code()).await;
+                    return;
+                }
+            };
+
+            let payload = match inner.protocol.decode(&data) {
+                Ok(payload) => payload,
+                Err(err) => {
+                    warn!(%err, "invalid payload from server");
+                    continue;
+                }
+            };
+
+            if let Some(event) = handle(&inner, payload, &mut ready).await {
+                let _ = tx.send(event);
+            }
+        }
+
+        match read.read_buf(&mut buf).await {
+            Ok(0) | Err(_) => return,
+            Ok(_) => {}
+        }
+    }
+}
+
+/// Update the roster and log in as `payload` says. Returns the event to
+/// pass on, if any.
+async fn handle(
+    inner: &Inner,
+    payload: Payload,
+    ready: &mut Option<oneshot::Sender<Result<(), Error>>>,
+) -> Option<Event> {
+    let event = match payload {
+        Payload::Challenge { nonce } => {
+            let public_key = inner.keys.public_key().to_hex();
+            let signed = format!("rschat:first:{nonce}:{public_key}");
+            let first = Payload::First {
+                signature: inner.keys.sign(signed.as_bytes()).to_hex(),
+                public_key,
+                name: inner.name.clone(),
+                device_id: Some(inner.device_id.clone()),
+                device_key: None,
+            };
+            if let Err(err) = inner.send(&first).await
+                && let Some(ready) = ready.take()
+            {
+                let _ = ready.send(Err(err));
+            }
+            return None;
+        }
+        Payload::Session { .. } => {
+            if let Some(ready) = ready.take() {
+                let _ = ready.send(Ok(()));
+            }
+            return None;
+        }
+        Payload::Error { request, message } => {
+            if request == "first"
+                && let Some(ready) = ready.take()
+            {
+                let _ = ready.send(Err(Error::Refused(message)));
+                return None;
+            }
+            Event::Error { request, message }
+        }
+        Payload::NewUser { user } => {
+            let peer = Peer::from(user);
+            inner.roster().insert(peer.public_key.clone(), peer.clone());
+            Event::UserJoined(peer)
+        }
+        Payload::UserLeft { user_id } => {
+            inner.roster().remove(&user_id);
+            Event::UserLeft {
+                public_key: user_id,
+            }
+        }
+        Payload::Presence {
+            ref user_id,
+            status,
+            ..
+        } => {
+            if let Some(peer) = inner.roster().get_mut(user_id) {
+                peer.status = status;
+            }
+            Event::Other(payload)
+        }
+        Payload::Handle {
+            ref user_id,
+            ref handle,
+        } => {
+            if let Some(peer) = inner.roster().get_mut(user_id) {
+                peer.handle = handle.clone();
+            }
+            Event::Other(payload)
+        }
+        Payload::RelayMessage {
+            ref sender,
+            payload: ref ciphertext,
+            ref group_id,
+            ref envelope,
+        } => match decrypt(&inner.keys, ciphertext) {
+            Some(text) => Event::Message(Message {
+                sender: sender.clone(),
+                text,
+                direct: group_id.as_deref()
+                    == Some(inner.keys.public_key().to_hex().as_str()),
+                sealed: false,
+                message_id: envelope.message_id.clone(),
+                received_at: envelope.received_at,
+                sent_at: envelope.sent_at,
+            }),
+            None => Event::Other(payload),
+        },
+        Payload::SealedMessage {
+            payload: ref ciphertext,
+            ref envelope,
+        } => {
+            let unsealed = Ciphertext::from_hex(ciphertext)
+                .and_then(|c| inner.keys.unseal(&c));
+            match unsealed {
+                Ok(unsealed) => Event::Message(Message {
+                    sender: unsealed.sender.to_hex(),
+                    text: unsealed.message,
+                    direct: true,
+                    sealed: true,
+                    message_id: envelope.message_id.clone(),
+                    received_at: envelope.received_at,
+                    sent_at: envelope.sent_at,
+                }),
+                Err(err) => {
+                    warn!(%err, "can't unseal message");
+                    Event::Other(payload)
+                }
+            }
+        }
+        payload => Event::Other(payload),
+    };
+
+    Some(event)
+}
+
+fn decrypt(keys: &KeyPair, ciphertext: &str) -> Option<String> {
+    let plaintext = Ciphertext::from_hex(ciphertext)
+        .and_then(|c| keys.decrypt(&c))
+        .inspect_err(|err| warn!(%err, "can't decrypt message"))
+        .ok()?;
+
+    String::from_utf8(plaintext).ok()
+}
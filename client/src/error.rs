@@ -0,0 +1,47 @@
+use std::{fmt, io};
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    /// The server didn't switch to WebSocket, with the reason.
+    Handshake(String),
+    /// The server refused `first`, with its message.
+    Refused(String),
+    /// The connection is closed.
+    Closed,
+    Crypto(rschat_crypto::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "{err}"),
+            Error::Handshake(reason) => write!(f, "handshake failed: {reason}"),
+            Error::Refused(message) => write!(f, "login refused: {message}"),
+            Error::Closed => f.write_str("connection closed"),
+            Error::Crypto(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            Error::Crypto(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+impl From<rschat_crypto::Error> for Error {
+    fn from(err: rschat_crypto::Error) -> Self {
+        Error::Crypto(err)
+    }
+}
@@ -0,0 +1,73 @@
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as B64;
+use bytes::BytesMut;
+use rschat_protocol::Protocol;
+use rschat_protocol::ws::accept_key;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::Error;
+use crate::client::random_bytes;
+
+/// Longest response header we wait for.
+const MAX_RESPONSE_LEN: usize = 8192;
+
+/// Upgrade `stream` to a WebSocket speaking `protocol`. Returns what the
+/// server sent after its response, the start of the first frames.
+pub async fn open(
+    stream: &mut TcpStream,
+    host: &str,
+    protocol: Protocol,
+) -> Result<BytesMut, Error> {
+    let key = B64.encode(random_bytes::<16>());
+    let request = format!(
+        "GET /ws HTTP/1.1\r\n\
+         Host: {host}\r\n\
+         Upgrade: websocket\r\n\
+         Connection: Upgrade\r\n\
+         Sec-WebSocket-Key: {key}\r\n\
+         Sec-WebSocket-Version: 13\r\n\
+         Sec-WebSocket-Protocol: {}\r\n\
+         \r\n",
+        protocol.name()
+    );
+    stream.write_all(request.as_bytes()).await?;
+
+    let mut buf = BytesMut::with_capacity(4096);
+    let end = loop {
+        if let Some(i) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break i + 4;
+        }
+        if buf.len() > MAX_RESPONSE_LEN {
+            return Err(Error::Handshake("response too long".to_string()));
+        }
+        if stream.read_buf(&mut buf).await? == 0 {
+            return Err(Error::Closed);
+        }
+    };
+
+    let head = buf.split_to(end);
+    let head = String::from_utf8_lossy(&head);
+    let mut lines = head.split("\r\n");
+
+    let status = lines.next().unwrap_or_default();
+    if status.split(' ').nth(1) != Some("101") {
+        return Err(Error::Handshake(status.to_string()));
+    }
+
+    let header = |name: &str| {
+        lines.clone().find_map(|line| {
+            let (k, v) = line.split_once(':')?;
+            k.trim().eq_ignore_ascii_case(name).then(|| v.trim())
+        })
+    };
+
+    if header("Sec-WebSocket-Accept") != Some(accept_key(&key).as_str()) {
+        return Err(Error::Handshake("bad Sec-WebSocket-Accept".to_string()));
+    }
+    if header("Sec-WebSocket-Protocol") != Some(protocol.name()) {
+        return Err(Error::Handshake("subprotocol not accepted".to_string()));
+    }
+
+    Ok(buf)
+}
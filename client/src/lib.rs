@@ -0,0 +1,15 @@
+//! Native client for the chat protocol: connects to `/ws`, logs in with
+//! `first`, keeps the roster and encrypts messages the way the web client
+//! does. Bots use it, and so do the server's integration tests.
+
+mod client;
+mod error;
+mod handshake;
+
+pub use rschat_crypto::KeyPair;
+pub use rschat_protocol::{
+    Encoding, Payload, PresenceStatus, Protocol, Version,
+};
+
+pub use crate::client::{Client, ClientBuilder, Event, Events, Message, Peer};
+pub use crate::error::Error;
@@ -0,0 +1,281 @@
+//! The server driven through the client, each test against its own
+//! in-process instance.
+
+use std::time::Duration;
+
+use rschat_client::{
+    Client, Encoding, Error, Event, Events, Message, Payload, Protocol, Version,
+};
+use tokio::net::TcpListener;
+use tokio::time::timeout;
+use wetsocks::Server;
+
+async fn start() -> (Server, String) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let server = Server::builder().build();
+    let serving = server.clone();
+    tokio::spawn(async move { serving.serve(listener).await });
+    (server, addr)
+}
+
+async fn connect(addr: &str, name: &str) -> (Client, Events) {
+    Client::builder().name(name).connect(addr).await.unwrap()
+}
+
+/// Skip events until `f` picks one.
+async fn until<T>(
+    events: &mut Events,
+    mut f: impl FnMut(Event) -> Option<T>,
+) -> T {
+    timeout(Duration::from_secs(5), async {
+        loop {
+            let event = events.next().await.expect("connection closed");
+            if let Some(found) = f(event) {
+                return found;
+            }
+        }
+    })
+    .await
+    .expect("timed out waiting for event")
+}
+
+async fn joined(events: &mut Events, name: &str) -> String {
+    until(events, |e| match e {
+        Event::UserJoined(peer) if peer.name == name => Some(peer.public_key),
+        _ => None,
+    })
+    .await
+}
+
+async fn message(events: &mut Events) -> Message {
+    until(events, |e| match e {
+        Event::Message(msg) => Some(msg),
+        _ => None,
+    })
+    .await
+}
+
+#[tokio::test]
+async fn test_roster() {
+    let (_server, addr) = start().await;
+    let (alice, mut alice_events) = connect(&addr, "alice").await;
+    let (bob, mut bob_events) = connect(&addr, "bob").await;
+
+    assert_eq!(joined(&mut alice_events, "bob").await, bob.public_key());
+    assert_eq!(joined(&mut bob_events, "alice").await, alice.public_key());
+    assert_eq!(alice.roster().len(), 1);
+    assert_eq!(alice.peer(&bob.public_key()).unwrap().name, "bob");
+
+    bob.close().await.unwrap();
+    let left = until(&mut alice_events, |e| match e {
+        Event::UserLeft { public_key } => Some(public_key),
+        _ => None,
+    })
+    .await;
+    assert_eq!(left, bob.public_key());
+    assert!(alice.roster().is_empty());
+
+    // Bob's events end once the server answered the Close frame.
+    let drained = timeout(Duration::from_secs(5), async {
+        while bob_events.next().await.is_some() {}
+    });
+    assert!(drained.await.is_ok());
+    assert!(matches!(bob.send_group("hi").await, Err(Error::Closed)));
+}
+
+#[tokio::test]
+async fn test_direct_and_group_messages() {
+    let (_server, addr) = start().await;
+    let (alice, mut alice_events) = connect(&addr, "alice").await;
+    let (bob, mut bob_events) = connect(&addr, "bob").await;
+    let (_carol, mut carol_events) = connect(&addr, "carol").await;
+    joined(&mut alice_events, "bob").await;
+    joined(&mut alice_events, "carol").await;
+
+    let id = alice
+        .send_direct(&bob.public_key(), "hi bob")
+        .await
+        .unwrap();
+    let msg = message(&mut bob_events).await;
+    assert_eq!(msg.sender, alice.public_key());
+    assert_eq!(msg.text, "hi bob");
+    assert_eq!(msg.message_id, id);
+    assert!(msg.direct && !msg.sealed);
+
+    let id = alice.send_group("hi all").await.unwrap();
+    for events in [&mut bob_events, &mut carol_events] {
+        let msg = message(events).await;
+        assert_eq!(msg.text, "hi all");
+        assert_eq!(msg.message_id, id);
+        assert!(!msg.direct);
+    }
+}
+
+#[tokio::test]
+async fn test_messagepack_v2() {
+    let (_server, addr) = start().await;
+    let protocol = Protocol {
+        version: Version::V2,
+        encoding: Encoding::MessagePack,
+    };
+    let (alice, _alice_events) = Client::builder()
+        .name("alice")
+        .protocol(protocol)
+        .connect(&addr)
+        .await
+        .unwrap();
+    let (bob, mut bob_events) = connect(&addr, "bob").await;
+
+    alice
+        .send_direct(&bob.public_key(), "packed")
+        .await
+        .unwrap();
+    let msg = message(&mut bob_events).await;
+    assert_eq!(msg.text, "packed");
+    assert!(msg.sent_at.is_some());
+}
+
+#[tokio::test]
+async fn test_sealed_message() {
+    let (_server, addr) = start().await;
+    let (alice, mut alice_events) = connect(&addr, "alice").await;
+    let (bob, mut bob_events) = connect(&addr, "bob").await;
+
+    let access_key = rschat_crypto::generate_access_key();
+    bob.send(&Payload::SetAccessKey {
+        access_key_hash: Some(rschat_crypto::access_key_hash(&access_key)),
+    })
+    .await
+    .unwrap();
+    // Answered once the access key is registered, requests being handled
+    // in order.
+    bob.send(&Payload::LookupHandle {
+        handle: "nobody".to_string(),
+    })
+    .await
+    .unwrap();
+    until(&mut bob_events, |e| match e {
+        Event::Other(Payload::HandleInfo { .. }) => Some(()),
+        _ => None,
+    })
+    .await;
+
+    let message_id = alice
+        .send_sealed(&bob.public_key(), &access_key, "psst")
+        .await
+        .unwrap();
+
+    let msg = message(&mut bob_events).await;
+    assert_eq!(msg.sender, alice.public_key());
+    assert_eq!(msg.text, "psst");
+    assert_eq!(msg.message_id, message_id);
+    assert!(msg.direct && msg.sealed);
+
+    // Over her logged in connection it would give Alice away.
+    let recipient = bob.keys().public_key();
+    let sealed = rschat_crypto::seal("psst", alice.keys(), recipient).unwrap();
+    alice
+        .send(&Payload::SendSealed {
+            recipient: bob.public_key(),
+            device_id: None,
+            delivery_token: access_key,
+            payload: sealed.to_hex(),
+            message_id: None,
+        })
+        .await
+        .unwrap();
+    let request = until(&mut alice_events, |e| match e {
+        Event::Error { request, .. } => Some(request),
+        _ => None,
+    })
+    .await;
+    assert_eq!(request, "send_sealed");
+}
+
+async fn lookup(client: &Client, events: &mut Events, handle: &str) -> usize {
+    client
+        .send(&Payload::LookupHandle {
+            handle: handle.to_string(),
+        })
+        .await
+        .unwrap();
+    until(events, |e| match e {
+        Event::Other(Payload::HandleInfo { devices, .. }) => {
+            Some(devices.len())
+        }
+        _ => None,
+    })
+    .await
+}
+
+#[tokio::test]
+async fn test_lookup_hides_devices_from_blocked() {
+    let (_server, addr) = start().await;
+    let (alice, mut alice_events) = connect(&addr, "alice").await;
+    let (bob, mut bob_events) = connect(&addr, "bob").await;
+    joined(&mut alice_events, "bob").await;
+
+    let public_key = bob.public_key();
+    let now = rschat_protocol::now_millis() / 1000;
+    let message = format!("rschat:claim_handle:bob:{public_key}:{now}");
+    bob.send(&Payload::ClaimHandle {
+        handle: "bob".to_string(),
+        timestamp: now,
+        signature: bob.keys().sign(message.as_bytes()).to_hex(),
+    })
+    .await
+    .unwrap();
+    until(&mut bob_events, |e| match e {
+        Event::Other(Payload::Handle { .. }) => Some(()),
+        _ => None,
+    })
+    .await;
+    assert_eq!(lookup(&alice, &mut alice_events, "bob").await, 1);
+
+    bob.send(&Payload::SetBlocked {
+        user_id: alice.public_key(),
+        blocked: true,
+    })
+    .await
+    .unwrap();
+    until(&mut alice_events, |e| match e {
+        Event::UserLeft { .. } => Some(()),
+        _ => None,
+    })
+    .await;
+    assert_eq!(lookup(&alice, &mut alice_events, "bob").await, 0);
+}
+
+#[tokio::test]
+async fn test_refused_request() {
+    let (_server, addr) = start().await;
+    let (alice, mut alice_events) = connect(&addr, "alice").await;
+
+    alice
+        .send(&Payload::ClaimHandle {
+            handle: "alice".to_string(),
+            timestamp: 0,
+            signature: "00".to_string(),
+        })
+        .await
+        .unwrap();
+    let request = until(&mut alice_events, |e| match e {
+        Event::Error { request, .. } => Some(request),
+        _ => None,
+    })
+    .await;
+    assert_eq!(request, "claim_handle");
+}
+
+#[tokio::test]
+async fn test_server_shutdown_ends_events() {
+    let (server, addr) = start().await;
+    let (_alice, mut alice_events) = connect(&addr, "alice").await;
+
+    server.shutdown();
+    let drained = timeout(Duration::from_secs(5), async {
+        while alice_events.next().await.is_some() {}
+    });
+    assert!(drained.await.is_ok());
+}
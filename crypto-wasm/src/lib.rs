@@ -1,35 +1,33 @@
-use aes_gcm::{
-    Aes256Gcm, Nonce,
-    aead::{Aead, KeyInit},
-};
-use rand_core::OsRng;
-use secp256k1::{PublicKey, Secp256k1, SecretKey};
-use serde::Serialize;
-use serde_json;
-use sha2::{Digest, Sha256};
+//! The web client's bindings to `rschat-crypto`. Keys, ciphertexts and
+//! signatures cross the boundary as hex strings.
+
+use std::fmt::Display;
+
+use rschat_crypto::{Ciphertext, KeyPair, PublicKey};
 use wasm_bindgen::prelude::*;
 
-#[derive(Serialize)]
-pub struct KeyPair {
-    pub private_key: String,
-    pub public_key: String,
+fn js(err: impl Display) -> JsValue {
+    JsValue::from_str(&err.to_string())
+}
+
+fn keys(private_key_hex: &str) -> Result<KeyPair, JsValue> {
+    KeyPair::from_secret_hex(private_key_hex).map_err(js)
+}
+
+fn utf8(bytes: Vec<u8>) -> Result<String, JsValue> {
+    String::from_utf8(bytes).map_err(|_| JsValue::from_str("Invalid UTF-8"))
 }
 
+/// Returns JSON with the hex `private_key` and `public_key`.
 #[wasm_bindgen]
-pub fn generate_keypair() -> Result<String, JsValue> {
-    let secp = Secp256k1::new();
-    let mut rng = OsRng;
-    let secret_key = SecretKey::new(&mut rng);
-    let public_key = PublicKey::from_secret_key(&secp, &secret_key);
-
-    let keypair = KeyPair {
-        private_key: hex::encode(secret_key.secret_bytes()),
-        public_key: hex::encode(public_key.serialize_uncompressed()),
-    };
-
-    serde_json::to_string(&keypair).map_err(|e| {
-        JsValue::from_str(&format!("Failed to serialize keypair: {}", e))
+pub fn generate_keypair() -> String {
+    let keys = KeyPair::generate();
+
+    serde_json::json!({
+        "private_key": keys.secret_hex(),
+        "public_key": keys.public_key().to_hex(),
     })
+    .to_string()
 }
 
 #[wasm_bindgen]
@@ -37,44 +35,12 @@ pub fn encrypt_message(
     message: &str,
     recipient_public_key_hex: &str,
 ) -> Result<String, JsValue> {
-    let secp = Secp256k1::new();
-    let mut rng = OsRng;
-
-    let recipient_pub_bytes =
-        hex::decode(recipient_public_key_hex).map_err(|e| {
-            JsValue::from_str(&format!("Invalid public key hex: {}", e))
-        })?;
-    let recipient_pub =
-        PublicKey::from_slice(&recipient_pub_bytes).map_err(|e| {
-            JsValue::from_str(&format!("Invalid public key: {}", e))
-        })?;
-
-    let ephemeral_sk = SecretKey::new(&mut rng);
-    let ephemeral_pk = PublicKey::from_secret_key(&secp, &ephemeral_sk);
-
-    let shared_point = recipient_pub.combine(&ephemeral_pk).map_err(|e| {
-        JsValue::from_str(&format!("Failed to compute shared secret: {}", e))
-    })?;
-    let shared_secret = shared_point.serialize_uncompressed();
-
-    let mut hasher = Sha256::new();
-    hasher.update(&shared_secret);
-    let encryption_key = hasher.finalize();
-
-    let cipher = Aes256Gcm::new(encryption_key.as_slice().into());
-    let nonce_bytes: [u8; 12] = rand::random();
-    let nonce = Nonce::from_slice(&nonce_bytes);
-
-    let ciphertext = cipher
-        .encrypt(nonce, message.as_bytes())
-        .map_err(|e| JsValue::from_str(&format!("Encryption failed: {}", e)))?;
-
-    let mut result = Vec::new();
-    result.extend_from_slice(&ephemeral_pk.serialize_uncompressed());
-    result.extend_from_slice(&nonce_bytes);
-    result.extend_from_slice(&ciphertext);
-
-    Ok(hex::encode(result))
+    let recipient =
+        PublicKey::from_hex(recipient_public_key_hex).map_err(js)?;
+    let ciphertext =
+        rschat_crypto::encrypt(message.as_bytes(), &recipient).map_err(js)?;
+
+    Ok(ciphertext.to_hex())
 }
 
 #[wasm_bindgen]
@@ -82,50 +48,86 @@ pub fn decrypt_message(
     encrypted_hex: &str,
     private_key_hex: &str,
 ) -> Result<String, JsValue> {
-    let secp = Secp256k1::new();
-
-    let private_key_bytes = hex::decode(private_key_hex).map_err(|e| {
-        JsValue::from_str(&format!("Invalid private key hex: {}", e))
-    })?;
-    let private_key =
-        SecretKey::from_slice(&private_key_bytes).map_err(|e| {
-            JsValue::from_str(&format!("Invalid private key: {}", e))
-        })?;
-
-    let encrypted_data = hex::decode(encrypted_hex).map_err(|e| {
-        JsValue::from_str(&format!("Invalid encrypted data hex: {}", e))
-    })?;
-
-    if encrypted_data.len() < 65 + 12 {
-        return Err(JsValue::from_str("Encrypted data too short"));
-    }
-
-    let ephemeral_pk_bytes = &encrypted_data[0..65];
-    let nonce_bytes = &encrypted_data[65..77];
-    let ciphertext = &encrypted_data[77..];
-
-    let ephemeral_pk =
-        PublicKey::from_slice(ephemeral_pk_bytes).map_err(|e| {
-            JsValue::from_str(&format!("Invalid ephemeral public key: {}", e))
-        })?;
-
-    let own_pub = PublicKey::from_secret_key(&secp, &private_key);
-    let shared_point = ephemeral_pk.combine(&own_pub).map_err(|e| {
-        JsValue::from_str(&format!("Failed to compute shared secret: {}", e))
-    })?;
-    let shared_secret = shared_point.serialize_uncompressed();
-
-    let mut hasher = Sha256::new();
-    hasher.update(&shared_secret);
-    let decryption_key = hasher.finalize();
-
-    let cipher = Aes256Gcm::new(decryption_key.as_slice().into());
-    let nonce = Nonce::from_slice(nonce_bytes);
-
-    let plaintext = cipher
-        .decrypt(nonce, ciphertext)
-        .map_err(|e| JsValue::from_str(&format!("Decryption failed: {}", e)))?;
-
-    String::from_utf8(plaintext)
-        .map_err(|e| JsValue::from_str(&format!("Invalid UTF-8: {}", e)))
+    let ciphertext = Ciphertext::from_hex(encrypted_hex).map_err(js)?;
+    utf8(keys(private_key_hex)?.decrypt(&ciphertext).map_err(js)?)
+}
+
+/// Sign the SHA-256 of `message`, e.g. a handle claim, returning the
+/// compact signature as hex.
+#[wasm_bindgen]
+pub fn sign_message(
+    message: &str,
+    private_key_hex: &str,
+) -> Result<String, JsValue> {
+    Ok(keys(private_key_hex)?.sign(message.as_bytes()).to_hex())
+}
+
+/// Encrypt `message` for the recipient with the sender's key and a
+/// signature inside, so the server only sees the ciphertext.
+#[wasm_bindgen]
+pub fn seal_message(
+    message: &str,
+    sender_private_key_hex: &str,
+    recipient_public_key_hex: &str,
+) -> Result<String, JsValue> {
+    let sender = keys(sender_private_key_hex)?;
+    let recipient =
+        PublicKey::from_hex(recipient_public_key_hex).map_err(js)?;
+    let ciphertext =
+        rschat_crypto::seal(message, &sender, &recipient).map_err(js)?;
+
+    Ok(ciphertext.to_hex())
+}
+
+/// Decrypt a sealed message and check its signature. Returns JSON with
+/// the `sender` public key and the `message`.
+#[wasm_bindgen]
+pub fn unseal_message(
+    sealed_hex: &str,
+    private_key_hex: &str,
+) -> Result<String, JsValue> {
+    let ciphertext = Ciphertext::from_hex(sealed_hex).map_err(js)?;
+    let unsealed = keys(private_key_hex)?.unseal(&ciphertext).map_err(js)?;
+
+    Ok(serde_json::json!({
+        "sender": unsealed.sender.to_hex(),
+        "message": unsealed.message,
+    })
+    .to_string())
+}
+
+/// Random access key to hand to the peers allowed to send sealed messages.
+#[wasm_bindgen]
+pub fn generate_access_key() -> String {
+    rschat_crypto::generate_access_key()
+}
+
+/// Hash of an access key, the form registered with `set_access_key`.
+#[wasm_bindgen]
+pub fn access_key_hash(access_key: &str) -> String {
+    rschat_crypto::access_key_hash(access_key)
+}
+
+/// Encrypt a history chunk (e.g. a JSON list of messages) for the other
+/// devices of the same identity. Returns hex of version|nonce|ciphertext.
+#[wasm_bindgen]
+pub fn encrypt_history(
+    history: &str,
+    private_key_hex: &str,
+) -> Result<String, JsValue> {
+    let blob = keys(private_key_hex)?
+        .encrypt_history(history.as_bytes())
+        .map_err(js)?;
+
+    Ok(hex::encode(blob))
+}
+
+#[wasm_bindgen]
+pub fn decrypt_history(
+    blob_hex: &str,
+    private_key_hex: &str,
+) -> Result<String, JsValue> {
+    let blob = hex::decode(blob_hex)
+        .map_err(|_| JsValue::from_str("Invalid history hex"))?;
+    utf8(keys(private_key_hex)?.decrypt_history(&blob).map_err(js)?)
 }
@@ -0,0 +1,38 @@
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+    InvalidPublicKey,
+    InvalidPrivateKey,
+    /// Not a compact ECDSA signature.
+    InvalidSignature,
+    /// A well-formed signature made with another key or over other data.
+    SignatureMismatch,
+    /// Too short, or without a valid ephemeral key.
+    InvalidCiphertext,
+    /// A format version this build does not read.
+    UnsupportedVersion,
+    Encrypt,
+    /// Wrong key, or the ciphertext was tampered with.
+    Decrypt,
+    /// Decrypted, but not the sender/message/signature triple of `seal`.
+    InvalidSealed,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Error::InvalidPublicKey => "Invalid public key",
+            Error::InvalidPrivateKey => "Invalid private key",
+            Error::InvalidSignature => "Invalid signature",
+            Error::SignatureMismatch => "Signature does not match",
+            Error::InvalidCiphertext => "Invalid ciphertext",
+            Error::UnsupportedVersion => "Unsupported version",
+            Error::Encrypt => "Encryption failed",
+            Error::Decrypt => "Decryption failed",
+            Error::InvalidSealed => "Invalid sealed message",
+        })
+    }
+}
+
+impl std::error::Error for Error {}
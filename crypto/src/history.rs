@@ -0,0 +1,91 @@
+use aes_gcm::aead::{Aead, KeyInit};
+use aes_gcm::{Aes256Gcm, Nonce};
+use rand_core::{OsRng, RngCore};
+use sha2::{Digest, Sha256};
+
+use crate::message::NONCE_LEN;
+use crate::{Error, KeyPair};
+
+/// Format version of history blobs, their first byte.
+const HISTORY_VERSION: u8 = 1;
+
+/// Length of a blob holding an empty history: version, nonce and tag.
+pub const MIN_HISTORY_LEN: usize = 1 + NONCE_LEN + 16;
+
+impl KeyPair {
+    /// Symmetric key for history blobs, derived from the identity key so
+    /// every device of the identity gets the same one.
+    fn history_key(&self) -> Aes256Gcm {
+        let mut hasher = Sha256::new();
+        hasher.update(b"rschat:history:");
+        hasher.update(self.secret.secret_bytes());
+        let key = hasher.finalize();
+
+        Aes256Gcm::new(key.as_slice().into())
+    }
+
+    /// Encrypt a history chunk (e.g. a JSON list of messages) for the
+    /// other devices of the same identity. Returns version|nonce|ciphertext.
+    pub fn encrypt_history(&self, history: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut nonce = [0u8; NONCE_LEN];
+        OsRng.fill_bytes(&mut nonce);
+
+        let ciphertext = self
+            .history_key()
+            .encrypt(Nonce::from_slice(&nonce), history)
+            .map_err(|_| Error::Encrypt)?;
+
+        let mut blob = vec![HISTORY_VERSION];
+        blob.extend_from_slice(&nonce);
+        blob.extend_from_slice(&ciphertext);
+        Ok(blob)
+    }
+
+    pub fn decrypt_history(&self, blob: &[u8]) -> Result<Vec<u8>, Error> {
+        if blob.len() < MIN_HISTORY_LEN {
+            return Err(Error::InvalidCiphertext);
+        }
+        if blob[0] != HISTORY_VERSION {
+            return Err(Error::UnsupportedVersion);
+        }
+
+        let (nonce, ciphertext) = blob[1..].split_at(NONCE_LEN);
+        self.history_key()
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| Error::Decrypt)
+    }
+}
+
+#[cfg(test)]
+mod history_tests {
+    use crate::{Error, KeyPair};
+
+    #[test]
+    fn test_history_round_trip() {
+        let keys = KeyPair::generate();
+        let blob = keys.encrypt_history(b"[]").unwrap();
+        assert_eq!(blob[0], 1);
+        assert_eq!(keys.decrypt_history(&blob).unwrap(), b"[]");
+
+        // Another device of the same identity.
+        let device = KeyPair::from_secret_bytes(&keys.secret_bytes()).unwrap();
+        assert_eq!(device.decrypt_history(&blob).unwrap(), b"[]");
+
+        let other = KeyPair::generate();
+        assert_eq!(other.decrypt_history(&blob), Err(Error::Decrypt));
+    }
+
+    #[test]
+    fn test_history_malformed() {
+        let keys = KeyPair::generate();
+        let mut blob = keys.encrypt_history(b"[]").unwrap();
+
+        assert_eq!(
+            keys.decrypt_history(&blob[..12]),
+            Err(Error::InvalidCiphertext)
+        );
+
+        blob[0] = 2;
+        assert_eq!(keys.decrypt_history(&blob), Err(Error::UnsupportedVersion));
+    }
+}
@@ -0,0 +1,197 @@
+use std::fmt;
+
+use rand_core::OsRng;
+use secp256k1::{Message, Secp256k1, SecretKey, ecdsa};
+use sha2::{Digest, Sha256};
+
+use crate::Error;
+
+/// An uncompressed secp256k1 public key, the form used on the wire.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct PublicKey(pub(crate) secp256k1::PublicKey);
+
+impl PublicKey {
+    pub const LEN: usize = 65;
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        secp256k1::PublicKey::from_slice(bytes)
+            .map(PublicKey)
+            .map_err(|_| Error::InvalidPublicKey)
+    }
+
+    pub fn from_hex(hex: &str) -> Result<Self, Error> {
+        let bytes = hex::decode(hex).map_err(|_| Error::InvalidPublicKey)?;
+        PublicKey::from_bytes(&bytes)
+    }
+
+    pub fn to_bytes(&self) -> [u8; Self::LEN] {
+        self.0.serialize_uncompressed()
+    }
+
+    pub fn to_hex(&self) -> String {
+        hex::encode(self.to_bytes())
+    }
+
+    /// Check a signature made by `KeyPair::sign` over `message`.
+    pub fn verify(
+        &self,
+        message: &[u8],
+        signature: &Signature,
+    ) -> Result<(), Error> {
+        Secp256k1::verification_only()
+            .verify_ecdsa(&digest(message), &signature.0, &self.0)
+            .map_err(|_| Error::SignatureMismatch)
+    }
+}
+
+/// A compact ECDSA signature over the SHA-256 of the signed bytes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Signature(ecdsa::Signature);
+
+impl Signature {
+    pub const LEN: usize = 64;
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        ecdsa::Signature::from_compact(bytes)
+            .map(Signature)
+            .map_err(|_| Error::InvalidSignature)
+    }
+
+    pub fn from_hex(hex: &str) -> Result<Self, Error> {
+        let bytes = hex::decode(hex).map_err(|_| Error::InvalidSignature)?;
+        Signature::from_bytes(&bytes)
+    }
+
+    pub fn to_bytes(&self) -> [u8; Self::LEN] {
+        self.0.serialize_compact()
+    }
+
+    pub fn to_hex(&self) -> String {
+        hex::encode(self.to_bytes())
+    }
+}
+
+/// An identity: the private key and its public key.
+#[derive(Clone)]
+pub struct KeyPair {
+    pub(crate) secret: SecretKey,
+    public: PublicKey,
+}
+
+impl KeyPair {
+    pub fn generate() -> Self {
+        KeyPair::from_secret(SecretKey::new(&mut OsRng))
+    }
+
+    pub fn from_secret_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        SecretKey::from_slice(bytes)
+            .map(KeyPair::from_secret)
+            .map_err(|_| Error::InvalidPrivateKey)
+    }
+
+    pub fn from_secret_hex(hex: &str) -> Result<Self, Error> {
+        let bytes = hex::decode(hex).map_err(|_| Error::InvalidPrivateKey)?;
+        KeyPair::from_secret_bytes(&bytes)
+    }
+
+    pub(crate) fn from_secret(secret: SecretKey) -> Self {
+        let public = secret.public_key(&Secp256k1::signing_only());
+        KeyPair {
+            secret,
+            public: PublicKey(public),
+        }
+    }
+
+    pub fn public_key(&self) -> &PublicKey {
+        &self.public
+    }
+
+    pub fn secret_bytes(&self) -> [u8; 32] {
+        self.secret.secret_bytes()
+    }
+
+    pub fn secret_hex(&self) -> String {
+        hex::encode(self.secret_bytes())
+    }
+
+    /// Sign the SHA-256 of `message`, e.g. a handle claim.
+    pub fn sign(&self, message: &[u8]) -> Signature {
+        let secp = Secp256k1::signing_only();
+        Signature(secp.sign_ecdsa(&digest(message), &self.secret))
+    }
+}
+
+impl fmt::Debug for KeyPair {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KeyPair")
+            .field("public", &self.public)
+            .finish_non_exhaustive()
+    }
+}
+
+fn digest(message: &[u8]) -> Message {
+    Message::from_digest(Sha256::digest(message).into())
+}
+
+#[cfg(test)]
+mod keys_tests {
+    use crate::{Error, KeyPair, PublicKey, Signature};
+
+    #[test]
+    fn test_keypair_round_trip() {
+        let keys = KeyPair::generate();
+        let again = KeyPair::from_secret_hex(&keys.secret_hex()).unwrap();
+        assert_eq!(again.public_key(), keys.public_key());
+
+        let hex = keys.public_key().to_hex();
+        assert_eq!(hex.len(), PublicKey::LEN * 2);
+        assert!(hex.starts_with("04"));
+        assert_eq!(PublicKey::from_hex(&hex).unwrap(), *keys.public_key());
+
+        assert_eq!(
+            KeyPair::from_secret_bytes(&[0; 32]).unwrap_err(),
+            Error::InvalidPrivateKey
+        );
+        assert_eq!(
+            PublicKey::from_hex("04zz").unwrap_err(),
+            Error::InvalidPublicKey
+        );
+    }
+
+    #[test]
+    fn test_sign_verify() {
+        let keys = KeyPair::from_secret_bytes(&[7; 32]).unwrap();
+        let signature = keys.sign(b"rschat:claim_handle:alice");
+        let public = keys.public_key();
+
+        assert_eq!(
+            public.verify(b"rschat:claim_handle:alice", &signature),
+            Ok(())
+        );
+        assert_eq!(
+            public.verify(b"rschat:claim_handle:bob", &signature),
+            Err(Error::SignatureMismatch)
+        );
+
+        let other = KeyPair::generate();
+        assert_eq!(
+            other
+                .public_key()
+                .verify(b"rschat:claim_handle:alice", &signature),
+            Err(Error::SignatureMismatch)
+        );
+
+        let hex = signature.to_hex();
+        assert_eq!(Signature::from_hex(&hex).unwrap(), signature);
+        assert_eq!(
+            Signature::from_hex("00").unwrap_err(),
+            Error::InvalidSignature
+        );
+    }
+
+    #[test]
+    fn test_debug_hides_secret() {
+        let keys = KeyPair::from_secret_bytes(&[7; 32]).unwrap();
+        assert!(!format!("{keys:?}").contains(&keys.secret_hex()));
+    }
+}
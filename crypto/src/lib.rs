@@ -0,0 +1,30 @@
+//! End-to-end encryption for rschat: identities, message encryption,
+//! sealed sender and history blobs. `crypto-wasm` exposes it to the web
+//! client, and native clients and the server use it directly.
+
+mod error;
+mod history;
+mod keys;
+mod message;
+mod sealed;
+
+use rand_core::{OsRng, RngCore};
+use sha2::{Digest, Sha256};
+
+pub use crate::error::Error;
+pub use crate::history::MIN_HISTORY_LEN;
+pub use crate::keys::{KeyPair, PublicKey, Signature};
+pub use crate::message::{Ciphertext, NONCE_LEN, encrypt};
+pub use crate::sealed::{Unsealed, seal};
+
+/// Random access key to hand to the peers allowed to send sealed messages.
+pub fn generate_access_key() -> String {
+    let mut bytes = [0u8; 16];
+    OsRng.fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
+
+/// Hash of an access key, the form registered with `set_access_key`.
+pub fn access_key_hash(access_key: &str) -> String {
+    hex::encode(Sha256::digest(access_key.as_bytes()))
+}
@@ -0,0 +1,195 @@
+use aes_gcm::aead::{Aead, KeyInit};
+use aes_gcm::{Aes256Gcm, Nonce};
+use rand_core::{OsRng, RngCore};
+use secp256k1::ecdh::SharedSecret;
+use secp256k1::{Secp256k1, SecretKey};
+
+use crate::{Error, KeyPair, PublicKey};
+
+pub const NONCE_LEN: usize = 12;
+
+/// Format version of ciphertexts, their first byte. Payloads from before
+/// it start with the ephemeral key's 0x04 and are refused.
+const MESSAGE_VERSION: u8 = 1;
+
+/// A message encrypted for one recipient. On the wire it is
+/// version | ephemeral public key | nonce | AES-256-GCM ciphertext, hex
+/// encoded.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Ciphertext {
+    pub ephemeral: PublicKey,
+    pub nonce: [u8; NONCE_LEN],
+    pub data: Vec<u8>,
+}
+
+impl Ciphertext {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        if bytes.len() < 1 + PublicKey::LEN + NONCE_LEN {
+            return Err(Error::InvalidCiphertext);
+        }
+        if bytes[0] != MESSAGE_VERSION {
+            return Err(Error::UnsupportedVersion);
+        }
+
+        let (ephemeral, rest) = bytes[1..].split_at(PublicKey::LEN);
+        let (nonce, data) = rest.split_at(NONCE_LEN);
+
+        Ok(Ciphertext {
+            ephemeral: PublicKey::from_bytes(ephemeral)
+                .map_err(|_| Error::InvalidCiphertext)?,
+            nonce: nonce.try_into().map_err(|_| Error::InvalidCiphertext)?,
+            data: data.to_vec(),
+        })
+    }
+
+    pub fn from_hex(hex: &str) -> Result<Self, Error> {
+        let bytes = hex::decode(hex).map_err(|_| Error::InvalidCiphertext)?;
+        Ciphertext::from_bytes(&bytes)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(
+            1 + PublicKey::LEN + NONCE_LEN + self.data.len(),
+        );
+        bytes.push(MESSAGE_VERSION);
+        bytes.extend_from_slice(&self.ephemeral.to_bytes());
+        bytes.extend_from_slice(&self.nonce);
+        bytes.extend_from_slice(&self.data);
+        bytes
+    }
+
+    pub fn to_hex(&self) -> String {
+        hex::encode(self.to_bytes())
+    }
+}
+
+/// Encrypt `plaintext` for `recipient`. The key comes from ECDH between a
+/// fresh ephemeral key and `recipient`, so reading it takes the
+/// recipient's private key. It says nothing about who encrypted it.
+pub fn encrypt(
+    plaintext: &[u8],
+    recipient: &PublicKey,
+) -> Result<Ciphertext, Error> {
+    let secret = SecretKey::new(&mut OsRng);
+    let ephemeral = PublicKey(secret.public_key(&Secp256k1::signing_only()));
+    let mut nonce = [0u8; NONCE_LEN];
+    OsRng.fill_bytes(&mut nonce);
+
+    let cipher = message_key(SharedSecret::new(&recipient.0, &secret));
+
+    let data = cipher
+        .encrypt(Nonce::from_slice(&nonce), plaintext)
+        .map_err(|_| Error::Encrypt)?;
+
+    Ok(Ciphertext {
+        ephemeral,
+        nonce,
+        data,
+    })
+}
+
+impl KeyPair {
+    /// Decrypt a message encrypted for this key pair.
+    pub fn decrypt(&self, ciphertext: &Ciphertext) -> Result<Vec<u8>, Error> {
+        let cipher = message_key(SharedSecret::new(
+            &ciphertext.ephemeral.0,
+            &self.secret,
+        ));
+
+        cipher
+            .decrypt(Nonce::from_slice(&ciphertext.nonce), &*ciphertext.data)
+            .map_err(|_| Error::Decrypt)
+    }
+}
+
+/// AES key for a message: the ECDH secret, which is already the SHA-256
+/// of the shared point.
+fn message_key(shared: SharedSecret) -> Aes256Gcm {
+    Aes256Gcm::new(shared.secret_bytes().as_slice().into())
+}
+
+#[cfg(test)]
+mod message_tests {
+    use aes_gcm::aead::{Aead, KeyInit};
+    use aes_gcm::{Aes256Gcm, Nonce};
+    use sha2::{Digest, Sha256};
+
+    use crate::{Ciphertext, Error, KeyPair, encrypt};
+
+    #[test]
+    fn test_encrypt_decrypt() {
+        let alice = KeyPair::generate();
+        let bob = KeyPair::generate();
+
+        let ciphertext = encrypt(b"hello", bob.public_key()).unwrap();
+        assert_eq!(bob.decrypt(&ciphertext).unwrap(), b"hello");
+        assert_eq!(alice.decrypt(&ciphertext), Err(Error::Decrypt));
+
+        let hex = ciphertext.to_hex();
+        assert_eq!(Ciphertext::from_hex(&hex).unwrap(), ciphertext);
+
+        // Every message gets a fresh ephemeral key and nonce.
+        assert_ne!(encrypt(b"hello", bob.public_key()).unwrap(), ciphertext);
+    }
+
+    #[test]
+    fn test_public_keys_dont_decrypt() {
+        let bob = KeyPair::generate();
+        let ciphertext = encrypt(b"hello", bob.public_key()).unwrap();
+
+        // The server knows both public keys. Whatever key it derives from
+        // them alone, e.g. their sum as a private key's public key, fails.
+        let sum = bob.public_key().0.combine(&ciphertext.ephemeral.0).unwrap();
+        let key = Sha256::digest(sum.serialize_uncompressed());
+        let cipher = Aes256Gcm::new(key.as_slice().into());
+        assert!(
+            cipher
+                .decrypt(
+                    Nonce::from_slice(&ciphertext.nonce),
+                    &*ciphertext.data
+                )
+                .is_err()
+        );
+
+        let eve = KeyPair::generate();
+        assert_eq!(eve.decrypt(&ciphertext), Err(Error::Decrypt));
+    }
+
+    #[test]
+    fn test_unversioned() {
+        let bob = KeyPair::generate();
+        let ciphertext = encrypt(b"hello", bob.public_key()).unwrap();
+
+        // The old format: no version byte, the ephemeral key comes first.
+        let old = &ciphertext.to_bytes()[1..];
+        assert_eq!(
+            Ciphertext::from_bytes(old).unwrap_err(),
+            Error::UnsupportedVersion
+        );
+    }
+
+    #[test]
+    fn test_tampered() {
+        let bob = KeyPair::generate();
+        let mut ciphertext = encrypt(b"hello", bob.public_key()).unwrap();
+        ciphertext.data[0] ^= 1;
+        assert_eq!(bob.decrypt(&ciphertext), Err(Error::Decrypt));
+
+        assert_eq!(
+            Ciphertext::from_bytes(&[1; 77]).unwrap_err(),
+            Error::InvalidCiphertext
+        );
+        assert_eq!(
+            Ciphertext::from_hex("zz").unwrap_err(),
+            Error::InvalidCiphertext
+        );
+
+        // Long enough, but the ephemeral key is not on the curve.
+        let mut bytes = ciphertext.to_bytes();
+        bytes[2] ^= 1;
+        assert_eq!(
+            Ciphertext::from_bytes(&bytes).unwrap_err(),
+            Error::InvalidCiphertext
+        );
+    }
+}
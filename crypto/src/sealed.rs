@@ -0,0 +1,133 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{Ciphertext, Error, KeyPair, PublicKey, Signature, encrypt};
+
+/// Sender identity and proof, only visible to the recipient of a sealed
+/// message.
+#[derive(Serialize, Deserialize)]
+struct Sealed {
+    sender: String,
+    message: String,
+    signature: String,
+}
+
+/// A sealed message opened by its recipient, with the sender verified.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Unsealed {
+    pub sender: PublicKey,
+    pub message: String,
+}
+
+/// What a sealed message's signature covers: the recipient is included so
+/// it can't be re-sealed for someone else.
+fn signed(recipient: &PublicKey, message: &str) -> Vec<u8> {
+    format!("rschat:sealed:{}:{message}", recipient.to_hex()).into_bytes()
+}
+
+/// Encrypt `message` for `recipient` with the sender's key and a signature
+/// inside, so the server only sees the ciphertext.
+pub fn seal(
+    message: &str,
+    sender: &KeyPair,
+    recipient: &PublicKey,
+) -> Result<Ciphertext, Error> {
+    let signature = sender.sign(&signed(recipient, message));
+
+    let sealed = Sealed {
+        sender: sender.public_key().to_hex(),
+        message: message.to_string(),
+        signature: signature.to_hex(),
+    };
+    let json = serde_json::to_vec(&sealed).map_err(|_| Error::Encrypt)?;
+
+    encrypt(&json, recipient)
+}
+
+impl KeyPair {
+    /// Decrypt a sealed message and check its signature.
+    pub fn unseal(&self, ciphertext: &Ciphertext) -> Result<Unsealed, Error> {
+        let json = self.decrypt(ciphertext)?;
+        let sealed: Sealed =
+            serde_json::from_slice(&json).map_err(|_| Error::InvalidSealed)?;
+
+        let sender = PublicKey::from_hex(&sealed.sender)?;
+        let signature = Signature::from_hex(&sealed.signature)?;
+        sender
+            .verify(&signed(self.public_key(), &sealed.message), &signature)?;
+
+        Ok(Unsealed {
+            sender,
+            message: sealed.message,
+        })
+    }
+}
+
+#[cfg(test)]
+mod sealed_tests {
+    use aes_gcm::aead::{Aead, KeyInit};
+    use aes_gcm::{Aes256Gcm, Nonce};
+    use sha2::{Digest, Sha256};
+
+    use crate::sealed::{Sealed, signed};
+    use crate::{Error, KeyPair, encrypt, seal};
+
+    #[test]
+    fn test_seal_unseal() {
+        let alice = KeyPair::generate();
+        let bob = KeyPair::generate();
+        let carol = KeyPair::generate();
+
+        let sealed = seal("hi bob", &alice, bob.public_key()).unwrap();
+        let unsealed = bob.unseal(&sealed).unwrap();
+        assert_eq!(unsealed.sender, *alice.public_key());
+        assert_eq!(unsealed.message, "hi bob");
+
+        assert_eq!(carol.unseal(&sealed), Err(Error::Decrypt));
+    }
+
+    #[test]
+    fn test_reseal_for_someone_else() {
+        let alice = KeyPair::generate();
+        let bob = KeyPair::generate();
+        let carol = KeyPair::generate();
+
+        // Bob passes Alice's signed message on to Carol.
+        let signature = alice.sign(&signed(bob.public_key(), "hi"));
+        let forged = Sealed {
+            sender: alice.public_key().to_hex(),
+            message: "hi".to_string(),
+            signature: signature.to_hex(),
+        };
+        let json = serde_json::to_vec(&forged).unwrap();
+        let ciphertext = encrypt(&json, carol.public_key()).unwrap();
+
+        assert_eq!(carol.unseal(&ciphertext), Err(Error::SignatureMismatch));
+    }
+
+    #[test]
+    fn test_public_keys_dont_unseal() {
+        let alice = KeyPair::generate();
+        let bob = KeyPair::generate();
+        let sealed = seal("hi bob", &alice, bob.public_key()).unwrap();
+
+        // The server sees every public key involved, and none of the
+        // points it can make from them gives the message key.
+        let points = [bob.public_key(), &sealed.ephemeral, alice.public_key()];
+        for point in points {
+            let shared = point.0.combine(&sealed.ephemeral.0);
+            for shared in [Ok(point.0), shared].into_iter().flatten() {
+                let key = Sha256::digest(shared.serialize());
+                let cipher = Aes256Gcm::new(key.as_slice().into());
+                let nonce = Nonce::from_slice(&sealed.nonce);
+                assert!(cipher.decrypt(nonce, &*sealed.data).is_err());
+            }
+        }
+    }
+
+    #[test]
+    fn test_not_sealed() {
+        let bob = KeyPair::generate();
+        let ciphertext = encrypt(b"plain", bob.public_key()).unwrap();
+        assert_eq!(bob.unseal(&ciphertext), Err(Error::InvalidSealed));
+    }
+}
@@ -0,0 +1,128 @@
+//! Vectors made by the web client's crypto. Run natively with `cargo test`
+//! and in wasm with `wasm-pack test --node crypto`, so both targets read
+//! what the other wrote.
+
+use rschat_crypto::{
+    Ciphertext, KeyPair, PublicKey, Signature, access_key_hash,
+};
+use serde::Deserialize;
+#[cfg(target_arch = "wasm32")]
+use wasm_bindgen_test::wasm_bindgen_test as test;
+
+#[derive(Deserialize)]
+struct Vectors {
+    keys: Vec<Key>,
+    signatures: Vec<Signed>,
+    messages: Vec<Message>,
+    sealed: Vec<Sealed>,
+    history: Vec<History>,
+    access_keys: Vec<AccessKey>,
+}
+
+#[derive(Deserialize)]
+struct Key {
+    private_key: String,
+    public_key: String,
+}
+
+#[derive(Deserialize)]
+struct Signed {
+    private_key: String,
+    message: String,
+    signature: String,
+}
+
+#[derive(Deserialize)]
+struct Message {
+    private_key: String,
+    plaintext: String,
+    ciphertext: String,
+}
+
+#[derive(Deserialize)]
+struct Sealed {
+    private_key: String,
+    sender: String,
+    message: String,
+    ciphertext: String,
+}
+
+#[derive(Deserialize)]
+struct History {
+    private_key: String,
+    plaintext: String,
+    blob: String,
+}
+
+#[derive(Deserialize)]
+struct AccessKey {
+    access_key: String,
+    hash: String,
+}
+
+fn vectors() -> Vectors {
+    serde_json::from_str(include_str!("vectors.json")).unwrap()
+}
+
+fn keys(private_key: &str) -> KeyPair {
+    KeyPair::from_secret_hex(private_key).unwrap()
+}
+
+#[test]
+fn test_vector_keys() {
+    for v in vectors().keys {
+        assert_eq!(keys(&v.private_key).public_key().to_hex(), v.public_key);
+    }
+}
+
+#[test]
+fn test_vector_signatures() {
+    for v in vectors().signatures {
+        let keys = keys(&v.private_key);
+        // RFC 6979 nonces make signatures deterministic.
+        assert_eq!(keys.sign(v.message.as_bytes()).to_hex(), v.signature);
+
+        let signature = Signature::from_hex(&v.signature).unwrap();
+        assert_eq!(
+            keys.public_key().verify(v.message.as_bytes(), &signature),
+            Ok(())
+        );
+    }
+}
+
+#[test]
+fn test_vector_messages() {
+    for v in vectors().messages {
+        let ciphertext = Ciphertext::from_hex(&v.ciphertext).unwrap();
+        assert_eq!(ciphertext.to_hex(), v.ciphertext);
+
+        let plaintext = keys(&v.private_key).decrypt(&ciphertext).unwrap();
+        assert_eq!(plaintext, v.plaintext.as_bytes());
+    }
+}
+
+#[test]
+fn test_vector_sealed() {
+    for v in vectors().sealed {
+        let ciphertext = Ciphertext::from_hex(&v.ciphertext).unwrap();
+        let unsealed = keys(&v.private_key).unseal(&ciphertext).unwrap();
+        assert_eq!(unsealed.sender, PublicKey::from_hex(&v.sender).unwrap());
+        assert_eq!(unsealed.message, v.message);
+    }
+}
+
+#[test]
+fn test_vector_history() {
+    for v in vectors().history {
+        let blob = hex::decode(&v.blob).unwrap();
+        let plaintext = keys(&v.private_key).decrypt_history(&blob).unwrap();
+        assert_eq!(plaintext, v.plaintext.as_bytes());
+    }
+}
+
+#[test]
+fn test_vector_access_keys() {
+    for v in vectors().access_keys {
+        assert_eq!(access_key_hash(&v.access_key), v.hash);
+    }
+}
@@ -1,45 +1,132 @@
-import { User } from "./types";
+import { Message, StoredMessage, User } from "./types";
 import * as utils from "./utils";
 import * as ws from "./wasm/crypto_wasm.js";
 import { messageStore } from "./store";
 
 const PROFILE_KEY = "profile";
+const DEVICE_KEY = "device_id";
+const ACCESS_KEY = "access_key";
+const TYPING_REFRESH_MS = 3000;
+const RECONNECT_MS = 1000;
+// Server limit on one history chunk
+const MAX_HISTORY_CHUNK = 256 * 1024;
+// Payload schema this client is written against
+const PROTOCOL = "rschat.v1.json";
 
 const welcome_dialog = document.getElementById("welcome_dialog") as HTMLDialogElement | null;
 const messages = document.getElementById("messages");
 const user_list = document.getElementById("user-list");
 const group_chat_item = document.querySelector('.chat-item[data-chat-id="group"]') as HTMLElement | null;
 const message_form = document.getElementById("message_form") as HTMLFormElement | null;
+const typing_indicator = document.getElementById("typing");
+const presence_select = document.getElementById("presence_select") as HTMLSelectElement | null;
+const contact_form = document.getElementById("contact_form") as HTMLFormElement | null;
 
 // Global state
 let socket: WebSocket | null = null;
 let profile: User | null = null;
 let groupId: string | null = null;
 const users: { [id: string]: User } = {};
+// Users currently typing, mapped to the chat they are typing in
+const typing = new Map<string, string | null>();
+let last_typing_sent = 0;
+// Lets a dropped connection pick up where it left off
+let resume_token: string | null = null;
+let last_seq = 0;
+// Signed by `first` to prove we hold the key
+let nonce: string | null = null;
+// Access keys peers handed us, so DMs to them can go out sealed
+const access_keys: { [id: string]: string } = {};
+// Set once another connection took over this device, which stops reconnects
+let replaced = false;
+// Our messages by id, stored once the server acknowledges them
+const pending = new Map<string, { text: string, groupId: string | null, sealed: boolean }>();
+// Never logs in, so the server can't tell who sends our sealed messages
+let sealed_socket: WebSocket | null = null;
+const sealed_queue: string[] = [];
+
+// Each tab is its own device, so it gets a session-scoped id
+function device_id(): string {
+    let id = sessionStorage.getItem(DEVICE_KEY);
+    if (!id) {
+        id = crypto.randomUUID();
+        sessionStorage.setItem(DEVICE_KEY, id);
+    }
+    return id;
+}
+
+function status_label(user: User): string {
+    if (user.status_text) return user.status_text;
+    switch (user.status) {
+        case "away": return "Away";
+        case "busy": return "Busy";
+        default: return "Online";
+    }
+}
+
+function update_typing_indicator() {
+    if (!typing_indicator) return;
+
+    const names: string[] = [];
+    typing.forEach((gid, id) => {
+        const user = users[id];
+        if (!user) return;
+        const chat = gid !== null && profile && gid === profile.public_key ? id : null;
+        if (chat === groupId) names.push(user.name);
+    });
+
+    typing_indicator.textContent = names.length > 0
+        ? `${names.join(", ")} ${names.length > 1 ? "are" : "is"} typing…`
+        : "";
+}
+
+function send_typing(active: boolean) {
+    if (socket == null) return;
+
+    const now = Date.now();
+    if (active && now - last_typing_sent < TYPING_REFRESH_MS) return;
+    last_typing_sent = active ? now : 0;
+
+    socket.send(JSON.stringify({
+        kind: "typing",
+        recipient: groupId ?? undefined,
+        typing: active
+    }));
+}
+
+// Names, handles and status texts are chosen by peers, so they only go in as text
+function element(tag: string, className: string, text?: string): HTMLElement {
+    const el = document.createElement(tag);
+    el.className = className;
+    if (text !== undefined) el.textContent = text;
+    return el;
+}
 
 async function update_users_list() {
     if (!user_list) return;
-    user_list.innerHTML = "";
+    user_list.replaceChildren();
 
     for (const id of Object.keys(users)) {
         const user = users[id];
-        const color = utils.name_color(user.name);
-        const isActive = groupId === user.public_key ? 'active' : '';
         const hasUnread = await messageStore.hasUnreadMessages(user.public_key);
-        const unreadIndicator = hasUnread ? '<span class="unread-indicator"></span>' : '';
-
-        user_list.innerHTML += `
-        <div class="chat-item ${isActive}" data-chat-id="${user.public_key}">
-            <div class="avatar online" style="background: ${color};">
-              ${user.name.charAt(0).toUpperCase()}
-            </div>
-            <div class="chat-info">
-                <div class="chat-name">${user.name}</div>
-                <div class="chat-status">Online</div>
-            </div>
-            ${unreadIndicator}
-        </div>
-        `;
+
+        const item = element("div", "chat-item");
+        if (groupId === user.public_key) item.classList.add("active");
+        item.dataset.chatId = user.public_key;
+
+        const status = ["online", "away", "busy"].includes(user.status ?? "") ? user.status! : "online";
+        const avatar = element("div", `avatar ${status}`, user.name.charAt(0).toUpperCase());
+        avatar.style.background = utils.name_color(user.name);
+
+        const name = element("div", "chat-name", user.name);
+        if (user.handle) name.append(" ", element("span", "chat-handle", `@${user.handle}`));
+
+        const info = element("div", "chat-info");
+        info.append(name, element("div", "chat-status", status_label(user)));
+
+        item.append(avatar, info);
+        if (hasUnread) item.append(element("span", "unread-indicator"));
+        user_list.append(item);
     }
 
     const chatItems = user_list.querySelectorAll('.chat-item');
@@ -54,7 +141,7 @@ async function update_users_list() {
     if (group_chat_item) {
         const existingIndicator = group_chat_item.querySelector('.unread-indicator');
         if (hasGroupUnread && !existingIndicator) {
-            group_chat_item.innerHTML += '<span class="unread-indicator"></span>';
+            group_chat_item.append(element("span", "unread-indicator"));
         } else if (!hasGroupUnread && existingIndicator) {
             existingIndicator.remove();
         }
@@ -76,31 +163,127 @@ function select_chat(chatId: string | null) {
     }
 
     load_stored_messages();
+    update_typing_indicator();
     messageStore.markMessagesAsRead(chatId).then(() => {
         update_users_list();
     });
 }
 
-function append_user_message(name: string, text: string): void {
-    const color = utils.name_color(name);
-    if (messages) {
-        messages.innerHTML += `
-        <div class="user-message">
-            <b style="color:${color}">${name}: </b>
-            ${text}
-        </div>
-        `;
+// Names, texts and reactions come from peers, so they only go in as text
+function append_user_message(message: Message & Partial<StoredMessage>): void {
+    if (!messages) return;
+
+    const item = document.createElement("div");
+    item.className = "user-message";
+
+    const sender = document.createElement("b");
+    sender.style.color = utils.name_color(message.sender);
+    sender.textContent = `${message.sender}: `;
+    item.append(sender, message.payload);
+
+    if (message.edited) {
+        const mark = document.createElement("i");
+        mark.className = "message-edited";
+        mark.textContent = "(edited)";
+        item.append(" ", mark);
+    }
+    const reactions = Object.values(message.reactions ?? {}).join(" ");
+    if (reactions) {
+        const emoji = document.createElement("span");
+        emoji.className = "message-reactions";
+        emoji.textContent = reactions;
+        item.append(" ", emoji);
     }
+
+    // Only messages with an id can be referred to later
+    if (message.messageId) {
+        const actions = element("span", "message-actions");
+        actions.append(action_button("React", () => react_to(message)));
+        // Sealed messages have no recorded sender, so the server refuses changes
+        if (message.senderKey === profile?.public_key && !message.sealed) {
+            actions.append(
+                action_button("Edit", () => edit_message(message)),
+                action_button("Delete", () => delete_message(message))
+            );
+        }
+        item.append(actions);
+    }
+
+    messages.append(item);
 }
 
-function append_server_message(text: string): void {
-    if (messages) {
-        messages.innerHTML += `
-            <div class="server-message">${text}</div>
-        `;
+function action_button(label: string, onclick: () => void): HTMLButtonElement {
+    const button = document.createElement("button");
+    button.type = "button";
+    button.textContent = label;
+    button.addEventListener("click", onclick);
+    return button;
+}
+
+// Send a change of `message` to everyone who got it, like the message itself
+function send_change(kind: string, message: Message, text?: string) {
+    if (socket == null || !message.messageId) return;
+
+    const recipients = message.groupId ? [message.groupId] : Object.keys(users);
+    for (const recipient of recipients) {
+        const change: any = {
+            kind,
+            recipient,
+            group_id: message.groupId,
+            target_id: message.messageId
+        };
+        if (text !== undefined) change.payload = ws.encrypt_message(text, recipient);
+        socket.send(JSON.stringify(change));
     }
 }
 
+async function edit_message(message: Message) {
+    const text = prompt("Edit message", message.payload);
+    if (!text || text === message.payload) return;
+
+    send_change("edit_message", message, text);
+    await messageStore.updateMessage(message.messageId!, m => {
+        m.payload = text;
+        m.edited = true;
+        return true;
+    });
+    load_stored_messages();
+}
+
+async function delete_message(message: Message) {
+    if (!confirm("Delete this message for everyone?")) return;
+
+    send_change("delete_message", message);
+    await messageStore.updateMessage(message.messageId!, () => false);
+    load_stored_messages();
+}
+
+// An empty answer takes our reaction back
+async function react_to(message: Message) {
+    if (profile == null) return;
+    const emoji = prompt("React with", "👍");
+    if (emoji === null) return;
+
+    send_change("react", message, emoji);
+    const own = profile.public_key;
+    await messageStore.updateMessage(message.messageId!, m => {
+        m.reactions = m.reactions ?? {};
+        if (emoji) m.reactions[own] = emoji;
+        else delete m.reactions[own];
+        return true;
+    });
+    load_stored_messages();
+}
+
+function append_server_message(text: string): void {
+    if (!messages) return;
+
+    const item = document.createElement("div");
+    item.className = "server-message";
+    item.textContent = text;
+    messages.append(item);
+}
+
 async function load_stored_messages() {
     if (messages) messages.innerHTML = "";
 
@@ -110,9 +293,61 @@ async function load_stored_messages() {
         msgs
     });
 
-    msgs.forEach(({ sender, payload }) => {
-        append_user_message(sender, payload);
-    });
+    msgs.forEach(append_user_message);
+}
+
+// A fresh device restores history from the others, the others back theirs up
+async function sync_history() {
+    if (socket == null || profile == null) return;
+
+    const stored = await messageStore.getAllMessages();
+    if (stored.length === 0) {
+        socket.send(JSON.stringify({ kind: "fetch_history" }));
+        return;
+    }
+
+    // Newest messages first, as many as fit into one chunk
+    let history = stored.map(({ sender, payload, groupId, messageId, timestamp, senderKey, sealed }) =>
+        ({ sender, payload, groupId, messageId, timestamp, senderKey, sealed }));
+    let data = ws.encrypt_history(JSON.stringify(history), profile.private_key);
+    while (data.length > MAX_HISTORY_CHUNK && history.length > 1) {
+        history = history.slice(Math.ceil(history.length / 4));
+        data = ws.encrypt_history(JSON.stringify(history), profile.private_key);
+    }
+    if (data.length > MAX_HISTORY_CHUNK) return;
+
+    socket.send(JSON.stringify({
+        kind: "upload_history",
+        chunk_id: device_id(),
+        data
+    }));
+}
+
+// Key peers need to send us sealed messages, kept across sessions
+function access_key(): string {
+    let key = localStorage.getItem(ACCESS_KEY);
+    if (!key) {
+        key = ws.generate_access_key();
+        localStorage.setItem(ACCESS_KEY, key);
+    }
+    return key;
+}
+
+// Register our access key and hand it to everyone online
+function register_access_key() {
+    socket?.send(JSON.stringify({
+        kind: "set_access_key",
+        access_key_hash: ws.access_key_hash(access_key())
+    }));
+    Object.keys(users).forEach(share_access_key);
+}
+
+function share_access_key(public_key: string) {
+    socket?.send(JSON.stringify({
+        kind: "share_access_key",
+        recipient: public_key,
+        payload: ws.encrypt_message(access_key(), public_key)
+    }));
 }
 
 async function on_message(event: MessageEvent) {
@@ -121,12 +356,93 @@ async function on_message(event: MessageEvent) {
     const msg = JSON.parse(event.data);
     console.log(msg);
 
+    // Comes before any numbered payload of this connection
+    if (msg.kind === "challenge") {
+        nonce = msg.nonce;
+        if (!resume_token) {
+            last_seq = 0;
+            send_first();
+        }
+        return;
+    }
+
+    // A refused resume starts over on a fresh connection
+    if (msg.kind === "error" && msg.request === "resume") {
+        resume_token = null;
+        last_seq = 0;
+        send_first();
+        return;
+    }
+
+    // Payloads are numbered per device, and resent after a resume
+    if (msg.seq <= last_seq) return;
+    if (msg.seq > last_seq + 1)
+        console.warn(`missed payloads ${last_seq + 1} to ${msg.seq - 1}`);
+    last_seq = msg.seq;
+
     switch (msg.kind) {
+        case "session":
+            resume_token = msg.resume_token;
+            register_access_key();
+            sync_history();
+            break;
+        case "history":
+            for (const chunk of msg.chunks) {
+                let restored: Message[];
+                try {
+                    restored = JSON.parse(ws.decrypt_history(chunk.data, profile.private_key));
+                } catch (e) {
+                    console.warn("Can't restore history chunk", chunk.chunk_id, e);
+                    continue;
+                }
+                for (const m of restored) await messageStore.appendMessage(m);
+            }
+            load_stored_messages();
+            update_users_list();
+            break;
+        case "handle_info":
+            // Answer to the add contact form
+            if (msg.public_key) {
+                socket?.send(JSON.stringify({
+                    kind: "contact_request",
+                    user_id: msg.public_key
+                }));
+                append_server_message(`Contact request sent to @${msg.handle}.`);
+            } else {
+                append_server_message(`No one is called @${msg.handle}.`);
+            }
+            break;
+        case "contact_request":
+            const asker = msg.name ?? msg.user_id.slice(0, 12);
+            socket?.send(JSON.stringify({
+                kind: confirm(`${asker} wants to add you as a contact.`)
+                    ? "contact_accept"
+                    : "contact_remove",
+                user_id: msg.user_id
+            }));
+            break;
+        case "error":
+            console.warn(`${msg.request} refused: ${msg.message}`);
+            if (msg.request === "first" && msg.message === "device connected elsewhere") {
+                replaced = true;
+                append_server_message("This device connected from somewhere else, reload to use it here.");
+            }
+            break;
         case "new_user":
+            // Resuming resends everyone online, only announce newcomers
+            if (!users[msg.user.public_key])
+                append_server_message(`${msg.user.name} joined the chat.`);
             users[msg.user.public_key] = msg.user;
-            append_server_message(`${msg.user.name} joined the chat.`);
+            share_access_key(msg.user.public_key);
             update_users_list();
             break;
+        case "access_key":
+            try {
+                access_keys[msg.sender] = ws.decrypt_message(msg.payload, profile.private_key);
+            } catch (e) {
+                console.warn("Can't read access key from", msg.sender, e);
+            }
+            break;
         case "relay_message":
             const user = users[msg.sender];
             const text = ws.decrypt_message(msg.payload, profile.private_key);
@@ -135,17 +451,108 @@ async function on_message(event: MessageEvent) {
             if (gid && gid == profile.public_key) gid = user.public_key;
             else gid = null;
 
-            await messageStore.appendMessage({
+            const message: Message = {
                 sender: user.name,
                 payload: text,
-                groupId: gid
-            }, groupId !== gid);
-
-            if (groupId === gid) append_user_message(user.name, text);
+                groupId: gid,
+                messageId: msg.message_id,
+                timestamp: msg.received_at,
+                senderKey: msg.sender
+            };
+            const stored = await messageStore.appendMessage(message, groupId !== gid);
+            if (stored === null) break;
+
+            typing.delete(msg.sender);
+            update_typing_indicator();
+
+            if (groupId === gid) append_user_message(message);
             else update_users_list();
             break;
+        case "message_sent":
+            await store_sent(msg);
+            break;
+        case "access_key_revoked":
+            // Dropped after we blocked someone who may hold it
+            localStorage.removeItem(ACCESS_KEY);
+            register_access_key();
+            break;
+        case "sealed_message": {
+            // Sealed messages are DMs, the sender is only known once unsealed
+            let sealed: { sender: string, message: string };
+            try {
+                sealed = JSON.parse(ws.unseal_message(msg.payload, profile.private_key));
+            } catch (e) {
+                console.warn("Can't unseal message", msg.message_id, e);
+                break;
+            }
+            const from = users[sealed.sender];
+            if (!from) break;
+
+            const message: Message = {
+                sender: from.name,
+                payload: sealed.message,
+                groupId: from.public_key,
+                messageId: msg.message_id,
+                timestamp: msg.received_at,
+                senderKey: from.public_key,
+                sealed: true
+            };
+            const stored = await messageStore.appendMessage(message, groupId !== from.public_key);
+            if (stored === null) break;
+
+            if (groupId === from.public_key) append_user_message(message);
+            else update_users_list();
+            break;
+        }
+        case "message_edited":
+        case "message_deleted":
+        case "reaction": {
+            const from = users[msg.sender];
+            if (!from) break;
+
+            const text = msg.payload !== undefined
+                ? ws.decrypt_message(msg.payload, profile.private_key)
+                : "";
+            const changed = await messageStore.updateMessage(msg.target_id, m => {
+                if (msg.kind === "message_deleted") return false;
+                if (msg.kind === "message_edited") {
+                    m.payload = text;
+                    m.edited = true;
+                } else {
+                    m.reactions = m.reactions ?? {};
+                    if (text) m.reactions[from.public_key] = text;
+                    else delete m.reactions[from.public_key];
+                }
+                return true;
+            });
+            if (changed) load_stored_messages();
+            break;
+        }
+        case "presence":
+            if (users[msg.user_id]) {
+                users[msg.user_id].status = msg.status;
+                users[msg.user_id].status_text = msg.text;
+                update_users_list();
+            }
+            break;
+        case "devices":
+            if (users[msg.user_id]) users[msg.user_id].devices = msg.devices;
+            break;
+        case "handle":
+            if (users[msg.user_id]) {
+                users[msg.user_id].handle = msg.handle ?? undefined;
+                update_users_list();
+            }
+            break;
+        case "user_typing":
+            if (msg.typing) typing.set(msg.user_id, msg.group_id ?? null);
+            else typing.delete(msg.user_id);
+            update_typing_indicator();
+            break;
         case "user_left":
             delete users[msg.user_id];
+            typing.delete(msg.user_id);
+            update_typing_indicator();
             // const name = users[msg.user_id].name;
             // append_server_message(`${name} left the chat.`);
             update_users_list();
@@ -153,33 +560,142 @@ async function on_message(event: MessageEvent) {
     }
 }
 
-function ws_setup() {
-    socket = new WebSocket("/ws");
+// Store our copy of a message the server acknowledged
+async function store_sent(msg: any) {
+    if (profile == null) return;
+
+    // Group messages are acknowledged once per recipient
+    const sent = pending.get(msg.message_id);
+    if (!sent) return;
+    pending.delete(msg.message_id);
+
+    const message: Message = {
+        sender: profile.name,
+        payload: sent.text,
+        groupId: sent.groupId,
+        messageId: msg.message_id,
+        timestamp: msg.received_at,
+        senderKey: profile.public_key,
+        sealed: sent.sealed
+    };
+    const stored = await messageStore.appendMessage(message);
+    if (stored !== null && groupId === sent.groupId) append_user_message(message);
+}
+
+// Send over the anonymous connection, opening it if needed
+function send_sealed(payload: object) {
+    sealed_queue.push(JSON.stringify(payload));
+    if (sealed_socket?.readyState === WebSocket.OPEN) {
+        sealed_queue.splice(0).forEach(m => sealed_socket?.send(m));
+        return;
+    }
+    if (sealed_socket) return;
+
+    const csrf = document.querySelector('meta[name="rschat-csrf"]')?.getAttribute("content");
+    sealed_socket = new WebSocket(csrf ? `/ws?csrf=${encodeURIComponent(csrf)}` : "/ws", PROTOCOL);
+    sealed_socket.onopen = () => {
+        sealed_queue.splice(0).forEach(m => sealed_socket?.send(m));
+    };
+    sealed_socket.onclose = () => {
+        sealed_socket = null;
+    };
+    sealed_socket.onmessage = async (event: MessageEvent) => {
+        const msg = JSON.parse(event.data);
+        if (msg.kind === "message_sent") await store_sent(msg);
+    };
+}
+
+function send_first() {
+    if (socket == null) return;
+    if (profile == null) return;
+    if (nonce == null) return;
+
+    socket.send(JSON.stringify({
+        kind: "first",
+        public_key: profile.public_key,
+        name: profile.name,
+        signature: ws.sign_message(`rschat:first:${nonce}:${profile.public_key}`, profile.private_key),
+        device_id: device_id()
+    }));
+}
+
+function connect() {
+    // Filled in by the server when it requires the token on upgrade
+    const csrf = document.querySelector('meta[name="rschat-csrf"]')?.getAttribute("content");
+    socket = new WebSocket(csrf ? `/ws?csrf=${encodeURIComponent(csrf)}` : "/ws", PROTOCOL);
 
     socket.onopen = () => {
         if (socket == null) return;
-        if (profile == null) return;
-        if (message_form == null) return;
 
-        socket.send(JSON.stringify({
-            kind: "first",
-            public_key: profile.public_key,
-            name: profile.name
-        }));
+        // Otherwise `first` goes out once the challenge arrives
+        if (resume_token) {
+            socket.send(JSON.stringify({
+                kind: "resume",
+                resume_token,
+                last_seq
+            }));
+        }
+    };
+
+    // Reconnect after a drop, resuming the session if the server still has it
+    socket.onclose = () => {
+        socket = null;
+        if (!replaced) setTimeout(connect, RECONNECT_MS);
+    };
+
+    socket.onmessage = on_message;
+}
+
+function ws_setup() {
+    connect();
+
+    if (message_form) {
+        message_form.getElementsByTagName("input")[0].addEventListener("input", () => {
+            send_typing(true);
+        });
+
+        contact_form?.addEventListener("submit", (event: SubmitEvent) => {
+            event.preventDefault();
+            if (socket == null) return;
+
+            const handle = contact_form.getElementsByTagName("input")[0].value;
+            socket.send(JSON.stringify({ kind: "lookup_handle", handle }));
+            contact_form.reset();
+        });
+
+        presence_select?.addEventListener("change", (event: Event) => {
+            if (socket == null) return;
+            socket.send(JSON.stringify({
+                kind: "set_presence",
+                status: (event.target as HTMLSelectElement).value
+            }));
+        });
 
         message_form.addEventListener("submit", (event: SubmitEvent) => {
             event.preventDefault();
+            send_typing(false);
 
             if (event.target == null) return;
             if (profile == null) return;
 
             const text = message_form.getElementsByTagName("input")[0].value;
-            append_user_message(profile.name, text);
-            messageStore.appendMessage({
-                sender: profile.name,
-                payload: text,
-                groupId
-            });
+            // One id for every recipient, so the message can be edited later
+            const message_id = crypto.randomUUID();
+            const peer_key = groupId ? access_keys[groupId] : undefined;
+            pending.set(message_id, { text, groupId, sealed: !!peer_key });
+
+            // DMs go out sealed once the peer handed us their access key
+            if (groupId && peer_key) {
+                send_sealed({
+                    kind: "send_sealed",
+                    recipient: groupId,
+                    delivery_token: peer_key,
+                    payload: ws.seal_message(text, profile.private_key, groupId),
+                    message_id
+                });
+                (event.target as HTMLFormElement).reset();
+                return;
+            }
 
             Object.keys(users).forEach(user_public_key => {
                 const user = users[user_public_key];
@@ -189,16 +705,16 @@ function ws_setup() {
                         kind: "send_message",
                         recipient: user.public_key,
                         payload,
-                        group_id: groupId
+                        group_id: groupId,
+                        sent_at: Date.now(),
+                        message_id
                     }));
                 }
             });
 
             (event.target as HTMLFormElement).reset();
         });
-    };
-
-    socket.onmessage = on_message;
+    }
 
     groupId = null;
     load_stored_messages();
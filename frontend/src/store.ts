@@ -5,6 +5,12 @@ const MESSAGES_KEY = "messages";
 const MAX_MESSAGES = 100;
 const NULL_GROUP_ID = "__NULL_GROUP__";
 
+// Server time first, then message id so every peer gets the same order
+function by_time(a: StoredMessage, b: StoredMessage): number {
+    if (a.timestamp !== b.timestamp) return a.timestamp - b.timestamp;
+    return (a.messageId ?? "").localeCompare(b.messageId ?? "");
+}
+
 class MessageStore {
     private db: IDBDatabase | null = null;
 
@@ -53,12 +59,19 @@ class MessageStore {
         };
     }
 
-    async appendMessage(message: Message, is_unread: boolean = false): Promise<number> {
+    // Resolves to null if a message with the same server id is stored already
+    async appendMessage(message: Message, is_unread: boolean = false): Promise<number | null> {
         const db = this.ensureDb();
         return new Promise(async (resolve, reject) => {
             try {
                 const existingMessages = await this.getMessagesByGroupId(message.groupId);
 
+                if (message.messageId !== undefined &&
+                    existingMessages.some(m => m.messageId === message.messageId)) {
+                    resolve(null);
+                    return;
+                }
+
                 const transaction = db.transaction([MESSAGES_KEY], 'readwrite');
                 const store = transaction.objectStore(MESSAGES_KEY);
 
@@ -73,7 +86,10 @@ class MessageStore {
                     sender: message.sender,
                     payload: message.payload,
                     groupId: this.normalizeGroupId(message.groupId),
-                    timestamp: Date.now(),
+                    messageId: message.messageId,
+                    timestamp: message.timestamp ?? Date.now(),
+                    senderKey: message.senderKey,
+                    sealed: message.sealed,
                     is_unread
                 };
 
@@ -87,6 +103,35 @@ class MessageStore {
         });
     }
 
+    // Apply `change` to the message with this server id, deleting it if
+    // `change` returns false. Resolves to false if there is no such message.
+    async updateMessage(messageId: string, change: (m: StoredMessage) => boolean): Promise<boolean> {
+        const db = this.ensureDb();
+
+        return new Promise((resolve, reject) => {
+            const transaction = db.transaction([MESSAGES_KEY], 'readwrite');
+            const store = transaction.objectStore(MESSAGES_KEY);
+            const request = store.openCursor();
+
+            request.onsuccess = (event) => {
+                const cursor = (event.target as IDBRequest).result;
+                if (!cursor) {
+                    resolve(false);
+                    return;
+                }
+                const message = cursor.value as StoredMessage;
+                if (message.messageId !== messageId) {
+                    cursor.continue();
+                    return;
+                }
+                if (change(message)) cursor.update(message);
+                else cursor.delete();
+                resolve(true);
+            };
+            request.onerror = () => reject(request.error);
+        });
+    }
+
     async getMessagesByGroupId(groupId: string | null): Promise<StoredMessage[]> {
         const db = this.ensureDb();
 
@@ -100,7 +145,7 @@ class MessageStore {
 
             request.onsuccess = () => {
                 const messages = request.result.map(msg => this.denormalizeMessage(msg));
-                messages.sort((a, b) => a.timestamp - b.timestamp);
+                messages.sort(by_time);
                 resolve(messages);
             };
             request.onerror = () => reject(request.error);
@@ -117,7 +162,7 @@ class MessageStore {
 
             request.onsuccess = () => {
                 const messages = request.result.map(msg => this.denormalizeMessage(msg));
-                messages.sort((a, b) => a.timestamp - b.timestamp);
+                messages.sort(by_time);
                 resolve(messages);
             };
             request.onerror = () => reject(request.error);
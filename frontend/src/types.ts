@@ -1,18 +1,39 @@
+export type PresenceStatus = "online" | "away" | "busy";
+
+export interface Device {
+    id: string;
+    public_key?: string;
+}
+
 export interface User {
     id: string;
     name: string;
     public_key: string;
     private_key: string;
+    handle?: string;
+    status?: PresenceStatus;
+    status_text?: string;
+    devices?: Device[];
 };
 
 export interface Message {
     sender: string;
     payload: string;
     groupId: string | null;
+    // Server message id and receive time, for relayed messages
+    messageId?: string;
+    timestamp?: number;
+    // Public key of the sender, and whether it came sealed, which rules
+    // out editing and deleting it
+    senderKey?: string;
+    sealed?: boolean;
 }
 
 export interface StoredMessage extends Message {
     id?: number;
     timestamp: number;
     is_unread: boolean;
+    edited?: boolean;
+    // Emoji of each reacting user, by public key
+    reactions?: { [sender: string]: string };
 }
@@ -0,0 +1,11 @@
+//! The chat protocol shared by the server and clients: payloads, how
+//! they are encoded for each subprotocol, and WebSocket framing.
+
+mod payload;
+mod protocol;
+pub mod ws;
+
+pub use crate::payload::{
+    Chunk, Device, Envelope, Payload, PresenceStatus, User, now_millis,
+};
+pub use crate::protocol::{DecodeError, Encoding, Outbound, Protocol, Version};
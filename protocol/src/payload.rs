@@ -0,0 +1,488 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+/// Milliseconds since the epoch.
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum PresenceStatus {
+    #[default]
+    Online,
+    Away,
+    Busy,
+}
+
+/// A device as advertised to peers.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Device {
+    pub id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub public_key: Option<String>,
+}
+
+/// A user as announced to peers in `new_user`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct User {
+    pub id: String,
+    pub name: String,
+    pub public_key: Option<String>,
+
+    /// Unique handle claimed by this user's key.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub handle: Option<String>,
+
+    #[serde(default)]
+    pub status: PresenceStatus,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status_text: Option<String>,
+
+    /// Connected devices, sorted by id.
+    #[serde(default)]
+    pub devices: Vec<Device>,
+}
+
+/// Server metadata of a relayed message, outside its encrypted payload.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Envelope {
+    /// Server-generated id, the same for every device of the recipient.
+    pub message_id: String,
+    /// When the server received the message, in ms since the epoch.
+    pub received_at: u64,
+    /// When the sender says it sent the message, in ms since the epoch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sent_at: Option<u64>,
+}
+
+/// An encrypted history chunk, stored by the server for the other
+/// devices of the same identity.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Chunk {
+    /// Chosen by the uploader, e.g. its device id. Uploading the same id
+    /// again replaces the chunk.
+    pub chunk_id: String,
+    pub data: String,
+    /// Server time of the upload, in ms since the epoch.
+    pub uploaded_at: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "kind")]
+pub enum Payload {
+    #[serde(rename = "send_message")]
+    SendMessage {
+        recipient: String,
+        /// Deliver to this device only, instead of every device.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        device_id: Option<String>,
+        payload: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        group_id: Option<String>,
+        /// Client clock when sending, in ms since the epoch.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        sent_at: Option<u64>,
+        /// Id picked by the sender, so it can refer to the message later.
+        /// Generated by the server if missing.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        message_id: Option<String>,
+    },
+
+    #[serde(rename = "relay_message")]
+    RelayMessage {
+        sender: String,
+        payload: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        group_id: Option<String>,
+        #[serde(flatten)]
+        envelope: Envelope,
+    },
+
+    /// Answer to an accepted `send_message` or `send_sealed`, with the
+    /// envelope the recipient got, so the sender stores its own copy the
+    /// same way.
+    #[serde(rename = "message_sent")]
+    MessageSent {
+        recipient: String,
+        #[serde(flatten)]
+        envelope: Envelope,
+    },
+
+    /// Sent on every new connection, before anything else.
+    #[serde(rename = "challenge")]
+    Challenge { nonce: String },
+
+    /// Register a key pair, signed over
+    /// `rschat:first:{nonce}:{public_key}` with the `challenge` nonce of
+    /// this connection.
+    #[serde(rename = "first")]
+    First {
+        public_key: String,
+        name: String,
+        signature: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        device_id: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        device_key: Option<String>,
+    },
+
+    /// Reattach a dropped device instead of sending `first`. Payloads
+    /// numbered after `last_seq` are sent again.
+    #[serde(rename = "resume")]
+    Resume {
+        resume_token: String,
+        #[serde(default)]
+        last_seq: u64,
+    },
+
+    /// Sent to a device after `first` or `resume`. The token is only valid
+    /// for the next `resume`.
+    #[serde(rename = "session")]
+    Session {
+        device_id: String,
+        resume_token: String,
+    },
+
+    #[serde(rename = "new_user")]
+    NewUser { user: User },
+
+    #[serde(rename = "user_left")]
+    UserLeft { user_id: String },
+
+    #[serde(rename = "devices")]
+    Devices {
+        user_id: String,
+        devices: Vec<Device>,
+    },
+
+    #[serde(rename = "set_presence")]
+    SetPresence {
+        status: PresenceStatus,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        text: Option<String>,
+    },
+
+    #[serde(rename = "presence")]
+    Presence {
+        user_id: String,
+        status: PresenceStatus,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        text: Option<String>,
+    },
+
+    /// Typing in a DM with `recipient`, or in the group chat when unset.
+    #[serde(rename = "typing")]
+    Typing {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        recipient: Option<String>,
+        typing: bool,
+    },
+
+    #[serde(rename = "user_typing")]
+    UserTyping {
+        user_id: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        group_id: Option<String>,
+        typing: bool,
+    },
+
+    /// Bind `handle` to the sender's key, signed over
+    /// `rschat:claim_handle:{handle}:{public_key}:{timestamp}`.
+    #[serde(rename = "claim_handle")]
+    ClaimHandle {
+        handle: String,
+        timestamp: u64,
+        signature: String,
+    },
+
+    /// Signed over `rschat:rename_handle:{old}:{new}:{public_key}:{timestamp}`.
+    #[serde(rename = "rename_handle")]
+    RenameHandle {
+        handle: String,
+        timestamp: u64,
+        signature: String,
+    },
+
+    /// Signed over `rschat:release_handle:{handle}:{public_key}:{timestamp}`.
+    #[serde(rename = "release_handle")]
+    ReleaseHandle { timestamp: u64, signature: String },
+
+    #[serde(rename = "lookup_handle")]
+    LookupHandle { handle: String },
+
+    /// Answer to `lookup_handle`. `public_key` is unset if the handle is
+    /// free, `devices` lists the owner's connected devices if the
+    /// requester may see the owner.
+    #[serde(rename = "handle_info")]
+    HandleInfo {
+        handle: String,
+        public_key: Option<String>,
+        devices: Vec<Device>,
+    },
+
+    /// The handle of `user_id` changed.
+    #[serde(rename = "handle")]
+    Handle {
+        user_id: String,
+        handle: Option<String>,
+    },
+
+    /// Block or unblock `user_id`. Blocked users can't message you, and
+    /// neither of you sees the other online.
+    #[serde(rename = "set_blocked")]
+    SetBlocked { user_id: String, blocked: bool },
+
+    /// Mute or unmute the presence and typing of `user_id`.
+    #[serde(rename = "set_muted")]
+    SetMuted { user_id: String, muted: bool },
+
+    /// Current lists of the user, sent after login and every change.
+    #[serde(rename = "blocklist")]
+    Blocklist {
+        blocked: Vec<String>,
+        muted: Vec<String>,
+    },
+
+    /// Ask `user_id` to become a contact. Forwarded to them with the
+    /// requester's key and `name`.
+    #[serde(rename = "contact_request")]
+    ContactRequest {
+        user_id: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        name: Option<String>,
+    },
+
+    #[serde(rename = "contact_accept")]
+    ContactAccept { user_id: String },
+
+    /// Decline, cancel or end a contact with `user_id`.
+    #[serde(rename = "contact_remove")]
+    ContactRemove { user_id: String },
+
+    /// Current contacts of the user, sent after login and every change.
+    #[serde(rename = "contacts")]
+    ContactList {
+        contacts: Vec<String>,
+        incoming: Vec<String>,
+        outgoing: Vec<String>,
+    },
+
+    /// Accept sealed messages from holders of the access key hashing to
+    /// `access_key_hash` (hex SHA-256), or stop accepting them.
+    #[serde(rename = "set_access_key")]
+    SetAccessKey {
+        #[serde(default)]
+        access_key_hash: Option<String>,
+    },
+
+    /// Hand `recipient` an access key, encrypted for them. Relayed as
+    /// `access_key` if the two may message each other.
+    #[serde(rename = "share_access_key")]
+    ShareAccessKey { recipient: String, payload: String },
+
+    /// An access key `sender` shared with us, encrypted for us.
+    #[serde(rename = "access_key")]
+    AccessKey { sender: String, payload: String },
+
+    /// The server dropped our access key because we blocked someone who
+    /// may hold it. Register and share a new one to keep receiving sealed
+    /// messages.
+    #[serde(rename = "access_key_revoked")]
+    AccessKeyRevoked,
+
+    /// Relay `payload`, made with `seal_message`, without naming the
+    /// sender. Only accepted on a connection that never sent `first`.
+    #[serde(rename = "send_sealed")]
+    SendSealed {
+        recipient: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        device_id: Option<String>,
+        /// The recipient's access key.
+        delivery_token: String,
+        payload: String,
+        /// Id for the relayed message, a random one if unset or invalid.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        message_id: Option<String>,
+    },
+
+    #[serde(rename = "sealed_message")]
+    SealedMessage {
+        payload: String,
+        #[serde(flatten)]
+        envelope: Envelope,
+    },
+
+    /// Replace the encrypted text of message `target_id`. Only its
+    /// sender may edit it.
+    #[serde(rename = "edit_message")]
+    EditMessage {
+        recipient: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        device_id: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        group_id: Option<String>,
+        target_id: String,
+        payload: String,
+    },
+
+    #[serde(rename = "message_edited")]
+    MessageEdited {
+        sender: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        group_id: Option<String>,
+        target_id: String,
+        payload: String,
+        #[serde(flatten)]
+        envelope: Envelope,
+    },
+
+    /// Delete message `target_id` for everyone. Only its sender may
+    /// delete it.
+    #[serde(rename = "delete_message")]
+    DeleteMessage {
+        recipient: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        device_id: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        group_id: Option<String>,
+        target_id: String,
+    },
+
+    #[serde(rename = "message_deleted")]
+    MessageDeleted {
+        sender: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        group_id: Option<String>,
+        target_id: String,
+        #[serde(flatten)]
+        envelope: Envelope,
+    },
+
+    /// React to message `target_id`. The encrypted payload holds the
+    /// emoji, or nothing to take the reaction back.
+    #[serde(rename = "react")]
+    React {
+        recipient: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        device_id: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        group_id: Option<String>,
+        target_id: String,
+        payload: String,
+    },
+
+    #[serde(rename = "reaction")]
+    Reaction {
+        sender: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        group_id: Option<String>,
+        target_id: String,
+        payload: String,
+        #[serde(flatten)]
+        envelope: Envelope,
+    },
+
+    /// Store an encrypted history chunk for the other devices of the
+    /// same identity.
+    #[serde(rename = "upload_history")]
+    UploadHistory { chunk_id: String, data: String },
+
+    #[serde(rename = "fetch_history")]
+    FetchHistory,
+
+    /// Answer to `fetch_history`, oldest chunk first.
+    #[serde(rename = "history")]
+    History { chunks: Vec<Chunk> },
+
+    /// A request of kind `request` was refused.
+    #[serde(rename = "error")]
+    Error { request: String, message: String },
+}
+
+impl Payload {
+    /// Value of the `kind` tag this payload is serialized with.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Payload::SendMessage { .. } => "send_message",
+            Payload::RelayMessage { .. } => "relay_message",
+            Payload::MessageSent { .. } => "message_sent",
+            Payload::Challenge { .. } => "challenge",
+            Payload::First { .. } => "first",
+            Payload::Resume { .. } => "resume",
+            Payload::Session { .. } => "session",
+            Payload::NewUser { .. } => "new_user",
+            Payload::UserLeft { .. } => "user_left",
+            Payload::Devices { .. } => "devices",
+            Payload::SetPresence { .. } => "set_presence",
+            Payload::Presence { .. } => "presence",
+            Payload::Typing { .. } => "typing",
+            Payload::UserTyping { .. } => "user_typing",
+            Payload::ClaimHandle { .. } => "claim_handle",
+            Payload::RenameHandle { .. } => "rename_handle",
+            Payload::ReleaseHandle { .. } => "release_handle",
+            Payload::LookupHandle { .. } => "lookup_handle",
+            Payload::HandleInfo { .. } => "handle_info",
+            Payload::Handle { .. } => "handle",
+            Payload::SetBlocked { .. } => "set_blocked",
+            Payload::SetMuted { .. } => "set_muted",
+            Payload::Blocklist { .. } => "blocklist",
+            Payload::ContactRequest { .. } => "contact_request",
+            Payload::ContactAccept { .. } => "contact_accept",
+            Payload::ContactRemove { .. } => "contact_remove",
+            Payload::ContactList { .. } => "contacts",
+            Payload::SetAccessKey { .. } => "set_access_key",
+            Payload::ShareAccessKey { .. } => "share_access_key",
+            Payload::AccessKey { .. } => "access_key",
+            Payload::AccessKeyRevoked => "access_key_revoked",
+            Payload::SendSealed { .. } => "send_sealed",
+            Payload::SealedMessage { .. } => "sealed_message",
+            Payload::EditMessage { .. } => "edit_message",
+            Payload::MessageEdited { .. } => "message_edited",
+            Payload::DeleteMessage { .. } => "delete_message",
+            Payload::MessageDeleted { .. } => "message_deleted",
+            Payload::React { .. } => "react",
+            Payload::Reaction { .. } => "reaction",
+            Payload::UploadHistory { .. } => "upload_history",
+            Payload::FetchHistory => "fetch_history",
+            Payload::History { .. } => "history",
+            Payload::Error { .. } => "error",
+        }
+    }
+}
+
+#[cfg(test)]
+mod payload_tests {
+    use crate::{Envelope, Payload};
+
+    #[test]
+    fn test_relay_envelope_outside_payload() {
+        let msg = Payload::RelayMessage {
+            sender: "A".to_string(),
+            payload: "ciphertext".to_string(),
+            group_id: None,
+            envelope: Envelope {
+                message_id: "m1".to_string(),
+                received_at: 1700000000000,
+                sent_at: Some(1699999999000),
+            },
+        };
+
+        let json: serde_json::Value = serde_json::to_value(&msg).unwrap();
+        assert_eq!(json["kind"], "relay_message");
+        assert_eq!(json["message_id"], "m1");
+        assert_eq!(json["received_at"], 1700000000000u64);
+        assert_eq!(json["sent_at"], 1699999999000u64);
+        assert_eq!(json["payload"], "ciphertext");
+
+        let parsed: Payload = serde_json::from_value(json).unwrap();
+        assert!(matches!(
+            parsed,
+            Payload::RelayMessage { envelope, .. } if envelope.message_id == "m1"
+        ));
+    }
+}
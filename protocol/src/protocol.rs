@@ -0,0 +1,502 @@
+use std::fmt;
+
+use serde_json::{Map, Number, Value};
+
+use crate::Payload;
+
+/// Envelope fields, next to the payload's own fields in v1 and grouped
+/// under `envelope` in v2.
+const ENVELOPE_FIELDS: [&str; 3] = ["message_id", "received_at", "sent_at"];
+
+/// Fields holding hex ciphertexts, sent as raw bytes in MessagePack.
+const CIPHERTEXT_FIELDS: [&str; 2] = ["payload", "data"];
+
+/// Deepest MessagePack nesting accepted, the same as `serde_json`'s.
+const MAX_DEPTH: usize = 128;
+
+/// A frame to send: a payload as `Protocol::encode` made it, any other
+/// frame, or a close with its code.
+pub enum Outbound {
+    Text(String),
+    Binary(Vec<u8>),
+    Frame(u8, Vec<u8>),
+    Close(u16),
+}
+
+/// Version of the payload schema. `Payload` serializes as v1, other
+/// versions are translated from and to it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Version {
+    V1,
+    /// Envelope fields are grouped under `envelope`, both in relayed
+    /// messages and in requests naming a `message_id` or `sent_at`.
+    V2,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Encoding {
+    /// Text frames holding JSON.
+    Json,
+    /// Binary frames holding MessagePack, with ciphertexts as bin rather
+    /// than hex strings.
+    MessagePack,
+}
+
+/// Payload schema and encoding of a connection, agreed on as its
+/// WebSocket subprotocol.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Protocol {
+    pub version: Version,
+    pub encoding: Encoding,
+}
+
+impl Protocol {
+    /// Spoken to clients that don't ask for a subprotocol.
+    pub const LEGACY: Protocol = Protocol {
+        version: Version::V1,
+        encoding: Encoding::Json,
+    };
+
+    const SUPPORTED: [Protocol; 4] = [
+        Protocol::LEGACY,
+        Protocol {
+            version: Version::V2,
+            encoding: Encoding::Json,
+        },
+        Protocol {
+            version: Version::V1,
+            encoding: Encoding::MessagePack,
+        },
+        Protocol {
+            version: Version::V2,
+            encoding: Encoding::MessagePack,
+        },
+    ];
+
+    /// Subprotocol name, e.g. `rschat.v1.json`.
+    pub fn name(&self) -> &'static str {
+        match (self.version, self.encoding) {
+            (Version::V1, Encoding::Json) => "rschat.v1.json",
+            (Version::V2, Encoding::Json) => "rschat.v2.json",
+            (Version::V1, Encoding::MessagePack) => "rschat.v1.msgpack",
+            (Version::V2, Encoding::MessagePack) => "rschat.v2.msgpack",
+        }
+    }
+
+    /// Pick the first protocol of a `Sec-WebSocket-Protocol` header we
+    /// speak. Clients list them in order of preference.
+    pub fn negotiate(offered: &str) -> Option<Protocol> {
+        offered.split(',').map(str::trim).find_map(|name| {
+            Protocol::SUPPORTED
+                .into_iter()
+                .find(|p| p.name().eq_ignore_ascii_case(name))
+        })
+    }
+
+    /// Frame carrying `value`, a payload as `Payload` serializes it.
+    pub fn encode(&self, value: &Value) -> Outbound {
+        let value = match self.version {
+            Version::V1 => value.clone(),
+            Version::V2 => group_envelope(value.clone()),
+        };
+
+        match self.encoding {
+            Encoding::Json => Outbound::Text(value.to_string()),
+            Encoding::MessagePack => {
+                let mut data = Vec::new();
+                rmpv::encode::write_value(
+                    &mut data,
+                    &to_msgpack(&value, false),
+                )
+                .expect("writing to a Vec can't fail");
+                Outbound::Binary(data)
+            }
+        }
+    }
+
+    /// Parse a message from the client, whether it came in a text or a
+    /// binary frame.
+    pub fn decode(&self, data: &[u8]) -> Result<Payload, DecodeError> {
+        let value = match self.encoding {
+            Encoding::Json => {
+                serde_json::from_slice(data).map_err(DecodeError::Payload)?
+            }
+            Encoding::MessagePack => {
+                let mut rd = data;
+                let value =
+                    rmpv::decode::read_value_with_max_depth(&mut rd, MAX_DEPTH)
+                        .map_err(|_| DecodeError::Syntax)?;
+                if !rd.is_empty() {
+                    return Err(DecodeError::Syntax);
+                }
+                from_msgpack(value)?
+            }
+        };
+        let value = match self.version {
+            Version::V1 => value,
+            Version::V2 => inline_envelope(value),
+        };
+
+        serde_json::from_value(value).map_err(DecodeError::Payload)
+    }
+}
+
+#[derive(Debug)]
+pub enum DecodeError {
+    /// Not one well-formed MessagePack value.
+    Syntax,
+    /// MessagePack without a JSON equivalent, like non-string map keys.
+    Unsupported,
+    /// Not a valid payload.
+    Payload(serde_json::Error),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::Syntax => f.write_str("malformed MessagePack"),
+            DecodeError::Unsupported => {
+                f.write_str("MessagePack type not allowed in payloads")
+            }
+            // serde errors may quote the request, which is not redacted
+            DecodeError::Payload(_) => f.write_str("not a valid payload"),
+        }
+    }
+}
+
+/// MessagePack form of a JSON value. Strings of `CIPHERTEXT_FIELDS` that
+/// are lowercase hex become bin, so they decode to the same string.
+fn to_msgpack(value: &Value, ciphertext: bool) -> rmpv::Value {
+    match value {
+        Value::Null => rmpv::Value::Nil,
+        Value::Bool(b) => rmpv::Value::from(*b),
+        Value::Number(n) => match (n.as_u64(), n.as_i64()) {
+            (Some(n), _) => rmpv::Value::from(n),
+            (None, Some(n)) => rmpv::Value::from(n),
+            _ => rmpv::Value::from(n.as_f64().unwrap_or_default()),
+        },
+        Value::String(s) if ciphertext && is_lower_hex(s) => {
+            rmpv::Value::Binary(hex::decode(s).unwrap_or_default())
+        }
+        Value::String(s) => rmpv::Value::from(s.as_str()),
+        Value::Array(items) => rmpv::Value::Array(
+            items.iter().map(|v| to_msgpack(v, false)).collect(),
+        ),
+        Value::Object(obj) => rmpv::Value::Map(
+            obj.iter()
+                .map(|(k, v)| {
+                    let ciphertext = CIPHERTEXT_FIELDS.contains(&k.as_str());
+                    (rmpv::Value::from(k.as_str()), to_msgpack(v, ciphertext))
+                })
+                .collect(),
+        ),
+    }
+}
+
+/// JSON form of a MessagePack value, with bin as lowercase hex.
+fn from_msgpack(value: rmpv::Value) -> Result<Value, DecodeError> {
+    Ok(match value {
+        rmpv::Value::Nil => Value::Null,
+        rmpv::Value::Boolean(b) => Value::Bool(b),
+        rmpv::Value::Integer(n) => match (n.as_u64(), n.as_i64()) {
+            (Some(n), _) => Value::from(n),
+            (None, Some(n)) => Value::from(n),
+            _ => return Err(DecodeError::Unsupported),
+        },
+        rmpv::Value::F32(n) => float(f64::from(n))?,
+        rmpv::Value::F64(n) => float(n)?,
+        rmpv::Value::String(s) => {
+            Value::String(s.into_str().ok_or(DecodeError::Unsupported)?)
+        }
+        rmpv::Value::Binary(data) => Value::String(hex::encode(data)),
+        rmpv::Value::Array(items) => Value::Array(
+            items
+                .into_iter()
+                .map(from_msgpack)
+                .collect::<Result<_, _>>()?,
+        ),
+        rmpv::Value::Map(entries) => {
+            let mut obj = Map::new();
+            for (k, v) in entries {
+                let rmpv::Value::String(k) = k else {
+                    return Err(DecodeError::Unsupported);
+                };
+                let k = k.into_str().ok_or(DecodeError::Unsupported)?;
+                obj.insert(k, from_msgpack(v)?);
+            }
+            Value::Object(obj)
+        }
+        rmpv::Value::Ext(..) => return Err(DecodeError::Unsupported),
+    })
+}
+
+fn float(n: f64) -> Result<Value, DecodeError> {
+    Number::from_f64(n)
+        .map(Value::Number)
+        .ok_or(DecodeError::Unsupported)
+}
+
+fn is_lower_hex(s: &str) -> bool {
+    s.len().is_multiple_of(2)
+        && s.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+/// v1 to v2: move the envelope fields of a payload under `envelope`.
+fn group_envelope(mut value: Value) -> Value {
+    if let Some(obj) = value.as_object_mut() {
+        let envelope: Map<String, Value> = ENVELOPE_FIELDS
+            .iter()
+            .filter_map(|field| obj.remove_entry(*field))
+            .collect();
+        if !envelope.is_empty() {
+            obj.insert("envelope".to_string(), Value::Object(envelope));
+        }
+    }
+    value
+}
+
+/// v2 to v1: put the fields under `envelope` back next to the others.
+fn inline_envelope(mut value: Value) -> Value {
+    if let Some(obj) = value.as_object_mut()
+        && let Some(Value::Object(envelope)) = obj.remove("envelope")
+    {
+        for (field, val) in envelope {
+            if ENVELOPE_FIELDS.contains(&field.as_str()) {
+                obj.insert(field, val);
+            }
+        }
+    }
+    value
+}
+
+#[cfg(test)]
+mod protocol_tests {
+    use serde_json::json;
+
+    use crate::protocol::{DecodeError, Encoding, Outbound, Protocol, Version};
+    use crate::{Chunk, Envelope, Payload};
+
+    const V2_JSON: Protocol = Protocol {
+        version: Version::V2,
+        encoding: Encoding::Json,
+    };
+    const V1_MSGPACK: Protocol = Protocol {
+        version: Version::V1,
+        encoding: Encoding::MessagePack,
+    };
+    const V2_MSGPACK: Protocol = Protocol {
+        version: Version::V2,
+        encoding: Encoding::MessagePack,
+    };
+
+    fn text(outbound: Outbound) -> serde_json::Value {
+        match outbound {
+            Outbound::Text(text) => serde_json::from_str(&text).unwrap(),
+            _ => panic!("expected a text frame"),
+        }
+    }
+
+    /// Bytes a client would send back for `outbound`.
+    fn data(outbound: Outbound) -> Vec<u8> {
+        match outbound {
+            Outbound::Text(text) => text.into_bytes(),
+            Outbound::Binary(data) => data,
+            _ => panic!("expected a data frame"),
+        }
+    }
+
+    fn sample_payloads() -> Vec<Payload> {
+        vec![
+            Payload::RelayMessage {
+                sender: "04ab".to_string(),
+                payload: "00ff10ee".repeat(8),
+                group_id: Some("group".to_string()),
+                envelope: Envelope {
+                    message_id: "m1".to_string(),
+                    received_at: 1700000000000,
+                    sent_at: Some(1699999999000),
+                },
+            },
+            Payload::SendMessage {
+                recipient: "04cd".to_string(),
+                device_id: None,
+                // Not hex, stays a string
+                payload: "Hello".to_string(),
+                group_id: None,
+                sent_at: None,
+                message_id: None,
+            },
+            Payload::History {
+                chunks: vec![Chunk {
+                    chunk_id: "laptop".to_string(),
+                    data: "deadbeef".to_string(),
+                    uploaded_at: 1,
+                }],
+            },
+            Payload::Typing {
+                recipient: None,
+                typing: true,
+            },
+            Payload::AccessKeyRevoked,
+        ]
+    }
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(
+            Protocol::negotiate("rschat.v1.json"),
+            Some(Protocol::LEGACY)
+        );
+        // Client preference wins over ours
+        assert_eq!(
+            Protocol::negotiate(
+                "rschat.v9.json, RSCHAT.V2.JSON, rschat.v1.json"
+            ),
+            Some(V2_JSON)
+        );
+        assert_eq!(Protocol::negotiate("chat, superchat"), None);
+        assert_eq!(V2_JSON.name(), "rschat.v2.json");
+    }
+
+    #[test]
+    fn test_v2_groups_envelope() {
+        let msg = Payload::RelayMessage {
+            sender: "A".to_string(),
+            payload: "ciphertext".to_string(),
+            group_id: None,
+            envelope: Envelope {
+                message_id: "m1".to_string(),
+                received_at: 1700000000000,
+                sent_at: None,
+            },
+        };
+        let value = serde_json::to_value(&msg).unwrap();
+
+        assert_eq!(text(Protocol::LEGACY.encode(&value)), value);
+        assert_eq!(
+            text(V2_JSON.encode(&value)),
+            json!({
+                "kind": "relay_message",
+                "sender": "A",
+                "payload": "ciphertext",
+                "envelope": {
+                    "message_id": "m1",
+                    "received_at": 1700000000000u64,
+                },
+            })
+        );
+
+        // Payloads without envelope fields are the same in both versions
+        let challenge =
+            serde_json::to_value(Payload::Challenge { nonce: "n".into() })
+                .unwrap();
+        assert_eq!(text(V2_JSON.encode(&challenge)), challenge);
+    }
+
+    #[test]
+    fn test_v2_request_translated() {
+        let req = r#"{"kind": "send_message", "recipient": "B",
+            "payload": "ciphertext",
+            "envelope": {"message_id": "m1", "sent_at": 5}}"#;
+
+        match V2_JSON.decode(req.as_bytes()).unwrap() {
+            Payload::SendMessage {
+                message_id,
+                sent_at,
+                ..
+            } => {
+                assert_eq!(message_id.as_deref(), Some("m1"));
+                assert_eq!(sent_at, Some(5));
+            }
+            _ => panic!("expected send_message"),
+        }
+
+        // v1 has no `envelope`, so the fields stay unset
+        match Protocol::LEGACY.decode(req.as_bytes()).unwrap() {
+            Payload::SendMessage { message_id, .. } => {
+                assert_eq!(message_id, None)
+            }
+            _ => panic!("expected send_message"),
+        }
+    }
+
+    #[test]
+    fn test_encodings_round_trip() {
+        for payload in sample_payloads() {
+            let value = serde_json::to_value(&payload).unwrap();
+
+            for protocol in [Protocol::LEGACY, V2_JSON, V1_MSGPACK, V2_MSGPACK]
+            {
+                let sent = data(protocol.encode(&value));
+                let parsed = protocol.decode(&sent).unwrap();
+                assert_eq!(
+                    serde_json::to_value(&parsed).unwrap(),
+                    value,
+                    "{}",
+                    protocol.name()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_msgpack_ciphertext_as_bytes() {
+        let payload = &sample_payloads()[0];
+        let value = serde_json::to_value(payload).unwrap();
+
+        let Outbound::Binary(msgpack) = V1_MSGPACK.encode(&value) else {
+            panic!("expected a binary frame");
+        };
+        let json = data(Protocol::LEGACY.encode(&value));
+        // 32 bytes of ciphertext instead of 64 hex digits
+        assert!(msgpack.len() + 32 < json.len());
+
+        let decoded = rmpv::decode::read_value(&mut &msgpack[..]).unwrap();
+        let field = |name: &str| {
+            decoded
+                .as_map()
+                .unwrap()
+                .iter()
+                .find(|(k, _)| k.as_str() == Some(name))
+                .map(|(_, v)| v.clone())
+                .unwrap()
+        };
+        assert_eq!(field("payload").as_slice().unwrap().len(), 32);
+        assert_eq!(field("sender").as_str(), Some("04ab"));
+    }
+
+    #[test]
+    fn test_msgpack_rejects_malformed() {
+        let mut data = Vec::new();
+        let typing = rmpv::Value::Map(vec![
+            ("kind".into(), "typing".into()),
+            ("typing".into(), true.into()),
+        ]);
+        rmpv::encode::write_value(&mut data, &typing).unwrap();
+        assert!(V1_MSGPACK.decode(&data).is_ok());
+
+        // Trailing bytes
+        let mut trailing = data.clone();
+        trailing.push(0xc0);
+        assert!(matches!(
+            V1_MSGPACK.decode(&trailing),
+            Err(DecodeError::Syntax)
+        ));
+        // Truncated
+        assert!(matches!(
+            V1_MSGPACK.decode(&data[..data.len() - 1]),
+            Err(DecodeError::Syntax)
+        ));
+        // Non-string key
+        let mut data = Vec::new();
+        let map = rmpv::Value::Map(vec![(1.into(), "typing".into())]);
+        rmpv::encode::write_value(&mut data, &map).unwrap();
+        assert!(matches!(
+            V1_MSGPACK.decode(&data),
+            Err(DecodeError::Unsupported)
+        ));
+        // Nested too deep
+        let deep = [vec![0x91; 1000], vec![0xc0]].concat();
+        assert!(matches!(V1_MSGPACK.decode(&deep), Err(DecodeError::Syntax)));
+    }
+}
@@ -0,0 +1,1044 @@
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as B64;
+use sha1::{Digest, Sha1};
+
+/// Appended to the client's key to make `Sec-WebSocket-Accept`.
+pub const WS_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+///
+/// WebSocket protocol frame parser
+///
+/// Reference: <https://websocket.org/guides/websocket-protocol/>
+///
+pub mod frame {
+    use bytes::{BufMut, BytesMut};
+
+    pub const OPCODE_CONTINUATION: u8 = 0x0;
+    pub const OPCODE_TEXT: u8 = 0x1;
+    pub const OPCODE_BINARY: u8 = 0x2;
+    pub const OPCODE_CLOSE: u8 = 0x8;
+    pub const OPCODE_PING: u8 = 0x9;
+    pub const OPCODE_PONG: u8 = 0xa;
+
+    pub const CLOSE_NORMAL: u16 = 1000;
+    pub const CLOSE_GOING_AWAY: u16 = 1001;
+    pub const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+    pub const CLOSE_INVALID_DATA: u16 = 1007;
+    pub const CLOSE_POLICY_VIOLATION: u16 = 1008;
+    pub const CLOSE_TOO_BIG: u16 = 1009;
+
+    #[derive(Debug, PartialEq)]
+    pub enum FrameError {
+        /// Buffer does not hold the whole frame yet.
+        Incomplete,
+        /// Frame or reassembled message is over the configured limit.
+        TooBig,
+        /// Frame breaks RFC 6455 framing rules.
+        Protocol,
+        /// Text message is not valid UTF-8.
+        InvalidUtf8,
+    }
+
+    impl FrameError {
+        /// Close code to send to the peer for this error.
+        pub fn close_code(&self) -> u16 {
+            match self {
+                FrameError::TooBig => CLOSE_TOO_BIG,
+                FrameError::InvalidUtf8 => CLOSE_INVALID_DATA,
+                _ => CLOSE_PROTOCOL_ERROR,
+            }
+        }
+    }
+
+    pub struct FrameHeader {
+        pub fin: bool,
+        /// Compressed message, with permessage-deflate.
+        pub rsv1: bool,
+        pub opcode: u8,
+        pub mask: Option<[u8; 4]>,
+        pub len: u64,
+        pub header_len: usize,
+    }
+
+    impl FrameHeader {
+        pub fn is_control(&self) -> bool {
+            self.opcode & 0x8 != 0
+        }
+    }
+
+    /// Emplace Websocket frame with payload into buffer.
+    pub fn set_frame(buf: &mut BytesMut, opcode: u8, payload: &[u8]) -> usize {
+        put_frame(buf, 0x80 | opcode, payload, None) // FIN + opcode
+    }
+
+    /// Emplace a frame masked with `mask`, as clients must send them.
+    pub fn set_masked(
+        buf: &mut BytesMut,
+        opcode: u8,
+        payload: &[u8],
+        mask: [u8; 4],
+    ) -> usize {
+        put_frame(buf, 0x80 | opcode, payload, Some(mask))
+    }
+
+    /// Emplace Websocket frame with a deflated payload into buffer.
+    pub fn set_compressed(
+        buf: &mut BytesMut,
+        opcode: u8,
+        payload: &[u8],
+    ) -> usize {
+        put_frame(buf, 0xc0 | opcode, payload, None) // FIN + RSV1 + opcode
+    }
+
+    fn put_frame(
+        buf: &mut BytesMut,
+        first: u8,
+        payload: &[u8],
+        mask: Option<[u8; 4]>,
+    ) -> usize {
+        let start_len = buf.len();
+
+        buf.put_u8(first);
+
+        let len = payload.len();
+        let masked = if mask.is_some() { 0x80 } else { 0 };
+
+        if len <= 125 {
+            buf.put_u8(masked | len as u8);
+        } else if len < 65536 {
+            buf.put_u8(masked | 126);
+            buf.put_u16(len as u16);
+        } else {
+            buf.put_u8(masked | 127);
+            buf.put_u64(len as u64);
+        }
+
+        match mask {
+            Some(mask) => {
+                buf.extend_from_slice(&mask);
+                buf.extend(
+                    payload.iter().enumerate().map(|(x, b)| b ^ mask[x % 4]),
+                );
+            }
+            None => buf.extend_from_slice(payload),
+        }
+
+        buf.len() - start_len
+    }
+
+    /// Emplace Websocket Text frame with message into buffer.
+    pub fn set_text(buf: &mut BytesMut, msg: &str) -> usize {
+        set_frame(buf, OPCODE_TEXT, msg.as_bytes())
+    }
+
+    /// Emplace Websocket Close frame with status code into buffer.
+    pub fn set_close(buf: &mut BytesMut, code: u16, reason: &str) -> usize {
+        let mut payload = Vec::with_capacity(2 + reason.len());
+        payload.extend_from_slice(&code.to_be_bytes());
+        payload.extend_from_slice(reason.as_bytes());
+
+        set_frame(buf, OPCODE_CLOSE, &payload)
+    }
+
+    /// Parse frame header without touching the payload.
+    pub fn get_header(buf: &[u8]) -> Result<FrameHeader, FrameError> {
+        if buf.len() < 2 {
+            return Err(FrameError::Incomplete);
+        }
+
+        if buf[0] & 0x30 != 0 {
+            // RSV bits without a negotiated extension
+            return Err(FrameError::Protocol);
+        }
+
+        let fin = (buf[0] & 0x80) != 0;
+        let rsv1 = (buf[0] & 0x40) != 0;
+        let opcode = buf[0] & 0x0f;
+        let masked = ((buf[1] & 0x80) >> 7) == 1;
+        let size_encoding = buf[1] & 0x7f;
+
+        let mut i: usize = 2;
+
+        let len = if size_encoding <= 125 {
+            size_encoding as u64
+        } else if size_encoding == 126 {
+            if buf.len() < i + 2 {
+                return Err(FrameError::Incomplete);
+            }
+            let size = u16::from_be_bytes([buf[i], buf[i + 1]]) as u64;
+            i += 2;
+            size
+        } else {
+            if buf.len() < i + 8 {
+                return Err(FrameError::Incomplete);
+            }
+            let mut bytes = [0u8; 8];
+            bytes.copy_from_slice(&buf[i..i + 8]);
+            i += 8;
+
+            let size = u64::from_be_bytes(bytes);
+            if size >> 63 != 0 {
+                return Err(FrameError::Protocol);
+            }
+            size
+        };
+
+        let mask = if masked {
+            if buf.len() < i + 4 {
+                return Err(FrameError::Incomplete);
+            }
+            let mut mask = [0u8; 4];
+            mask.copy_from_slice(&buf[i..i + 4]);
+            i += 4;
+            Some(mask)
+        } else {
+            None
+        };
+
+        let header = FrameHeader {
+            fin,
+            rsv1,
+            opcode,
+            mask,
+            len,
+            header_len: i,
+        };
+
+        if header.is_control() && (!fin || rsv1 || len > 125) {
+            return Err(FrameError::Protocol);
+        }
+
+        Ok(header)
+    }
+
+    /// Extract unmasked payload of a frame whose header was already parsed.
+    pub fn get_payload(
+        buf: &[u8],
+        header: &FrameHeader,
+    ) -> Result<Vec<u8>, FrameError> {
+        let start = header.header_len;
+        let end = start
+            .checked_add(header.len as usize)
+            .ok_or(FrameError::TooBig)?;
+
+        if buf.len() < end {
+            return Err(FrameError::Incomplete);
+        }
+
+        let mut data = buf[start..end].to_vec();
+
+        if let Some(mask) = header.mask {
+            for (x, byte) in data.iter_mut().enumerate() {
+                *byte ^= mask[x % 4];
+            }
+        }
+
+        Ok(data)
+    }
+
+    /// Extract header and payload of a frame, rejecting payloads larger
+    /// than `max_size` before they are allocated.
+    pub fn get_frame(
+        buf: &[u8],
+        max_size: usize,
+    ) -> Result<(FrameHeader, Vec<u8>), FrameError> {
+        let header = get_header(buf)?;
+
+        if header.len > max_size as u64 {
+            return Err(FrameError::TooBig);
+        }
+
+        let data = get_payload(buf, &header)?;
+        Ok((header, data))
+    }
+}
+
+///
+/// WebSocket message reassembly on top of [`frame`]
+///
+pub mod message {
+    use std::sync::Arc;
+
+    use bytes::{Buf, BytesMut};
+
+    use super::deflate::Inflater;
+    use super::frame::{self, FrameError};
+
+    /// Told of every frame a `Reader` takes in, e.g. to count them.
+    pub trait FrameCounter: Send + Sync {
+        fn frame_in(&self, opcode: u8);
+    }
+
+    pub enum Message {
+        Text(String),
+        Binary(Vec<u8>),
+        Ping(Vec<u8>),
+        Pong,
+        Close(Option<u16>),
+    }
+
+    /// Pulls complete messages out of a read buffer, enforcing frame and
+    /// message size limits on the header before anything is allocated.
+    pub struct Reader {
+        max_frame_size: usize,
+        max_message_size: usize,
+        opcode: Option<u8>,
+        data: Vec<u8>,
+        inflater: Option<Inflater>,
+        /// Whether the message being reassembled is compressed.
+        compressed: bool,
+        /// Refuse unmasked frames, as a server must.
+        require_mask: bool,
+        metrics: Option<Arc<dyn FrameCounter>>,
+    }
+
+    impl Reader {
+        pub fn new(max_frame_size: usize, max_message_size: usize) -> Self {
+            Reader {
+                max_frame_size,
+                max_message_size,
+                opcode: None,
+                data: Vec::new(),
+                inflater: None,
+                compressed: false,
+                require_mask: false,
+                metrics: None,
+            }
+        }
+
+        /// Fail on frames without a mask, which clients must always set
+        /// (RFC 6455 section 5.1).
+        pub fn require_mask(&mut self) {
+            self.require_mask = true;
+        }
+
+        /// Accept messages compressed with permessage-deflate.
+        pub fn set_inflater(&mut self, inflater: Inflater) {
+            self.inflater = Some(inflater);
+        }
+
+        /// Count incoming frames in `metrics`.
+        pub fn set_metrics(&mut self, metrics: Arc<dyn FrameCounter>) {
+            self.metrics = Some(metrics);
+        }
+
+        /// Decode the next message from `buf`, consuming its frames.
+        /// Returns `Ok(None)` when more bytes must be read first.
+        pub fn next(
+            &mut self,
+            buf: &mut BytesMut,
+        ) -> Result<Option<Message>, FrameError> {
+            loop {
+                let header = match frame::get_header(buf) {
+                    Ok(h) => h,
+                    Err(FrameError::Incomplete) => return Ok(None),
+                    Err(e) => return Err(e),
+                };
+
+                if self.require_mask && header.mask.is_none() {
+                    return Err(FrameError::Protocol);
+                }
+
+                if header.len > self.max_frame_size as u64 {
+                    return Err(FrameError::TooBig);
+                }
+
+                if !header.is_control()
+                    && self.data.len() as u64 + header.len
+                        > self.max_message_size as u64
+                {
+                    return Err(FrameError::TooBig);
+                }
+
+                let data = match frame::get_payload(buf, &header) {
+                    Ok(d) => d,
+                    Err(FrameError::Incomplete) => return Ok(None),
+                    Err(e) => return Err(e),
+                };
+
+                buf.advance(header.header_len + data.len());
+                if let Some(metrics) = &self.metrics {
+                    metrics.frame_in(header.opcode);
+                }
+
+                match header.opcode {
+                    frame::OPCODE_PING => return Ok(Some(Message::Ping(data))),
+                    frame::OPCODE_PONG => return Ok(Some(Message::Pong)),
+                    frame::OPCODE_CLOSE => {
+                        let code = data
+                            .get(..2)
+                            .map(|c| u16::from_be_bytes([c[0], c[1]]));
+                        return Ok(Some(Message::Close(code)));
+                    }
+                    frame::OPCODE_CONTINUATION => {
+                        if self.opcode.is_none() || header.rsv1 {
+                            return Err(FrameError::Protocol);
+                        }
+                    }
+                    frame::OPCODE_TEXT | frame::OPCODE_BINARY => {
+                        if self.opcode.is_some()
+                            || header.rsv1 && self.inflater.is_none()
+                        {
+                            return Err(FrameError::Protocol);
+                        }
+                        self.opcode = Some(header.opcode);
+                        self.compressed = header.rsv1;
+                    }
+                    _ => return Err(FrameError::Protocol),
+                }
+
+                self.data.extend_from_slice(&data);
+
+                if !header.fin {
+                    continue;
+                }
+
+                let mut data = std::mem::take(&mut self.data);
+
+                if std::mem::take(&mut self.compressed)
+                    && let Some(inflater) = self.inflater.as_mut()
+                {
+                    data = inflater.decompress(&data, self.max_message_size)?;
+                }
+
+                return match self.opcode.take() {
+                    Some(frame::OPCODE_TEXT) => String::from_utf8(data)
+                        .map(|s| Some(Message::Text(s)))
+                        .map_err(|_| FrameError::InvalidUtf8),
+                    _ => Ok(Some(Message::Binary(data))),
+                };
+            }
+        }
+    }
+}
+
+///
+/// permessage-deflate extension
+///
+/// Reference: <https://datatracker.ietf.org/doc/html/rfc7692>
+///
+pub mod deflate {
+    use flate2::{
+        Compress, Compression, Decompress, FlushCompress, FlushDecompress,
+    };
+
+    use super::frame::FrameError;
+
+    /// Empty stored block ending every compressed message, left out on
+    /// the wire.
+    const TRAILER: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+    /// Parameters agreed on in `Sec-WebSocket-Extensions`.
+    #[derive(Debug, Default, PartialEq)]
+    pub struct Params {
+        /// Reset our compressor after every message.
+        pub server_no_context_takeover: bool,
+        /// The client resets its compressor, so we reset our decompressor.
+        pub client_no_context_takeover: bool,
+        /// Echoed if the client asked for it. Only 15 is supported.
+        pub server_max_window_bits: Option<u8>,
+    }
+
+    impl Params {
+        /// Value of the `Sec-WebSocket-Extensions` response header.
+        pub fn response(&self) -> String {
+            let mut out = String::from("permessage-deflate");
+            if self.server_no_context_takeover {
+                out.push_str("; server_no_context_takeover");
+            }
+            if self.client_no_context_takeover {
+                out.push_str("; client_no_context_takeover");
+            }
+            if let Some(bits) = self.server_max_window_bits {
+                out.push_str(&format!("; server_max_window_bits={bits}"));
+            }
+            out
+        }
+    }
+
+    /// Pick the first permessage-deflate offer of a
+    /// `Sec-WebSocket-Extensions` header we can accept.
+    pub fn negotiate(extensions: &str) -> Option<Params> {
+        extensions.split(',').find_map(accept_offer)
+    }
+
+    fn accept_offer(offer: &str) -> Option<Params> {
+        let mut parts = offer.split(';').map(str::trim);
+        if !parts.next()?.eq_ignore_ascii_case("permessage-deflate") {
+            return None;
+        }
+
+        let mut params = Params::default();
+        let mut seen = Vec::new();
+
+        for part in parts {
+            let (name, value) = match part.split_once('=') {
+                Some((name, value)) => {
+                    (name.trim(), Some(value.trim().trim_matches('"')))
+                }
+                None => (part, None),
+            };
+            let name = name.to_ascii_lowercase();
+            if seen.contains(&name) {
+                return None;
+            }
+
+            let bits = value.map(|v| v.parse::<u8>().ok());
+            match (name.as_str(), bits) {
+                ("server_no_context_takeover", None) => {
+                    params.server_no_context_takeover = true;
+                }
+                ("client_no_context_takeover", None) => {
+                    params.client_no_context_takeover = true;
+                }
+                // A smaller window can't be set on the compressor.
+                ("server_max_window_bits", Some(Some(15))) => {
+                    params.server_max_window_bits = Some(15);
+                }
+                // Our decompressor handles any window size.
+                ("client_max_window_bits", None) => {}
+                ("client_max_window_bits", Some(Some(8..=15))) => {}
+                _ => return None,
+            }
+            seen.push(name);
+        }
+
+        Some(params)
+    }
+
+    /// Compresses outgoing messages.
+    pub struct Deflater {
+        compress: Compress,
+        reset: bool,
+        threshold: usize,
+    }
+
+    impl Deflater {
+        /// Messages shorter than `threshold` bytes are sent uncompressed.
+        pub fn new(params: &Params, threshold: usize) -> Self {
+            Deflater {
+                compress: Compress::new(Compression::default(), false),
+                reset: params.server_no_context_takeover,
+                threshold,
+            }
+        }
+
+        /// Compressed `data`, or `None` to send it as it is.
+        pub fn compress(&mut self, data: &[u8]) -> Option<Vec<u8>> {
+            if data.len() < self.threshold {
+                return None;
+            }
+
+            let mut out = Vec::with_capacity(data.len() / 2 + 64);
+            let mut consumed = 0;
+
+            loop {
+                if out.capacity() - out.len() < 64 {
+                    out.reserve(out.capacity().max(1024));
+                }
+
+                let before = self.compress.total_in();
+                let result = self.compress.compress_vec(
+                    &data[consumed..],
+                    &mut out,
+                    FlushCompress::Sync,
+                );
+                if result.is_err() {
+                    // The peer never saw this data, start over in sync.
+                    self.compress.reset();
+                    return None;
+                }
+                consumed += (self.compress.total_in() - before) as usize;
+
+                // The flush is complete once it stopped short of the
+                // end of the buffer.
+                if consumed == data.len() && out.len() < out.capacity() {
+                    break;
+                }
+            }
+
+            if out.ends_with(&TRAILER) {
+                out.truncate(out.len() - TRAILER.len());
+            }
+            if self.reset {
+                self.compress.reset();
+            }
+
+            Some(out)
+        }
+    }
+
+    /// Decompresses incoming messages.
+    pub struct Inflater {
+        decompress: Decompress,
+        reset: bool,
+    }
+
+    impl Inflater {
+        pub fn new(params: &Params) -> Self {
+            Inflater {
+                decompress: Decompress::new(false),
+                reset: params.client_no_context_takeover,
+            }
+        }
+
+        /// Decompress a message, failing with `TooBig` as soon as the
+        /// output would be over `max_size` bytes.
+        pub fn decompress(
+            &mut self,
+            data: &[u8],
+            max_size: usize,
+        ) -> Result<Vec<u8>, FrameError> {
+            let mut input = Vec::with_capacity(data.len() + TRAILER.len());
+            input.extend_from_slice(data);
+            input.extend_from_slice(&TRAILER);
+
+            let limit = max_size.saturating_add(1);
+            let mut out = Vec::with_capacity((data.len() * 4).clamp(64, limit));
+            let mut consumed = 0;
+
+            loop {
+                if out.len() > max_size {
+                    return Err(FrameError::TooBig);
+                }
+                if out.len() == out.capacity() {
+                    let more = out.capacity().min(limit - out.len());
+                    out.reserve_exact(more);
+                }
+
+                let (before_in, before_out) =
+                    (self.decompress.total_in(), self.decompress.total_out());
+                self.decompress
+                    .decompress_vec(
+                        &input[consumed..],
+                        &mut out,
+                        FlushDecompress::Sync,
+                    )
+                    .map_err(|_| FrameError::Protocol)?;
+                consumed += (self.decompress.total_in() - before_in) as usize;
+
+                if consumed == input.len() && out.len() < out.capacity() {
+                    break;
+                }
+                if self.decompress.total_in() == before_in
+                    && self.decompress.total_out() == before_out
+                {
+                    // Stuck on input that isn't a deflate stream.
+                    return Err(FrameError::Protocol);
+                }
+            }
+
+            if out.len() > max_size {
+                return Err(FrameError::TooBig);
+            }
+            if self.reset {
+                self.decompress.reset(false);
+            }
+
+            Ok(out)
+        }
+    }
+}
+
+/// `Sec-WebSocket-Accept` for `key`, answering an opening handshake.
+pub fn accept_key(key: &str) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(key.as_bytes());
+    hasher.update(WS_GUID.as_bytes());
+    B64.encode(hasher.finalize())
+}
+
+#[cfg(test)]
+mod ws_frame_tests {
+    use crate::ws::frame::{FrameError, get_frame, set_text};
+    use bytes::BytesMut;
+    use const_format::str_repeat;
+
+    fn get_text(buf: &[u8]) -> String {
+        let (_, data) =
+            get_frame(buf, usize::MAX).expect("failed to decode frame");
+        String::from_utf8(data).expect("frame is not UTF-8")
+    }
+
+    #[test]
+    fn test_ws_get_text() {
+        let msg = "aaa";
+
+        let mut buf = BytesMut::with_capacity(1024);
+        buf.extend_from_slice(&[129, 131, 194, 47, 97, 242, 163, 78, 0]);
+
+        let decoded = get_text(&buf);
+        assert_eq!(decoded, msg);
+    }
+
+    #[test]
+    fn test_ws_text_frame_small() {
+        let msg = str_repeat!("a", 3);
+        let mut buf = BytesMut::with_capacity(1024);
+
+        let _ = set_text(&mut buf, msg);
+        let decoded = get_text(&buf);
+        assert_eq!(decoded, msg);
+    }
+
+    #[test]
+    fn test_ws_text_frame_medium() {
+        let msg = str_repeat!("a", 150);
+        let mut buf = BytesMut::with_capacity(1024);
+
+        let _ = set_text(&mut buf, msg);
+        let decoded = get_text(&buf);
+        assert_eq!(decoded, msg);
+    }
+
+    #[test]
+    fn test_ws_text_frame_medium_high_bit() {
+        let msg = str_repeat!("a", 40000);
+        let mut buf = BytesMut::with_capacity(1024 * 64);
+
+        let _ = set_text(&mut buf, msg);
+        let decoded = get_text(&buf);
+        assert_eq!(decoded, msg);
+    }
+
+    #[test]
+    fn test_ws_text_frame_huge() {
+        let msg = str_repeat!("a", 65536);
+        let mut buf = BytesMut::with_capacity(1024 * 1024);
+
+        let _ = set_text(&mut buf, msg);
+        let decoded = get_text(&buf);
+        assert_eq!(decoded, msg);
+    }
+
+    #[test]
+    fn test_ws_frame_truncated() {
+        let msg = str_repeat!("a", 150);
+        let mut buf = BytesMut::with_capacity(1024);
+
+        let len = set_text(&mut buf, msg);
+        for cut in [1, 3, len - 1] {
+            let result = get_frame(&buf[..cut], usize::MAX);
+            assert_eq!(result.err(), Some(FrameError::Incomplete));
+        }
+    }
+
+    #[test]
+    fn test_ws_frame_too_big_7bit() {
+        // 100 byte payload announced, no payload bytes sent
+        let buf = [0x81, 0x80 | 100, 0, 0, 0, 0];
+        let result = get_frame(&buf, 99);
+        assert_eq!(result.err(), Some(FrameError::TooBig));
+    }
+
+    #[test]
+    fn test_ws_frame_too_big_16bit() {
+        let buf = [0x81, 0x80 | 126, 0xff, 0xff, 0, 0, 0, 0];
+        let result = get_frame(&buf, 1024);
+        assert_eq!(result.err(), Some(FrameError::TooBig));
+    }
+
+    #[test]
+    fn test_ws_frame_too_big_64bit() {
+        // 4 GiB announced
+        let buf = [0x81, 0x80 | 127, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0];
+        let result = get_frame(&buf, 1024 * 1024);
+        assert_eq!(result.err(), Some(FrameError::TooBig));
+    }
+
+    #[test]
+    fn test_ws_frame_64bit_msb_set() {
+        let buf = [0x81, 127, 0x80, 0, 0, 0, 0, 0, 0, 0];
+        let result = get_frame(&buf, usize::MAX);
+        assert_eq!(result.err(), Some(FrameError::Protocol));
+    }
+}
+
+#[cfg(test)]
+mod ws_message_tests {
+    use crate::ws::frame::{self, FrameError, set_frame, set_masked, set_text};
+    use crate::ws::message::{Message, Reader};
+    use bytes::{BufMut, BytesMut};
+
+    fn fragment(buf: &mut BytesMut, fin: bool, opcode: u8, payload: &[u8]) {
+        let start = buf.len();
+        set_frame(buf, opcode, payload);
+        if !fin {
+            buf[start] &= 0x7f;
+        }
+    }
+
+    #[test]
+    fn test_ws_reader_text() {
+        let mut reader = Reader::new(1024, 1024);
+        let mut buf = BytesMut::new();
+        set_text(&mut buf, "hello");
+        set_text(&mut buf, "world");
+
+        match reader.next(&mut buf) {
+            Ok(Some(Message::Text(s))) => assert_eq!(s, "hello"),
+            _ => panic!("expected first text message"),
+        }
+        match reader.next(&mut buf) {
+            Ok(Some(Message::Text(s))) => assert_eq!(s, "world"),
+            _ => panic!("expected second text message"),
+        }
+        assert!(matches!(reader.next(&mut buf), Ok(None)));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_ws_reader_partial_frame() {
+        let mut reader = Reader::new(1024, 1024);
+        let mut full = BytesMut::new();
+        set_text(&mut full, "hello");
+
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(&full[..4]);
+        assert!(matches!(reader.next(&mut buf), Ok(None)));
+
+        buf.extend_from_slice(&full[4..]);
+        assert!(matches!(reader.next(&mut buf), Ok(Some(Message::Text(_)))));
+    }
+
+    #[test]
+    fn test_ws_reader_fragmented() {
+        let mut reader = Reader::new(1024, 1024);
+        let mut buf = BytesMut::new();
+        fragment(&mut buf, false, frame::OPCODE_TEXT, b"hel");
+        set_frame(&mut buf, frame::OPCODE_PING, b"p");
+        fragment(&mut buf, true, frame::OPCODE_CONTINUATION, b"lo");
+
+        assert!(matches!(reader.next(&mut buf), Ok(Some(Message::Ping(_)))));
+        match reader.next(&mut buf) {
+            Ok(Some(Message::Text(s))) => assert_eq!(s, "hello"),
+            _ => panic!("expected reassembled text message"),
+        }
+    }
+
+    #[test]
+    fn test_ws_reader_frame_too_big() {
+        let mut reader = Reader::new(4, 1024);
+        let mut buf = BytesMut::new();
+        set_text(&mut buf, "hello");
+
+        assert_eq!(reader.next(&mut buf).err(), Some(FrameError::TooBig));
+    }
+
+    #[test]
+    fn test_ws_reader_message_too_big() {
+        let mut reader = Reader::new(4, 6);
+        let mut buf = BytesMut::new();
+        fragment(&mut buf, false, frame::OPCODE_TEXT, b"abcd");
+        // Only the header of the second fragment has arrived
+        buf.put_u8(frame::OPCODE_CONTINUATION | 0x80);
+        buf.put_u8(4);
+
+        assert_eq!(reader.next(&mut buf).err(), Some(FrameError::TooBig));
+    }
+
+    #[test]
+    fn test_ws_reader_unexpected_continuation() {
+        let mut reader = Reader::new(1024, 1024);
+        let mut buf = BytesMut::new();
+        set_frame(&mut buf, frame::OPCODE_CONTINUATION, b"abc");
+
+        assert_eq!(reader.next(&mut buf).err(), Some(FrameError::Protocol));
+    }
+
+    #[test]
+    fn test_ws_reader_require_mask() {
+        let mut reader = Reader::new(1024, 1024);
+        reader.require_mask();
+        let mut buf = BytesMut::new();
+        set_masked(&mut buf, frame::OPCODE_TEXT, b"hi", [1, 2, 3, 4]);
+        assert!(matches!(reader.next(&mut buf), Ok(Some(Message::Text(_)))));
+
+        set_text(&mut buf, "hi");
+        let err = reader.next(&mut buf).err().unwrap();
+        assert_eq!(err, FrameError::Protocol);
+        assert_eq!(err.close_code(), frame::CLOSE_PROTOCOL_ERROR);
+    }
+
+    #[test]
+    fn test_ws_reader_masked_extended_length() {
+        let mut reader = Reader::new(1024, 1024);
+        reader.require_mask();
+        let mut buf = BytesMut::new();
+        let text = "x".repeat(300);
+        set_masked(&mut buf, frame::OPCODE_TEXT, text.as_bytes(), [9, 8, 7, 6]);
+
+        match reader.next(&mut buf) {
+            Ok(Some(Message::Text(s))) => assert_eq!(s, text),
+            _ => panic!("expected masked text message"),
+        }
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_ws_reader_close_code() {
+        let mut reader = Reader::new(1024, 1024);
+        let mut buf = BytesMut::new();
+        frame::set_close(&mut buf, frame::CLOSE_TOO_BIG, "");
+
+        assert!(matches!(
+            reader.next(&mut buf),
+            Ok(Some(Message::Close(Some(frame::CLOSE_TOO_BIG))))
+        ));
+    }
+}
+
+#[cfg(test)]
+mod ws_deflate_tests {
+    use bytes::BytesMut;
+
+    use crate::ws::deflate::{Deflater, Inflater, Params, negotiate};
+    use crate::ws::frame::{self, FrameError};
+    use crate::ws::message::{Message, Reader};
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(
+            negotiate("permessage-deflate; client_max_window_bits"),
+            Some(Params::default())
+        );
+        assert_eq!(negotiate("x-webkit-deflate-frame"), None);
+
+        // First acceptable offer wins
+        let params = negotiate(
+            "permessage-deflate; server_max_window_bits=10, \
+             permessage-deflate; server_no_context_takeover; \
+             client_no_context_takeover; server_max_window_bits=\"15\"",
+        )
+        .unwrap();
+        assert!(params.server_no_context_takeover);
+        assert!(params.client_no_context_takeover);
+        assert_eq!(
+            params.response(),
+            "permessage-deflate; server_no_context_takeover; \
+             client_no_context_takeover; server_max_window_bits=15"
+        );
+
+        // Unknown or repeated parameters and bad values
+        assert_eq!(negotiate("permessage-deflate; foo"), None);
+        assert_eq!(
+            negotiate(
+                "permessage-deflate; client_no_context_takeover; \
+                 client_no_context_takeover"
+            ),
+            None
+        );
+        assert_eq!(
+            negotiate("permessage-deflate; client_max_window_bits=16"),
+            None
+        );
+        assert_eq!(
+            negotiate("permessage-deflate; server_no_context_takeover=1"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_inflate_rfc_example() {
+        // "Hello", RFC 7692 section 7.2.3.1
+        let compressed = [0xf2, 0x48, 0xcd, 0xc9, 0xc9, 0x07, 0x00];
+        let mut inflater = Inflater::new(&Params::default());
+
+        assert_eq!(inflater.decompress(&compressed, 1024).unwrap(), b"Hello");
+        // Same message again, using the window of the first one
+        let again = [0xf2, 0x00, 0x11, 0x00, 0x00];
+        assert_eq!(inflater.decompress(&again, 1024).unwrap(), b"Hello");
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for no_context_takeover in [false, true] {
+            let params = Params {
+                server_no_context_takeover: no_context_takeover,
+                client_no_context_takeover: no_context_takeover,
+                ..Params::default()
+            };
+            let mut deflater = Deflater::new(&params, 0);
+            let mut inflater = Inflater::new(&params);
+
+            let msg = "{\"payload\":\"00ff00ff00ff00ff00ff00ff\"}".repeat(50);
+            let first = deflater.compress(msg.as_bytes()).unwrap();
+            let second = deflater.compress(msg.as_bytes()).unwrap();
+
+            assert!(first.len() < msg.len() / 4);
+            // Context takeover makes a repeated message almost free
+            assert_eq!(second.len() < first.len(), !no_context_takeover);
+
+            for data in [first, second] {
+                let out = inflater.decompress(&data, msg.len()).unwrap();
+                assert_eq!(out, msg.as_bytes());
+            }
+        }
+    }
+
+    #[test]
+    fn test_threshold() {
+        let mut deflater = Deflater::new(&Params::default(), 16);
+        assert_eq!(deflater.compress(b"short"), None);
+        assert!(deflater.compress(&[b'a'; 16]).is_some());
+    }
+
+    #[test]
+    fn test_inflate_bounded() {
+        // 10 MB of zeros compress to about 10 kB
+        let mut deflater = Deflater::new(&Params::default(), 0);
+        let bomb = deflater.compress(&vec![0; 10_000_000]).unwrap();
+        assert!(bomb.len() < 20_000);
+
+        let mut inflater = Inflater::new(&Params::default());
+        assert_eq!(
+            inflater.decompress(&bomb, 1024 * 1024),
+            Err(FrameError::TooBig)
+        );
+
+        let mut inflater = Inflater::new(&Params::default());
+        assert_eq!(
+            inflater.decompress(&[0xff; 16], 1024),
+            Err(FrameError::Protocol)
+        );
+    }
+
+    /// Client frame with RSV1 set, masked with a zero key.
+    fn compressed_frame(opcode: u8, payload: &[u8]) -> BytesMut {
+        let mut buf = BytesMut::new();
+        frame::set_compressed(&mut buf, opcode, payload);
+        buf[1] |= 0x80;
+        let mut masked = BytesMut::new();
+        masked.extend_from_slice(&buf[..2]);
+        masked.extend_from_slice(&[0; 4]);
+        masked.extend_from_slice(&buf[2..]);
+        masked
+    }
+
+    #[test]
+    fn test_reader_inflates() {
+        let mut deflater = Deflater::new(&Params::default(), 0);
+        let data = deflater.compress(b"hello hello hello").unwrap();
+
+        let mut buf = compressed_frame(frame::OPCODE_TEXT, &data);
+        let mut reader = Reader::new(1024, 1024);
+        reader.set_inflater(Inflater::new(&Params::default()));
+        match reader.next(&mut buf) {
+            Ok(Some(Message::Text(text))) => {
+                assert_eq!(text, "hello hello hello")
+            }
+            _ => panic!("expected text"),
+        }
+
+        // Not negotiated
+        let mut buf = compressed_frame(frame::OPCODE_TEXT, &data);
+        let mut reader = Reader::new(1024, 1024);
+        assert!(matches!(reader.next(&mut buf), Err(FrameError::Protocol)));
+
+        // Control frames can't be compressed
+        let mut buf = compressed_frame(frame::OPCODE_PING, b"");
+        let mut reader = Reader::new(1024, 1024);
+        reader.set_inflater(Inflater::new(&Params::default()));
+        assert!(matches!(reader.next(&mut buf), Err(FrameError::Protocol)));
+    }
+}
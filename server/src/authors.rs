@@ -0,0 +1,92 @@
+///
+/// Sender of each recent message id
+///
+/// Edits and deletions must come from the sender of the message they
+/// refer to. Only the last `MAX_TRACKED_MESSAGES` ids are kept, in memory,
+/// so older messages can no longer be changed.
+///
+use std::collections::{HashMap, VecDeque};
+
+use crate::AppState;
+use crate::constants::*;
+
+#[derive(Default)]
+pub(crate) struct Authors {
+    senders: HashMap<String, String>,
+    /// Ids oldest first, to forget them in order.
+    order: VecDeque<String>,
+}
+
+impl Authors {
+    /// Note `sender` sent `message_id`. A sender may use one id for several
+    /// recipients, but not an id someone else used.
+    fn record(&mut self, message_id: &str, sender: &str) -> bool {
+        let len = message_id.chars().count();
+        if len == 0 || len > MAX_MESSAGE_ID_CHARS {
+            return false;
+        }
+
+        match self.senders.get(message_id) {
+            Some(known) => return known == sender,
+            None => {
+                self.senders.insert(message_id.into(), sender.into());
+                self.order.push_back(message_id.into());
+            }
+        }
+
+        while self.order.len() > MAX_TRACKED_MESSAGES {
+            if let Some(old) = self.order.pop_front() {
+                self.senders.remove(&old);
+            }
+        }
+        true
+    }
+
+    fn sent(&self, message_id: &str, sender: &str) -> bool {
+        self.senders.get(message_id).is_some_and(|s| s == sender)
+    }
+}
+
+/// Returns false if `message_id` is invalid or belongs to someone else.
+pub async fn record(state: &AppState, message_id: &str, sender: &str) -> bool {
+    state.authors.lock().await.record(message_id, sender)
+}
+
+/// Whether `sender` sent `message_id`, as far as the server remembers.
+pub async fn sent(state: &AppState, message_id: &str, sender: &str) -> bool {
+    state.authors.lock().await.sent(message_id, sender)
+}
+
+#[cfg(test)]
+mod authors_tests {
+    use crate::authors::Authors;
+    use crate::constants::*;
+
+    #[test]
+    fn test_record_owner() {
+        let mut authors = Authors::default();
+
+        assert!(authors.record("m1", "A"));
+        // Fanned out to another recipient
+        assert!(authors.record("m1", "A"));
+        assert!(!authors.record("m1", "B"));
+        assert!(!authors.record("", "A"));
+        assert!(!authors.record(&"m".repeat(65), "A"));
+
+        assert!(authors.sent("m1", "A"));
+        assert!(!authors.sent("m1", "B"));
+        assert!(!authors.sent("m2", "A"));
+    }
+
+    #[test]
+    fn test_forgets_oldest() {
+        let mut authors = Authors::default();
+
+        for i in 0..=MAX_TRACKED_MESSAGES {
+            authors.record(&i.to_string(), "A");
+        }
+        assert!(!authors.sent("0", "A"));
+        assert!(authors.sent("1", "A"));
+        assert_eq!(authors.senders.len(), MAX_TRACKED_MESSAGES);
+    }
+}
@@ -0,0 +1,148 @@
+use std::collections::BTreeSet;
+use std::fmt;
+
+use rschat_crypto::PublicKey;
+use serde::{Deserialize, Serialize};
+
+use crate::AppState;
+use crate::constants::MAX_LIST_ENTRIES;
+
+/// Keys a user blocked or muted.
+///
+/// Blocked users and the user can't see or message each other. Muted
+/// users can still message, but their presence and typing are not shown.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct Lists {
+    #[serde(default)]
+    pub blocked: BTreeSet<String>,
+    #[serde(default)]
+    pub muted: BTreeSet<String>,
+}
+
+impl Lists {
+    pub fn blocks(&self, public_key: &str) -> bool {
+        self.blocked.contains(public_key)
+    }
+
+    pub fn mutes(&self, public_key: &str) -> bool {
+        self.muted.contains(public_key)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.blocked.is_empty() && self.muted.is_empty()
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ListError {
+    InvalidKey,
+    Full,
+}
+
+impl fmt::Display for ListError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ListError::InvalidKey => "not a public key",
+            ListError::Full => "list is full",
+        })
+    }
+}
+
+/// Add or remove `key` in `list`, keeping it under `MAX_LIST_ENTRIES`.
+fn set(
+    list: &mut BTreeSet<String>,
+    key: &str,
+    present: bool,
+) -> Result<(), ListError> {
+    if !present {
+        list.remove(key);
+    } else if !list.contains(key) {
+        if list.len() >= MAX_LIST_ENTRIES {
+            return Err(ListError::Full);
+        }
+        list.insert(key.to_string());
+    }
+    Ok(())
+}
+
+pub async fn load(state: &AppState, public_key: &str) -> Lists {
+    state
+        .blocklists
+        .lock()
+        .await
+        .get(public_key)
+        .cloned()
+        .unwrap_or_default()
+}
+
+/// Block or unblock `other` for `public_key`. Returns the updated lists.
+pub async fn set_blocked(
+    state: &AppState,
+    public_key: &str,
+    other: &str,
+    blocked: bool,
+) -> Result<Lists, ListError> {
+    update(state, public_key, other, |lists| {
+        set(&mut lists.blocked, other, blocked)
+    })
+    .await
+}
+
+/// Mute or unmute `other` for `public_key`. Returns the updated lists.
+pub async fn set_muted(
+    state: &AppState,
+    public_key: &str,
+    other: &str,
+    muted: bool,
+) -> Result<Lists, ListError> {
+    update(state, public_key, other, |lists| {
+        set(&mut lists.muted, other, muted)
+    })
+    .await
+}
+
+async fn update(
+    state: &AppState,
+    public_key: &str,
+    other: &str,
+    change: impl FnOnce(&mut Lists) -> Result<(), ListError>,
+) -> Result<Lists, ListError> {
+    if PublicKey::from_hex(other).is_err() {
+        return Err(ListError::InvalidKey);
+    }
+
+    let mut all = state.blocklists.lock().await;
+
+    let lists = all.entry(public_key.to_string()).or_default();
+    change(lists)?;
+    let lists = lists.clone();
+
+    if lists.is_empty() {
+        all.remove(public_key);
+    }
+
+    state.blocklists.save(&all).await;
+    Ok(lists)
+}
+
+#[cfg(test)]
+mod blocklist_tests {
+    use std::collections::BTreeSet;
+
+    use crate::blocklist::{ListError, set};
+    use crate::constants::MAX_LIST_ENTRIES;
+
+    #[test]
+    fn test_set_limits() {
+        let mut list = BTreeSet::new();
+        for i in 0..MAX_LIST_ENTRIES {
+            set(&mut list, &i.to_string(), true).unwrap();
+        }
+        assert_eq!(set(&mut list, "new", true), Err(ListError::Full));
+
+        // Entries already listed, and removals, still go through.
+        assert_eq!(set(&mut list, "0", true), Ok(()));
+        set(&mut list, "0", false).unwrap();
+        assert_eq!(set(&mut list, "new", true), Ok(()));
+    }
+}
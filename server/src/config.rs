@@ -0,0 +1,192 @@
+use std::env;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::constants::*;
+
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Who appears in a user's roster and may be messaged.
+#[derive(Clone, Copy, Default, PartialEq)]
+pub enum Roster {
+    /// Everyone online.
+    #[default]
+    Lobby,
+    /// Only accepted contacts.
+    Contacts,
+}
+
+impl FromStr for Roster {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lobby" => Ok(Roster::Lobby),
+            "contacts" => Ok(Roster::Contacts),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Server settings. The binary reads them from `RSCHAT_*` environment
+/// variables, embedders may build them directly.
+pub struct Config {
+    pub addr: String,
+
+    /// Largest payload accepted in a single WebSocket frame.
+    pub max_frame_size: usize,
+
+    /// Largest message accepted after reassembling fragmented frames.
+    pub max_message_size: usize,
+
+    pub roster: Roster,
+
+    /// Offer permessage-deflate to clients that support it.
+    pub deflate: bool,
+
+    /// Outgoing messages shorter than this are not compressed.
+    pub deflate_threshold: usize,
+
+    /// Origins browsers may open WebSockets from, e.g.
+    /// `https://chat.example.com`. `*` allows any. When empty, only pages
+    /// served by this server may.
+    pub allowed_origins: Vec<String>,
+
+    /// Require the token embedded in `index.html` on upgrade.
+    pub csrf: bool,
+
+    /// How long shutdown waits for connections to drain.
+    pub drain_timeout: Duration,
+
+    /// How long a dropped device may take to `resume` before peers are
+    /// told it left.
+    pub resume_grace: Duration,
+
+    /// How long a typing indicator lasts without being refreshed.
+    pub typing_timeout: Duration,
+
+    /// `tracing` filter directive, e.g. `info` or `wetsocks=debug`.
+    pub log_level: String,
+    pub log_format: LogFormat,
+
+    /// Log message payloads and user names instead of redacting them.
+    pub log_payloads: bool,
+
+    /// Where persistent state is stored. Kept in memory only when unset.
+    pub data_dir: Option<PathBuf>,
+
+    /// Directory of the web client, served at `/`.
+    pub static_dir: PathBuf,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            addr: DEFAULT_ADDR.to_string(),
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            roster: Roster::Lobby,
+            deflate: true,
+            deflate_threshold: DEFAULT_DEFLATE_THRESHOLD,
+            allowed_origins: Vec::new(),
+            csrf: false,
+            drain_timeout: Duration::from_secs(DEFAULT_DRAIN_TIMEOUT_SECS),
+            resume_grace: Duration::from_secs(DEFAULT_RESUME_GRACE_SECS),
+            typing_timeout: Duration::from_secs(DEFAULT_TYPING_TIMEOUT_SECS),
+            log_level: DEFAULT_LOG_LEVEL.to_string(),
+            log_format: LogFormat::Text,
+            log_payloads: false,
+            data_dir: None,
+            static_dir: PathBuf::from(DEFAULT_STATIC_DIR),
+        }
+    }
+}
+
+impl Config {
+    /// Defaults, overridden by whichever `RSCHAT_*` variables are set.
+    pub fn from_env() -> Self {
+        let default = Config::default();
+
+        Config {
+            addr: env::var("RSCHAT_ADDR").unwrap_or(default.addr),
+            max_frame_size: parse_var(
+                "RSCHAT_MAX_FRAME_SIZE",
+                default.max_frame_size,
+            ),
+            max_message_size: parse_var(
+                "RSCHAT_MAX_MESSAGE_SIZE",
+                default.max_message_size,
+            ),
+            roster: parse_var("RSCHAT_ROSTER", default.roster),
+            deflate: parse_var("RSCHAT_DEFLATE", default.deflate),
+            deflate_threshold: parse_var(
+                "RSCHAT_DEFLATE_THRESHOLD",
+                default.deflate_threshold,
+            ),
+            allowed_origins: env::var("RSCHAT_ALLOWED_ORIGINS")
+                .map(|val| {
+                    val.split(',')
+                        .map(|o| o.trim().trim_end_matches('/').to_string())
+                        .filter(|o| !o.is_empty())
+                        .collect()
+                })
+                .unwrap_or(default.allowed_origins),
+            csrf: parse_var("RSCHAT_CSRF", default.csrf),
+            drain_timeout: parse_secs(
+                "RSCHAT_DRAIN_TIMEOUT",
+                default.drain_timeout,
+            ),
+            resume_grace: parse_secs(
+                "RSCHAT_RESUME_GRACE",
+                default.resume_grace,
+            ),
+            typing_timeout: parse_secs(
+                "RSCHAT_TYPING_TIMEOUT",
+                default.typing_timeout,
+            ),
+            log_level: env::var("RSCHAT_LOG").unwrap_or(default.log_level),
+            log_format: parse_var("RSCHAT_LOG_FORMAT", default.log_format),
+            log_payloads: parse_var(
+                "RSCHAT_LOG_PAYLOADS",
+                default.log_payloads,
+            ),
+            data_dir: env::var_os("RSCHAT_DATA_DIR")
+                .map(PathBuf::from)
+                .or(default.data_dir),
+            static_dir: env::var_os("RSCHAT_STATIC_DIR")
+                .map(PathBuf::from)
+                .unwrap_or(default.static_dir),
+        }
+    }
+}
+
+fn parse_secs(name: &str, default: Duration) -> Duration {
+    Duration::from_secs(parse_var(name, default.as_secs()))
+}
+
+fn parse_var<T: FromStr>(name: &str, default: T) -> T {
+    match env::var(name) {
+        Ok(val) => val.parse().unwrap_or_else(|_| {
+            // Config is read before the logger exists, so print directly.
+            eprintln!("[warn] ignoring invalid {name}={val}");
+            default
+        }),
+        Err(_) => default,
+    }
+}
@@ -1,4 +1,29 @@
-pub const WS_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+pub const DEFAULT_ADDR: &str = "0.0.0.0:3333";
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 1024 * 1024;
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 4 * 1024 * 1024;
+pub const DEFAULT_DEFLATE_THRESHOLD: usize = 256;
+pub const DEFAULT_DRAIN_TIMEOUT_SECS: u64 = 5;
+pub const DEFAULT_RESUME_GRACE_SECS: u64 = 30;
+pub const DEFAULT_TYPING_TIMEOUT_SECS: u64 = 6;
+pub const DEFAULT_LOG_LEVEL: &str = "info";
+pub const DEFAULT_STATIC_DIR: &str = "./static";
 
-pub const ERR_WS_CONNECTION: &str = "Invalid Websocket Handshake.";
-pub const ERR_WS_VERSION: &str = "Unsupported WebSocket version.";
+pub const MAX_STATUS_TEXT_CHARS: usize = 140;
+pub const MAX_DEVICE_ID_CHARS: usize = 64;
+pub const MIN_HANDLE_CHARS: usize = 3;
+pub const MAX_HANDLE_CHARS: usize = 32;
+pub const MAX_SIGNATURE_AGE_SECS: u64 = 300;
+pub const MAX_REPLAY_MESSAGES: usize = 256;
+pub const MAX_OUTBOX_DEPTH: usize = 1024;
+pub const MAX_HISTORY_CHUNK_BYTES: usize = 256 * 1024;
+pub const MAX_HISTORY_BYTES: usize = 1024 * 1024;
+pub const MAX_HISTORY_IDENTITIES: usize = 10_000;
+pub const MAX_MEMORY_HISTORY_BYTES: usize = 32 * 1024 * 1024;
+pub const HISTORY_UPLOADS_PER_WINDOW: usize = 10;
+pub const HISTORY_UPLOAD_WINDOW_SECS: u64 = 60;
+pub const MAX_MESSAGE_ID_CHARS: usize = 64;
+pub const MAX_TRACKED_MESSAGES: usize = 100_000;
+pub const MAX_CONTACTS: usize = 1000;
+pub const MAX_PENDING_REQUESTS: usize = 100;
+pub const CONTACT_REQUEST_TTL_SECS: u64 = 30 * 24 * 60 * 60;
+pub const MAX_LIST_ENTRIES: usize = 1000;
@@ -0,0 +1,278 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+
+use rschat_crypto::PublicKey;
+use rschat_protocol::now_millis;
+use serde::{Deserialize, Serialize};
+
+use crate::AppState;
+use crate::constants::*;
+
+/// Accepted contacts of a user, and requests still waiting for an answer.
+#[derive(Serialize, Deserialize, Clone, Default, Debug, PartialEq)]
+pub struct Contacts {
+    #[serde(default)]
+    pub contacts: BTreeSet<String>,
+    /// Keys that asked to become a contact, with when they asked.
+    #[serde(default)]
+    pub incoming: BTreeMap<String, u64>,
+    /// Keys this user asked, with when.
+    #[serde(default)]
+    pub outgoing: BTreeMap<String, u64>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ContactError {
+    InvalidKey,
+    TooManyContacts,
+    TooManyRequests,
+}
+
+impl fmt::Display for ContactError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ContactError::InvalidKey => "not a public key",
+            ContactError::TooManyContacts => "too many contacts",
+            ContactError::TooManyRequests => "too many pending requests",
+        })
+    }
+}
+
+impl Contacts {
+    pub fn has(&self, public_key: &str) -> bool {
+        self.contacts.contains(public_key)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.contacts.is_empty()
+            && self.incoming.is_empty()
+            && self.outgoing.is_empty()
+    }
+
+    /// Drop requests older than `CONTACT_REQUEST_TTL_SECS`.
+    fn expire(&mut self, now: u64) {
+        let fresh = |_: &String, at: &mut u64| {
+            now.saturating_sub(*at) < CONTACT_REQUEST_TTL_SECS * 1000
+        };
+        self.incoming.retain(fresh);
+        self.outgoing.retain(fresh);
+    }
+}
+
+/// Ask `to` to become a contact of `from`. A request crossing one from
+/// `to` accepts it. Returns false if they already are contacts.
+fn request(
+    all: &mut HashMap<String, Contacts>,
+    from: &str,
+    to: &str,
+    now: u64,
+) -> Result<bool, ContactError> {
+    if from == to || all.get(from).is_some_and(|c| c.has(to)) {
+        return Ok(false);
+    }
+
+    if all.get(from).is_some_and(|c| c.incoming.contains_key(to)) {
+        return accept(all, from, to);
+    }
+
+    let full = |key: &str, of: fn(&Contacts) -> &BTreeMap<String, u64>| {
+        all.get(key)
+            .is_some_and(|c| of(c).len() >= MAX_PENDING_REQUESTS)
+    };
+    if full(from, |c| &c.outgoing) || full(to, |c| &c.incoming) {
+        return Err(ContactError::TooManyRequests);
+    }
+
+    all.entry(from.into())
+        .or_default()
+        .outgoing
+        .insert(to.into(), now);
+    all.entry(to.into())
+        .or_default()
+        .incoming
+        .insert(from.into(), now);
+    Ok(true)
+}
+
+/// Accept the request `by` got from `from`. Returns false if there was
+/// no such request.
+fn accept(
+    all: &mut HashMap<String, Contacts>,
+    by: &str,
+    from: &str,
+) -> Result<bool, ContactError> {
+    if !all.get(by).is_some_and(|c| c.incoming.contains_key(from)) {
+        return Ok(false);
+    }
+    let full = |key: &str| {
+        all.get(key)
+            .is_some_and(|c| c.contacts.len() >= MAX_CONTACTS)
+    };
+    if full(by) || full(from) {
+        return Err(ContactError::TooManyContacts);
+    }
+
+    let c = all.entry(by.into()).or_default();
+    c.incoming.remove(from);
+    c.contacts.insert(from.into());
+
+    let c = all.entry(from.into()).or_default();
+    c.outgoing.remove(by);
+    c.contacts.insert(by.into());
+    Ok(true)
+}
+
+/// Drop any contact or pending request between `a` and `b`.
+fn remove(all: &mut HashMap<String, Contacts>, a: &str, b: &str) {
+    for (this, other) in [(a, b), (b, a)] {
+        if let Some(c) = all.get_mut(this) {
+            c.contacts.remove(other);
+            c.incoming.remove(other);
+            c.outgoing.remove(other);
+            if c.is_empty() {
+                all.remove(this);
+            }
+        }
+    }
+}
+
+pub async fn load(state: &AppState, public_key: &str) -> Contacts {
+    let mut contacts = state
+        .contacts
+        .lock()
+        .await
+        .get(public_key)
+        .cloned()
+        .unwrap_or_default();
+    contacts.expire(now_millis());
+    contacts
+}
+
+pub enum Change {
+    Request,
+    Accept,
+    Remove,
+}
+
+/// Apply `change` between `by` and `other`. Returns the new contacts of
+/// both, or `None` if nothing changed.
+pub async fn update(
+    state: &AppState,
+    change: Change,
+    by: &str,
+    other: &str,
+) -> Result<Option<(Contacts, Contacts)>, ContactError> {
+    if PublicKey::from_hex(other).is_err() {
+        return Err(ContactError::InvalidKey);
+    }
+
+    let now = now_millis();
+    let mut all = state.contacts.lock().await;
+    for key in [by, other] {
+        if let Some(c) = all.get_mut(key) {
+            c.expire(now);
+        }
+    }
+
+    let changed = match change {
+        Change::Request => request(&mut all, by, other, now)?,
+        Change::Accept => accept(&mut all, by, other)?,
+        Change::Remove => {
+            remove(&mut all, by, other);
+            true
+        }
+    };
+    if !changed {
+        return Ok(None);
+    }
+
+    state.contacts.save(&all).await;
+
+    let get = |key: &str| all.get(key).cloned().unwrap_or_default();
+    Ok(Some((get(by), get(other))))
+}
+
+#[cfg(test)]
+mod contacts_tests {
+    use std::collections::HashMap;
+
+    use crate::constants::*;
+    use crate::contacts::{ContactError, Contacts, accept, remove, request};
+
+    #[test]
+    fn test_request_and_accept() {
+        let mut all = HashMap::new();
+
+        assert!(request(&mut all, "A", "B", 0).unwrap());
+        assert!(all["A"].outgoing.contains_key("B"));
+        assert!(all["B"].incoming.contains_key("A"));
+        assert!(!all["A"].has("B"));
+
+        // Only the asked side can accept.
+        assert!(!accept(&mut all, "A", "B").unwrap());
+        assert!(accept(&mut all, "B", "A").unwrap());
+        assert!(all["A"].has("B") && all["B"].has("A"));
+        assert!(all["A"].outgoing.is_empty() && all["B"].incoming.is_empty());
+
+        assert!(!request(&mut all, "B", "A", 0).unwrap());
+    }
+
+    #[test]
+    fn test_crossing_requests_accept() {
+        let mut all = HashMap::new();
+
+        assert!(request(&mut all, "A", "B", 0).unwrap());
+        assert!(request(&mut all, "B", "A", 0).unwrap());
+        assert!(all["A"].has("B") && all["B"].has("A"));
+    }
+
+    #[test]
+    fn test_remove() {
+        let mut all = HashMap::new();
+        request(&mut all, "A", "B", 0).unwrap();
+        accept(&mut all, "B", "A").unwrap();
+        request(&mut all, "A", "C", 0).unwrap();
+
+        remove(&mut all, "B", "A");
+        assert!(!all["A"].has("B"));
+        assert!(!all.contains_key("B"));
+
+        // Declining a request drops it on both sides.
+        remove(&mut all, "C", "A");
+        assert!(!all.contains_key("A") && !all.contains_key("C"));
+    }
+
+    #[test]
+    fn test_pending_limits() {
+        let mut all = HashMap::new();
+        for i in 0..MAX_PENDING_REQUESTS {
+            assert!(request(&mut all, "A", &i.to_string(), 0).unwrap());
+        }
+        assert_eq!(
+            request(&mut all, "A", "B", 0),
+            Err(ContactError::TooManyRequests)
+        );
+
+        // Nor can anyone flood one user with requests.
+        for i in 0..MAX_PENDING_REQUESTS {
+            request(&mut all, &format!("x{i}"), "B", 0).unwrap();
+        }
+        assert_eq!(
+            request(&mut all, "C", "B", 0),
+            Err(ContactError::TooManyRequests)
+        );
+    }
+
+    #[test]
+    fn test_requests_expire() {
+        let mut all = HashMap::new();
+        request(&mut all, "A", "B", 1000).unwrap();
+
+        let ttl = CONTACT_REQUEST_TTL_SECS * 1000;
+        let mut b = all["B"].clone();
+        b.expire(ttl);
+        assert!(b.incoming.contains_key("A"));
+        b.expire(ttl + 1000);
+        assert_eq!(b, Contacts::default());
+    }
+}
@@ -0,0 +1,277 @@
+///
+/// Registry of unique handles, each bound to one public key
+///
+/// Every change must be signed with the key the handle is bound to. The
+/// signed text names the operation, the handle(s), the key and a unix
+/// timestamp, e.g. `rschat:claim_handle:alice:04ab…:1760000000`, so a
+/// signature can't be reused for another operation or replayed later.
+///
+use std::collections::HashMap;
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::AppState;
+use crate::constants::*;
+use crate::signature;
+
+#[derive(Debug, PartialEq)]
+pub enum HandleError {
+    Invalid,
+    Taken,
+    AlreadyClaimed,
+    NotClaimed,
+    BadSignature,
+    Expired,
+}
+
+impl fmt::Display for HandleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            HandleError::Invalid => {
+                "handles are 3 to 32 characters of a-z, 0-9 and _"
+            }
+            HandleError::Taken => "handle is taken",
+            HandleError::AlreadyClaimed => "key already has a handle",
+            HandleError::NotClaimed => "key has no handle",
+            HandleError::BadSignature => "bad signature",
+            HandleError::Expired => "signature timestamp too old or too new",
+        })
+    }
+}
+
+#[derive(Default, Serialize, Deserialize)]
+pub struct Registry {
+    /// Public key of each handle.
+    handles: HashMap<String, String>,
+}
+
+impl Registry {
+    pub fn owner(&self, handle: &str) -> Option<&str> {
+        self.handles.get(handle).map(String::as_str)
+    }
+
+    pub fn handle_of(&self, public_key: &str) -> Option<&str> {
+        self.handles
+            .iter()
+            .find(|(_, key)| *key == public_key)
+            .map(|(handle, _)| handle.as_str())
+    }
+
+    fn claim(
+        &mut self,
+        handle: &str,
+        public_key: &str,
+    ) -> Result<(), HandleError> {
+        if self.handle_of(public_key).is_some() {
+            return Err(HandleError::AlreadyClaimed);
+        }
+        if self.handles.contains_key(handle) {
+            return Err(HandleError::Taken);
+        }
+
+        self.handles.insert(handle.into(), public_key.into());
+        Ok(())
+    }
+
+    fn rename(
+        &mut self,
+        old: &str,
+        new: &str,
+        public_key: &str,
+    ) -> Result<(), HandleError> {
+        if self.owner(old) != Some(public_key) {
+            return Err(HandleError::NotClaimed);
+        }
+        if self.handles.contains_key(new) {
+            return Err(HandleError::Taken);
+        }
+
+        self.handles.remove(old);
+        self.handles.insert(new.into(), public_key.into());
+        Ok(())
+    }
+
+    fn release(&mut self, handle: &str, public_key: &str) {
+        if self.owner(handle) == Some(public_key) {
+            self.handles.remove(handle);
+        }
+    }
+}
+
+/// Lowercase `handle` and check it only uses allowed characters.
+pub fn normalize(handle: &str) -> Result<String, HandleError> {
+    let handle = handle.to_ascii_lowercase();
+    let len = handle.chars().count();
+
+    let valid = (MIN_HANDLE_CHARS..=MAX_HANDLE_CHARS).contains(&len)
+        && handle
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_');
+
+    if valid {
+        Ok(handle)
+    } else {
+        Err(HandleError::Invalid)
+    }
+}
+
+/// Check `signature` over `message`, made by `public_key` at `timestamp`.
+fn check_signature(
+    public_key: &str,
+    message: &str,
+    timestamp: u64,
+    signature: &str,
+) -> Result<(), HandleError> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+
+    if now.abs_diff(timestamp) > MAX_SIGNATURE_AGE_SECS {
+        return Err(HandleError::Expired);
+    }
+
+    if signature::verify(public_key, message, signature) {
+        Ok(())
+    } else {
+        Err(HandleError::BadSignature)
+    }
+}
+
+/// Bind `handle` to `public_key`. Returns the normalized handle.
+pub async fn claim(
+    state: &AppState,
+    public_key: &str,
+    handle: &str,
+    timestamp: u64,
+    signature: &str,
+) -> Result<String, HandleError> {
+    let handle = normalize(handle)?;
+    let message =
+        format!("rschat:claim_handle:{handle}:{public_key}:{timestamp}");
+    check_signature(public_key, &message, timestamp, signature)?;
+
+    let mut registry = state.handles.lock().await;
+    registry.claim(&handle, public_key)?;
+    state.handles.save(&registry).await;
+
+    Ok(handle)
+}
+
+/// Move the handle of `public_key` to `handle`. Returns the new handle.
+pub async fn rename(
+    state: &AppState,
+    public_key: &str,
+    handle: &str,
+    timestamp: u64,
+    signature: &str,
+) -> Result<String, HandleError> {
+    let new = normalize(handle)?;
+
+    let mut registry = state.handles.lock().await;
+    let old = registry
+        .handle_of(public_key)
+        .ok_or(HandleError::NotClaimed)?
+        .to_string();
+
+    let message =
+        format!("rschat:rename_handle:{old}:{new}:{public_key}:{timestamp}");
+    check_signature(public_key, &message, timestamp, signature)?;
+
+    registry.rename(&old, &new, public_key)?;
+    state.handles.save(&registry).await;
+
+    Ok(new)
+}
+
+/// Give up the handle of `public_key`.
+pub async fn release(
+    state: &AppState,
+    public_key: &str,
+    timestamp: u64,
+    signature: &str,
+) -> Result<(), HandleError> {
+    let mut registry = state.handles.lock().await;
+    let handle = registry
+        .handle_of(public_key)
+        .ok_or(HandleError::NotClaimed)?
+        .to_string();
+
+    let message =
+        format!("rschat:release_handle:{handle}:{public_key}:{timestamp}");
+    check_signature(public_key, &message, timestamp, signature)?;
+
+    registry.release(&handle, public_key);
+    state.handles.save(&registry).await;
+
+    Ok(())
+}
+
+/// Public key bound to `handle`, if any.
+pub async fn resolve(state: &AppState, handle: &str) -> Option<String> {
+    let handle = normalize(handle).ok()?;
+    state.handles.lock().await.owner(&handle).map(String::from)
+}
+
+pub async fn handle_of(state: &AppState, public_key: &str) -> Option<String> {
+    state
+        .handles
+        .lock()
+        .await
+        .handle_of(public_key)
+        .map(String::from)
+}
+
+#[cfg(test)]
+mod handles_tests {
+    use crate::handles::{HandleError, Registry, normalize};
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(normalize("Alice_01"), Ok("alice_01".to_string()));
+        assert_eq!(normalize("al"), Err(HandleError::Invalid));
+        assert_eq!(normalize("alice!"), Err(HandleError::Invalid));
+        assert_eq!(normalize("ålice"), Err(HandleError::Invalid));
+        assert_eq!(normalize(&"a".repeat(33)), Err(HandleError::Invalid));
+    }
+
+    #[test]
+    fn test_claim_is_unique() {
+        let mut registry = Registry::default();
+
+        assert_eq!(registry.claim("alice", "k1"), Ok(()));
+        assert_eq!(registry.claim("alice", "k2"), Err(HandleError::Taken));
+        assert_eq!(
+            registry.claim("alice2", "k1"),
+            Err(HandleError::AlreadyClaimed)
+        );
+        assert_eq!(registry.owner("alice"), Some("k1"));
+        assert_eq!(registry.handle_of("k2"), None);
+    }
+
+    #[test]
+    fn test_rename_and_release() {
+        let mut registry = Registry::default();
+        registry.claim("alice", "k1").unwrap();
+        registry.claim("bob", "k2").unwrap();
+
+        assert_eq!(
+            registry.rename("alice", "bob", "k1"),
+            Err(HandleError::Taken)
+        );
+        assert_eq!(
+            registry.rename("bob", "carol", "k1"),
+            Err(HandleError::NotClaimed)
+        );
+        assert_eq!(registry.rename("alice", "alicia", "k1"), Ok(()));
+        assert_eq!(registry.owner("alice"), None);
+        assert_eq!(registry.handle_of("k1"), Some("alicia"));
+
+        registry.release("bob", "k1");
+        assert_eq!(registry.owner("bob"), Some("k2"));
+        registry.release("bob", "k2");
+        assert_eq!(registry.owner("bob"), None);
+    }
+}
@@ -0,0 +1,367 @@
+///
+/// Opening handshake validation
+///
+/// Reference: <https://datatracker.ietf.org/doc/html/rfc6455#section-4.2.1>
+///
+use std::fmt;
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as B64;
+use rschat_protocol::ws::accept_key;
+
+use crate::http::header::HttpHeader;
+use crate::service::same_secret;
+
+#[derive(Debug, PartialEq)]
+pub enum HandshakeError {
+    /// Request is not HTTP/1.1 or newer.
+    HttpVersion,
+    MissingHost,
+    /// `Upgrade` does not list `websocket`.
+    NotUpgrade,
+    /// `Connection` does not list `Upgrade`.
+    NotConnectionUpgrade,
+    /// `Sec-WebSocket-Key` is missing or not 16 bytes of base64.
+    BadKey,
+    /// `Sec-WebSocket-Version` is missing or not 13.
+    BadVersion,
+    /// `Origin` is not allowed to connect.
+    BadOrigin,
+    /// Missing or wrong `csrf` query parameter.
+    BadCsrfToken,
+    /// `Sec-WebSocket-Protocol` names no protocol we speak.
+    BadProtocol,
+}
+
+impl fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            HandshakeError::HttpVersion => "HTTP/1.1 or newer required",
+            HandshakeError::MissingHost => "missing Host header",
+            HandshakeError::NotUpgrade => "Upgrade must be websocket",
+            HandshakeError::NotConnectionUpgrade => {
+                "Connection must include Upgrade"
+            }
+            HandshakeError::BadKey => "invalid Sec-WebSocket-Key",
+            HandshakeError::BadVersion => "unsupported WebSocket version",
+            HandshakeError::BadOrigin => "origin not allowed",
+            HandshakeError::BadCsrfToken => "invalid CSRF token",
+            HandshakeError::BadProtocol => "unsupported subprotocol",
+        })
+    }
+}
+
+impl HandshakeError {
+    /// HTTP response refusing the upgrade.
+    pub fn response(&self) -> String {
+        let (status, extra, connection) = match self {
+            HandshakeError::NotUpgrade => (
+                "426 Upgrade Required",
+                "Upgrade: websocket\r\n",
+                "Upgrade, close",
+            ),
+            HandshakeError::BadVersion => (
+                "426 Upgrade Required",
+                "Sec-WebSocket-Version: 13\r\n",
+                "close",
+            ),
+            HandshakeError::BadOrigin | HandshakeError::BadCsrfToken => {
+                ("403 Forbidden", "", "close")
+            }
+            _ => ("400 Bad Request", "", "close"),
+        };
+        let body = self.to_string();
+
+        format!(
+            "HTTP/1.1 {status}\r\n\
+             {extra}\
+             Content-Type: text/plain; charset=utf-8\r\n\
+             Content-Length: {}\r\n\
+             Connection: {connection}\r\n\
+             \r\n\
+             {body}",
+            body.len()
+        )
+    }
+}
+
+/// Check an upgrade request. Returns the `Sec-WebSocket-Accept` value
+/// to answer with.
+pub fn validate(header: &HttpHeader) -> Result<String, HandshakeError> {
+    let http_version = header
+        .version
+        .strip_prefix("HTTP/")
+        .and_then(|v| v.split_once('.'))
+        .and_then(|(major, minor)| {
+            Some((major.parse::<u32>().ok()?, minor.parse::<u32>().ok()?))
+        });
+    if http_version.is_none_or(|v| v < (1, 1)) {
+        return Err(HandshakeError::HttpVersion);
+    }
+
+    if header.get("Host").is_none_or(str::is_empty) {
+        return Err(HandshakeError::MissingHost);
+    }
+    if !header.has_token("Upgrade", "websocket") {
+        return Err(HandshakeError::NotUpgrade);
+    }
+    if !header.has_token("Connection", "Upgrade") {
+        return Err(HandshakeError::NotConnectionUpgrade);
+    }
+
+    let key = header.get("Sec-WebSocket-Key").unwrap_or_default();
+    if !B64.decode(key).is_ok_and(|k| k.len() == 16) {
+        return Err(HandshakeError::BadKey);
+    }
+
+    if header.get("Sec-WebSocket-Version") != Some("13") {
+        return Err(HandshakeError::BadVersion);
+    }
+
+    Ok(accept_key(key))
+}
+
+/// Check the request comes from an allowed page. Requests without an
+/// `Origin` are from non-browser clients and always allowed. With an
+/// empty `allowed` list the origin must be this server, as named by
+/// `Host`.
+pub fn check_origin(
+    header: &HttpHeader,
+    allowed: &[String],
+) -> Result<(), HandshakeError> {
+    let Some(origin) = header.get("Origin") else {
+        return Ok(());
+    };
+
+    let ok = if allowed.is_empty() {
+        let host = origin.split_once("://").map(|(_, host)| host);
+        host.is_some_and(|host| {
+            header
+                .get("Host")
+                .is_some_and(|h| h.eq_ignore_ascii_case(host))
+        })
+    } else {
+        allowed
+            .iter()
+            .any(|a| a == "*" || a.eq_ignore_ascii_case(origin))
+    };
+
+    if ok {
+        Ok(())
+    } else {
+        Err(HandshakeError::BadOrigin)
+    }
+}
+
+/// Check the `csrf` query parameter is `token`. Like `check_origin`,
+/// only requests with an `Origin` are checked: other sites can only
+/// reach the server through a browser, and non-browser clients never
+/// see the page holding the token.
+pub fn check_csrf(
+    header: &HttpHeader,
+    token: &str,
+) -> Result<(), HandshakeError> {
+    if header.get("Origin").is_none()
+        || header.param("csrf").is_some_and(|t| same_secret(t, token))
+    {
+        Ok(())
+    } else {
+        Err(HandshakeError::BadCsrfToken)
+    }
+}
+
+#[cfg(test)]
+mod handshake_tests {
+    use rschat_protocol::ws::accept_key;
+
+    use crate::handshake::{
+        HandshakeError, check_csrf, check_origin, validate,
+    };
+    use crate::http::header::{HttpHeader, parse};
+
+    const KEY: &str = "dGhlIHNhbXBsZSBub25jZQ==";
+
+    /// A valid upgrade request, with `change` applied to its lines.
+    fn request(change: impl FnOnce(&mut Vec<String>)) -> HttpHeader {
+        let mut lines = vec![
+            "GET /ws HTTP/1.1".to_string(),
+            "Host: server.example.com".to_string(),
+            "Upgrade: websocket".to_string(),
+            "Connection: Upgrade".to_string(),
+            format!("Sec-WebSocket-Key: {KEY}"),
+            "Sec-WebSocket-Version: 13".to_string(),
+        ];
+        change(&mut lines);
+
+        let text = format!("{}\r\n\r\n", lines.join("\r\n"));
+        let Ok(header) = parse(&text) else {
+            panic!("failed to parse header");
+        };
+        header
+    }
+
+    fn without(name: &str) -> impl FnOnce(&mut Vec<String>) {
+        move |lines| lines.retain(|l| !l.starts_with(name))
+    }
+
+    fn replace(name: &str, line: &str) -> impl FnOnce(&mut Vec<String>) {
+        move |lines| {
+            for l in lines.iter_mut().filter(|l| l.starts_with(name)) {
+                *l = line.to_string();
+            }
+        }
+    }
+
+    #[test]
+    fn test_accept_key() {
+        // Example from RFC 6455, section 1.3
+        assert_eq!(accept_key(KEY), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+        assert_eq!(validate(&request(|_| {})), Ok(accept_key(KEY)));
+    }
+
+    #[test]
+    fn test_tokens_ignore_case() {
+        let header = request(|lines| {
+            lines.retain(|l| l.starts_with("GET") || l.starts_with("Sec"));
+            lines.push("host: server.example.com".into());
+            lines.push("UPGRADE: WebSocket".into());
+            lines.push("connection: keep-alive, upgrade".into());
+        });
+        assert!(validate(&header).is_ok());
+    }
+
+    #[test]
+    fn test_http_version() {
+        let header = request(replace("GET", "GET /ws HTTP/1.0"));
+        assert_eq!(validate(&header), Err(HandshakeError::HttpVersion));
+
+        let header = request(replace("GET", "GET /ws"));
+        assert_eq!(validate(&header), Err(HandshakeError::HttpVersion));
+
+        let header = request(replace("GET", "GET /ws HTTP/2.0"));
+        assert!(validate(&header).is_ok());
+    }
+
+    #[test]
+    fn test_missing_host() {
+        let header = request(without("Host"));
+        assert_eq!(validate(&header), Err(HandshakeError::MissingHost));
+    }
+
+    #[test]
+    fn test_not_upgrade() {
+        let header = request(without("Upgrade"));
+        assert_eq!(validate(&header), Err(HandshakeError::NotUpgrade));
+
+        let header = request(replace("Upgrade", "Upgrade: h2c"));
+        assert_eq!(validate(&header), Err(HandshakeError::NotUpgrade));
+    }
+
+    #[test]
+    fn test_not_connection_upgrade() {
+        let header = request(without("Connection"));
+        assert_eq!(
+            validate(&header),
+            Err(HandshakeError::NotConnectionUpgrade)
+        );
+
+        let header = request(replace("Connection", "Connection: keep-alive"));
+        assert_eq!(
+            validate(&header),
+            Err(HandshakeError::NotConnectionUpgrade)
+        );
+    }
+
+    #[test]
+    fn test_bad_key() {
+        let header = request(without("Sec-WebSocket-Key"));
+        assert_eq!(validate(&header), Err(HandshakeError::BadKey));
+
+        let header = request(replace(
+            "Sec-WebSocket-Key",
+            "Sec-WebSocket-Key: not base64!",
+        ));
+        assert_eq!(validate(&header), Err(HandshakeError::BadKey));
+
+        // 15 bytes
+        let header = request(replace(
+            "Sec-WebSocket-Key",
+            "Sec-WebSocket-Key: AAAAAAAAAAAAAAAAAAAA",
+        ));
+        assert_eq!(validate(&header), Err(HandshakeError::BadKey));
+    }
+
+    #[test]
+    fn test_bad_version() {
+        let header = request(without("Sec-WebSocket-Version"));
+        assert_eq!(validate(&header), Err(HandshakeError::BadVersion));
+
+        let header = request(replace(
+            "Sec-WebSocket-Version",
+            "Sec-WebSocket-Version: 8",
+        ));
+        assert_eq!(validate(&header), Err(HandshakeError::BadVersion));
+    }
+
+    #[test]
+    fn test_origin() {
+        let none = request(|_| {});
+        let same = request(|lines| {
+            lines.push("Origin: http://server.example.com".into())
+        });
+        let other =
+            request(|lines| lines.push("Origin: https://evil.example".into()));
+
+        assert_eq!(check_origin(&none, &[]), Ok(()));
+        assert_eq!(check_origin(&same, &[]), Ok(()));
+        assert_eq!(check_origin(&other, &[]), Err(HandshakeError::BadOrigin));
+
+        let allowed = ["https://evil.example".to_string()];
+        assert_eq!(check_origin(&other, &allowed), Ok(()));
+        assert_eq!(
+            check_origin(&same, &allowed),
+            Err(HandshakeError::BadOrigin)
+        );
+        assert_eq!(check_origin(&other, &["*".to_string()]), Ok(()));
+    }
+
+    #[test]
+    fn test_csrf() {
+        let browser = |lines: &mut Vec<String>| {
+            lines.push("Origin: http://server.example.com".to_string());
+        };
+        let header = request(|lines| {
+            browser(lines);
+            lines[0] = "GET /ws?csrf=t0ken HTTP/1.1".to_string();
+        });
+        assert_eq!(check_csrf(&header, "t0ken"), Ok(()));
+        assert_eq!(
+            check_csrf(&header, "other"),
+            Err(HandshakeError::BadCsrfToken)
+        );
+        assert_eq!(
+            check_csrf(&request(browser), "t0ken"),
+            Err(HandshakeError::BadCsrfToken)
+        );
+        // Non-browser clients never saw the page with the token.
+        assert_eq!(check_csrf(&request(|_| {}), "t0ken"), Ok(()));
+    }
+
+    #[test]
+    fn test_responses() {
+        let response = HandshakeError::BadVersion.response();
+        assert!(response.starts_with("HTTP/1.1 426 Upgrade Required\r\n"));
+        assert!(response.contains("\r\nSec-WebSocket-Version: 13\r\n"));
+
+        let response = HandshakeError::NotUpgrade.response();
+        assert!(response.starts_with("HTTP/1.1 426 Upgrade Required\r\n"));
+        assert!(response.contains("\r\nUpgrade: websocket\r\n"));
+
+        let response = HandshakeError::BadOrigin.response();
+        assert!(response.starts_with("HTTP/1.1 403 Forbidden\r\n"));
+
+        let response = HandshakeError::BadKey.response();
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+        assert!(response.ends_with("\r\n\r\ninvalid Sec-WebSocket-Key"));
+    }
+}
@@ -0,0 +1,360 @@
+///
+/// Encrypted history chunks, kept per identity
+///
+/// Devices upload their recent messages encrypted under a key only the
+/// identity has (`encrypt_history` in crypto-wasm), so a new device can
+/// fetch and restore them. The server only sees opaque blobs.
+///
+use std::fmt;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+use rschat_crypto::MIN_HISTORY_LEN;
+use rschat_protocol::{Chunk, now_millis};
+
+use crate::AppState;
+use crate::constants::*;
+
+#[derive(Debug, PartialEq)]
+pub enum HistoryError {
+    InvalidId,
+    /// Not a hex encoded history blob.
+    InvalidData,
+    TooBig,
+    /// Too many identities store history already, or, without a backend,
+    /// history takes up all the memory it may.
+    Full,
+    /// The identity uploaded too often.
+    RateLimited,
+}
+
+impl fmt::Display for HistoryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            HistoryError::InvalidId => "chunk id must be 1 to 64 characters",
+            HistoryError::InvalidData => "history data must be a hex blob",
+            HistoryError::TooBig => "history chunk too big",
+            HistoryError::Full => "no room for another history",
+            HistoryError::RateLimited => "too many history uploads",
+        })
+    }
+}
+
+/// Add `chunk` to `chunks`, dropping the oldest ones while the total is
+/// over `MAX_HISTORY_BYTES`.
+fn put(chunks: &mut Vec<Chunk>, chunk: Chunk) -> Result<(), HistoryError> {
+    let len = chunk.chunk_id.chars().count();
+    if len == 0 || len > MAX_DEVICE_ID_CHARS {
+        return Err(HistoryError::InvalidId);
+    }
+    if chunk.data.len() > MAX_HISTORY_CHUNK_BYTES {
+        return Err(HistoryError::TooBig);
+    }
+    let data = chunk.data.as_bytes();
+    if !data.len().is_multiple_of(2)
+        || data.len() < 2 * MIN_HISTORY_LEN
+        || !data.iter().all(u8::is_ascii_hexdigit)
+    {
+        return Err(HistoryError::InvalidData);
+    }
+
+    chunks.retain(|c| c.chunk_id != chunk.chunk_id);
+    chunks.push(chunk);
+
+    let mut total = size(chunks);
+    while total > MAX_HISTORY_BYTES {
+        total -= chunks.remove(0).data.len();
+    }
+
+    Ok(())
+}
+
+fn size(chunks: &[Chunk]) -> usize {
+    chunks.iter().map(|c| c.data.len()).sum()
+}
+
+/// Account for history held only in memory growing from `before` to
+/// `after` bytes, refusing growth past `MAX_MEMORY_HISTORY_BYTES`.
+fn reserve(
+    state: &AppState,
+    before: usize,
+    after: usize,
+) -> Result<(), HistoryError> {
+    state
+        .history_bytes
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+            let used = used - before + after;
+            (after <= before || used <= MAX_MEMORY_HISTORY_BYTES)
+                .then_some(used)
+        })
+        .map(drop)
+        .map_err(|_| HistoryError::Full)
+}
+
+/// Uploads of one identity, at most `HISTORY_UPLOADS_PER_WINDOW` every
+/// `HISTORY_UPLOAD_WINDOW_SECS`.
+pub struct UploadLimit {
+    window_start: Instant,
+    uploads: usize,
+}
+
+impl UploadLimit {
+    pub fn new(now: Instant) -> Self {
+        UploadLimit {
+            window_start: now,
+            uploads: 0,
+        }
+    }
+
+    /// Count an upload at `now`, unless the identity is over its limit.
+    pub fn allow(&mut self, now: Instant) -> bool {
+        if self.expired(now) {
+            self.window_start = now;
+            self.uploads = 0;
+        }
+        if self.uploads >= HISTORY_UPLOADS_PER_WINDOW {
+            return false;
+        }
+        self.uploads += 1;
+        true
+    }
+
+    /// Whether the window is over, so the limit no longer holds anything.
+    fn expired(&self, now: Instant) -> bool {
+        let window = Duration::from_secs(HISTORY_UPLOAD_WINDOW_SECS);
+        now.duration_since(self.window_start) >= window
+    }
+}
+
+/// Count an upload of `public_key` at `now` against its `UploadLimit`,
+/// which outlives its connections.
+pub async fn allow_upload(
+    state: &AppState,
+    public_key: &str,
+    now: Instant,
+) -> bool {
+    let mut limits = state.history_uploads.lock().await;
+    limits.retain(|_, limit| !limit.expired(now));
+    limits
+        .entry(public_key.to_string())
+        .or_insert_with(|| UploadLimit::new(now))
+        .allow(now)
+}
+
+pub async fn upload(
+    state: &AppState,
+    public_key: &str,
+    chunk_id: String,
+    data: String,
+) -> Result<(), HistoryError> {
+    let chunk = Chunk {
+        chunk_id,
+        data,
+        uploaded_at: now_millis(),
+    };
+
+    // Only this user's chunks stay locked while they are written.
+    let entry = state.history.entry(public_key).await;
+    let mut chunks = entry.lock().await;
+    let stored = if chunks.is_empty()
+        && state.history.saved_keys().await >= MAX_HISTORY_IDENTITIES
+    {
+        Err(HistoryError::Full)
+    } else {
+        let mut updated = chunks.clone();
+        put(&mut updated, chunk).and_then(|()| {
+            if !state.history.persistent() {
+                reserve(state, size(&chunks), size(&updated))?;
+            }
+            *chunks = updated;
+            Ok(())
+        })
+    };
+    if stored.is_ok() {
+        state.history.save(public_key, &chunks).await;
+    }
+    drop(chunks);
+    drop(entry);
+
+    state.history.evict(public_key).await;
+    stored
+}
+
+/// Every chunk of `public_key`, oldest first.
+pub async fn fetch(state: &AppState, public_key: &str) -> Vec<Chunk> {
+    let chunks = state.history.entry(public_key).await.lock().await.clone();
+    state.history.evict(public_key).await;
+    chunks
+}
+
+#[cfg(test)]
+mod history_tests {
+    use std::time::{Duration, Instant};
+
+    use rschat_crypto::{KeyPair, MIN_HISTORY_LEN};
+    use rschat_protocol::Chunk;
+
+    use crate::constants::*;
+    use crate::history::{
+        HistoryError, UploadLimit, allow_upload, fetch, put, upload,
+    };
+    use crate::server::Server;
+    use crate::storage::MemoryBackend;
+
+    fn chunk(id: &str, len: usize) -> Chunk {
+        Chunk {
+            chunk_id: id.to_string(),
+            data: "0".repeat(len),
+            uploaded_at: 0,
+        }
+    }
+
+    /// Smallest valid upload.
+    fn blob() -> String {
+        "0".repeat(2 * MIN_HISTORY_LEN)
+    }
+
+    #[test]
+    fn test_put_replaces_same_id() {
+        let mut chunks = Vec::new();
+        put(&mut chunks, chunk("laptop", 100)).unwrap();
+        put(&mut chunks, chunk("phone", 100)).unwrap();
+        put(&mut chunks, chunk("laptop", 200)).unwrap();
+
+        let ids: Vec<_> = chunks.iter().map(|c| c.chunk_id.as_str()).collect();
+        assert_eq!(ids, ["phone", "laptop"]);
+        assert_eq!(chunks[1].data.len(), 200);
+    }
+
+    #[test]
+    fn test_put_requires_hex() {
+        let mut chunks = Vec::new();
+        let blob = KeyPair::generate().encrypt_history(b"hi").unwrap();
+
+        for data in [
+            "".to_string(),
+            "0".repeat(2 * MIN_HISTORY_LEN + 1),
+            "0".repeat(2 * MIN_HISTORY_LEN - 2),
+            "zz".repeat(MIN_HISTORY_LEN),
+        ] {
+            let bad = Chunk {
+                data,
+                ..chunk("laptop", 0)
+            };
+            assert_eq!(put(&mut chunks, bad), Err(HistoryError::InvalidData));
+        }
+
+        let good = Chunk {
+            data: hex::encode(blob),
+            ..chunk("laptop", 0)
+        };
+        put(&mut chunks, good).unwrap();
+        assert_eq!(chunks.len(), 1);
+    }
+
+    #[test]
+    fn test_put_limits() {
+        let mut chunks = Vec::new();
+
+        assert_eq!(
+            put(&mut chunks, chunk("", 100)),
+            Err(HistoryError::InvalidId)
+        );
+        assert_eq!(
+            put(&mut chunks, chunk("a", MAX_HISTORY_CHUNK_BYTES + 2)),
+            Err(HistoryError::TooBig)
+        );
+
+        // Oldest chunks make room for new ones.
+        let per_chunk = MAX_HISTORY_CHUNK_BYTES;
+        let fits = MAX_HISTORY_BYTES / per_chunk;
+        for i in 0..=fits {
+            put(&mut chunks, chunk(&i.to_string(), per_chunk)).unwrap();
+        }
+        assert_eq!(chunks.len(), fits);
+        assert_eq!(chunks[0].chunk_id, "1");
+    }
+
+    #[tokio::test]
+    async fn test_upload_caps_identities() {
+        let server = Server::builder().build();
+        let state = server.state();
+
+        for i in 0..MAX_HISTORY_IDENTITIES {
+            let key = i.to_string();
+            upload(state, &key, "laptop".into(), blob()).await.unwrap();
+        }
+        assert_eq!(
+            upload(state, "late", "laptop".into(), blob()).await,
+            Err(HistoryError::Full)
+        );
+        assert!(fetch(state, "late").await.is_empty());
+
+        // Those storing history already may go on.
+        upload(state, "0", "phone".into(), blob()).await.unwrap();
+        assert_eq!(fetch(state, "0").await.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_upload_caps_memory() {
+        let server = Server::builder().build();
+        let state = server.state();
+        let big = || "0".repeat(MAX_HISTORY_CHUNK_BYTES);
+
+        let fits = MAX_MEMORY_HISTORY_BYTES / MAX_HISTORY_CHUNK_BYTES;
+        for i in 0..fits {
+            let key = i.to_string();
+            upload(state, &key, "laptop".into(), big()).await.unwrap();
+        }
+        assert_eq!(
+            upload(state, "late", "laptop".into(), big()).await,
+            Err(HistoryError::Full)
+        );
+        assert!(fetch(state, "late").await.is_empty());
+
+        // Replacing a chunk with a smaller one frees memory.
+        upload(state, "0", "laptop".into(), blob()).await.unwrap();
+        upload(state, "late", "laptop".into(), blob())
+            .await
+            .unwrap();
+
+        // A backend holds history instead of memory.
+        let server =
+            Server::builder().storage(MemoryBackend::default()).build();
+        let state = server.state();
+        for i in 0..=fits {
+            let key = i.to_string();
+            upload(state, &key, "laptop".into(), big()).await.unwrap();
+        }
+    }
+
+    #[test]
+    fn test_upload_limit() {
+        let start = Instant::now();
+        let mut limit = UploadLimit::new(start);
+        for _ in 0..HISTORY_UPLOADS_PER_WINDOW {
+            assert!(limit.allow(start));
+        }
+        assert!(!limit.allow(start + Duration::from_secs(1)));
+
+        let next = start + Duration::from_secs(HISTORY_UPLOAD_WINDOW_SECS);
+        assert!(limit.allow(next));
+    }
+
+    #[tokio::test]
+    async fn test_upload_limit_per_identity() {
+        let server = Server::builder().build();
+        let state = server.state();
+        let start = Instant::now();
+        for _ in 0..HISTORY_UPLOADS_PER_WINDOW {
+            assert!(allow_upload(state, "a", start).await);
+        }
+        // Held by the identity, so a new connection doesn't reset it.
+        assert!(!allow_upload(state, "a", start).await);
+        assert!(allow_upload(state, "b", start).await);
+
+        let next = start + Duration::from_secs(HISTORY_UPLOAD_WINDOW_SECS);
+        assert!(allow_upload(state, "a", next).await);
+        assert_eq!(state.history_uploads.lock().await.len(), 1);
+    }
+}
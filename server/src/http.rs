@@ -19,10 +19,44 @@ pub mod header {
 
     pub struct HttpHeader {
         pub verb: HttpVerb,
+        /// Path without the query string.
         pub path: String,
+        /// Text after `?` in the request target.
+        pub query: Option<String>,
+        /// e.g. `HTTP/1.1`
+        pub version: String,
+        /// Header values by lowercased name. Repeated headers are joined
+        /// with `, `.
         pub table: HashMap<String, String>,
     }
 
+    impl HttpHeader {
+        /// Value of header `name`, whatever its case.
+        pub fn get(&self, name: &str) -> Option<&str> {
+            self.table
+                .get(&name.to_ascii_lowercase())
+                .map(String::as_str)
+        }
+
+        /// Value of query parameter `name`, undecoded.
+        pub fn param(&self, name: &str) -> Option<&str> {
+            self.query.as_deref()?.split('&').find_map(|pair| {
+                let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+                (key == name).then_some(value)
+            })
+        }
+
+        /// Whether the comma-separated header `name` holds `token`,
+        /// ignoring case.
+        pub fn has_token(&self, name: &str, token: &str) -> bool {
+            self.get(name).is_some_and(|value| {
+                value
+                    .split(',')
+                    .any(|t| t.trim().eq_ignore_ascii_case(token))
+            })
+        }
+    }
+
     pub fn parse(data: &str) -> Result<HttpHeader, HttpParseError> {
         let mut table: HashMap<String, String> = HashMap::new();
 
@@ -32,7 +66,12 @@ pub mod header {
 
         let mut parts = request_line.split_whitespace();
         let method = parts.next().unwrap_or("");
-        let path = parts.next().unwrap_or("").to_string();
+        let target = parts.next().unwrap_or("");
+        let (path, query) = match target.split_once('?') {
+            Some((path, query)) => (path.to_string(), Some(query.to_string())),
+            None => (target.to_string(), None),
+        };
+        let version = parts.next().unwrap_or("").to_string();
 
         let verb = match method {
             "GET" => HttpVerb::Get,
@@ -47,11 +86,60 @@ pub mod header {
                 break;
             }
 
-            if let Some((key, value)) = line.split_once(": ") {
-                table.insert(key.to_string(), value.to_string());
+            if let Some((key, value)) = line.split_once(':') {
+                let value = value.trim();
+                table
+                    .entry(key.trim().to_ascii_lowercase())
+                    .and_modify(|v: &mut String| {
+                        v.push_str(", ");
+                        v.push_str(value);
+                    })
+                    .or_insert_with(|| value.to_string());
             }
         }
 
-        Ok(HttpHeader { verb, path, table })
+        Ok(HttpHeader {
+            verb,
+            path,
+            query,
+            version,
+            table,
+        })
+    }
+}
+
+#[cfg(test)]
+mod http_header_tests {
+    use crate::http::header::parse;
+
+    #[test]
+    fn test_header_names_ignore_case() {
+        let Ok(header) = parse(
+            "GET /ws HTTP/1.1\r\n\
+             HOST: example.com\r\n\
+             connection:keep-alive\r\n\
+             Connection: Upgrade\r\n\r\n",
+        ) else {
+            panic!("failed to parse header");
+        };
+
+        assert_eq!(header.version, "HTTP/1.1");
+        assert_eq!(header.get("Host"), Some("example.com"));
+        assert_eq!(header.get("connection"), Some("keep-alive, Upgrade"));
+        assert!(header.has_token("Connection", "upgrade"));
+        assert!(!header.has_token("Connection", "close"));
+    }
+
+    #[test]
+    fn test_query() {
+        let Ok(header) = parse("GET /ws?a=1&csrf=abc&b HTTP/1.1\r\n\r\n")
+        else {
+            panic!("failed to parse header");
+        };
+
+        assert_eq!(header.path, "/ws");
+        assert_eq!(header.param("csrf"), Some("abc"));
+        assert_eq!(header.param("b"), Some(""));
+        assert_eq!(header.param("c"), None);
     }
 }
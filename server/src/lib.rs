@@ -0,0 +1,23 @@
+mod authors;
+mod blocklist;
+pub mod config;
+mod constants;
+mod contacts;
+mod handles;
+pub mod handshake;
+mod history;
+pub mod http;
+pub mod log;
+pub mod metrics;
+pub mod outbox;
+mod presence;
+mod sealed;
+mod server;
+pub mod service;
+pub mod shutdown;
+mod signature;
+mod storage;
+
+pub use crate::config::Config;
+pub use crate::server::{AppState, Hooks, Server, ServerBuilder};
+pub use crate::storage::{Backend, FileBackend, MemoryBackend};
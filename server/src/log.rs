@@ -0,0 +1,46 @@
+use std::fmt;
+
+use tracing_subscriber::EnvFilter;
+
+use crate::config::{Config, LogFormat};
+
+/// Install the global tracing subscriber according to `config`.
+pub fn init(config: &Config) {
+    let filter = EnvFilter::try_new(&config.log_level)
+        .unwrap_or_else(|_| EnvFilter::new("info"));
+
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+
+    match config.log_format {
+        LogFormat::Text => builder.init(),
+        LogFormat::Json => builder.json().init(),
+    }
+}
+
+/// Short prefix of a public key, enough to tell users apart in logs.
+pub fn key_prefix(public_key: &str) -> String {
+    public_key.chars().take(12).collect()
+}
+
+/// Hides user content (ciphertexts, names, raw JSON) from logs unless
+/// the server's `log_payloads` is enabled.
+pub struct Redacted<'a> {
+    text: &'a str,
+    shown: bool,
+}
+
+impl<'a> Redacted<'a> {
+    pub fn new(text: &'a str, shown: bool) -> Self {
+        Redacted { text, shown }
+    }
+}
+
+impl fmt::Display for Redacted<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.shown {
+            write!(f, "{}", self.text)
+        } else {
+            write!(f, "<redacted {} bytes>", self.text.len())
+        }
+    }
+}
@@ -0,0 +1,293 @@
+///
+/// Prometheus metrics, rendered in the text exposition format
+///
+/// Reference: <https://prometheus.io/docs/instrumenting/exposition_formats/>
+///
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use rschat_protocol::ws::message::FrameCounter;
+
+use crate::service::OnlineUser;
+
+/// Upper bounds of the handler latency buckets, in seconds.
+const LATENCY_BUCKETS: [f64; 10] = [
+    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.05, 0.1, 0.5,
+];
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, secs: f64) {
+        for (i, bound) in LATENCY_BUCKETS.iter().enumerate() {
+            if secs <= *bound {
+                self.buckets[i] += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += secs;
+    }
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    handshakes_accepted: AtomicU64,
+    handshakes_rejected: AtomicU64,
+    frames_in: [AtomicU64; 16],
+    frames_out: [AtomicU64; 16],
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    messages_relayed: AtomicU64,
+    messages_dropped: AtomicU64,
+    messages_blocked: AtomicU64,
+    handler_latency: Mutex<BTreeMap<&'static str, Histogram>>,
+}
+
+fn opcode_name(opcode: usize) -> Option<&'static str> {
+    match opcode {
+        0x0 => Some("continuation"),
+        0x1 => Some("text"),
+        0x2 => Some("binary"),
+        0x8 => Some("close"),
+        0x9 => Some("ping"),
+        0xa => Some("pong"),
+        _ => None,
+    }
+}
+
+impl Metrics {
+    pub fn handshake(&self, accepted: bool) {
+        if accepted {
+            self.handshakes_accepted.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.handshakes_rejected.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn frame_out(&self, opcode: u8) {
+        self.frames_out[(opcode & 0x0f) as usize]
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn bytes_in(&self, len: usize) {
+        self.bytes_in.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    pub fn bytes_out(&self, len: usize) {
+        self.bytes_out.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    pub fn message_relayed(&self) {
+        self.messages_relayed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn message_dropped(&self) {
+        self.messages_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn message_blocked(&self) {
+        self.messages_blocked.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record how long handling a payload of `kind` took.
+    pub fn handled(&self, kind: &'static str, elapsed: Duration) {
+        if let Ok(mut latency) = self.handler_latency.lock() {
+            latency
+                .entry(kind)
+                .or_default()
+                .observe(elapsed.as_secs_f64());
+        }
+    }
+
+    /// Render every metric, plus per-connection gauges for `users`.
+    pub fn render(&self, users: &HashMap<String, OnlineUser>) -> String {
+        let mut out = String::with_capacity(4096);
+        let load = |c: &AtomicU64| c.load(Ordering::Relaxed);
+
+        let _ = writeln!(
+            out,
+            "# HELP rschat_connected_users Users currently connected.\n\
+             # TYPE rschat_connected_users gauge\n\
+             rschat_connected_users {}",
+            users.len()
+        );
+
+        let _ = writeln!(
+            out,
+            "# HELP rschat_handshakes_total WebSocket upgrade attempts.\n\
+             # TYPE rschat_handshakes_total counter\n\
+             rschat_handshakes_total{{result=\"accepted\"}} {}\n\
+             rschat_handshakes_total{{result=\"rejected\"}} {}",
+            load(&self.handshakes_accepted),
+            load(&self.handshakes_rejected)
+        );
+
+        for (name, help, frames) in [
+            (
+                "rschat_frames_in_total",
+                "Frames received.",
+                &self.frames_in,
+            ),
+            ("rschat_frames_out_total", "Frames sent.", &self.frames_out),
+        ] {
+            let _ = writeln!(out, "# HELP {name} {help}");
+            let _ = writeln!(out, "# TYPE {name} counter");
+            for (opcode, count) in frames.iter().enumerate() {
+                if let Some(op) = opcode_name(opcode) {
+                    let _ = writeln!(
+                        out,
+                        "{name}{{opcode=\"{op}\"}} {}",
+                        load(count)
+                    );
+                }
+            }
+        }
+
+        let _ = writeln!(
+            out,
+            "# HELP rschat_bytes_in_total Bytes read from WebSocket clients.\n\
+             # TYPE rschat_bytes_in_total counter\n\
+             rschat_bytes_in_total {}\n\
+             # HELP rschat_bytes_out_total Bytes written to WebSocket clients.\n\
+             # TYPE rschat_bytes_out_total counter\n\
+             rschat_bytes_out_total {}",
+            load(&self.bytes_in),
+            load(&self.bytes_out)
+        );
+
+        let _ = writeln!(
+            out,
+            "# HELP rschat_messages_relayed_total Messages delivered to a recipient.\n\
+             # TYPE rschat_messages_relayed_total counter\n\
+             rschat_messages_relayed_total {}\n\
+             # HELP rschat_messages_dropped_total Messages dropped undelivered.\n\
+             # TYPE rschat_messages_dropped_total counter\n\
+             rschat_messages_dropped_total{{reason=\"unknown_recipient\"}} {}\n\
+             rschat_messages_dropped_total{{reason=\"blocked\"}} {}",
+            load(&self.messages_relayed),
+            load(&self.messages_dropped),
+            load(&self.messages_blocked)
+        );
+
+        // Aggregated, as per connection labels would name every user.
+        let outboxes = users
+            .values()
+            .flat_map(|user| user.sessions.values())
+            .map(|session| &session.outbox);
+        let (mut queued, mut deepest, mut high_water) = (0, 0, 0);
+        for outbox in outboxes {
+            queued += outbox.depth();
+            deepest = deepest.max(outbox.depth());
+            high_water = high_water.max(outbox.high_water());
+        }
+        let _ = writeln!(
+            out,
+            "# HELP rschat_queued_frames Frames waiting in writer queues.\n\
+             # TYPE rschat_queued_frames gauge\n\
+             rschat_queued_frames {queued}\n\
+             # HELP rschat_queue_depth_max Deepest writer queue.\n\
+             # TYPE rschat_queue_depth_max gauge\n\
+             rschat_queue_depth_max {deepest}\n\
+             # HELP rschat_queue_high_water Largest writer queue depth seen on a connection.\n\
+             # TYPE rschat_queue_high_water gauge\n\
+             rschat_queue_high_water {high_water}"
+        );
+
+        let name = "rschat_handler_duration_seconds";
+        let _ = writeln!(
+            out,
+            "# HELP {name} Time spent handling a client payload.\n\
+             # TYPE {name} histogram"
+        );
+        if let Ok(latency) = self.handler_latency.lock() {
+            for (kind, hist) in latency.iter() {
+                for (i, bound) in LATENCY_BUCKETS.iter().enumerate() {
+                    let _ = writeln!(
+                        out,
+                        "{name}_bucket{{kind=\"{kind}\",le=\"{bound}\"}} {}",
+                        hist.buckets[i]
+                    );
+                }
+                let _ = writeln!(
+                    out,
+                    "{name}_bucket{{kind=\"{kind}\",le=\"+Inf\"}} {}\n\
+                     {name}_sum{{kind=\"{kind}\"}} {}\n\
+                     {name}_count{{kind=\"{kind}\"}} {}",
+                    hist.count, hist.sum, hist.count
+                );
+            }
+        }
+
+        out
+    }
+}
+
+impl FrameCounter for Metrics {
+    fn frame_in(&self, opcode: u8) {
+        self.frames_in[(opcode & 0x0f) as usize]
+            .fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod metrics_tests {
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    use rschat_protocol::ws::frame;
+    use rschat_protocol::ws::message::FrameCounter;
+
+    use crate::metrics::Metrics;
+
+    #[test]
+    fn test_metrics_render_counters() {
+        let metrics = Metrics::default();
+        metrics.handshake(true);
+        metrics.handshake(false);
+        metrics.handshake(false);
+        metrics.frame_in(frame::OPCODE_TEXT);
+        metrics.frame_out(frame::OPCODE_CLOSE);
+        metrics.message_dropped();
+
+        let out = metrics.render(&HashMap::new());
+        assert!(out.contains("rschat_connected_users 0\n"));
+        assert!(
+            out.contains("rschat_handshakes_total{result=\"rejected\"} 2\n")
+        );
+        assert!(out.contains("rschat_frames_in_total{opcode=\"text\"} 1\n"));
+        assert!(out.contains("rschat_frames_out_total{opcode=\"close\"} 1\n"));
+        assert!(out.contains(
+            "rschat_messages_dropped_total{reason=\"unknown_recipient\"} 1\n"
+        ));
+        assert!(out.contains("rschat_queued_frames 0\n"));
+        assert!(out.contains("rschat_queue_high_water 0\n"));
+    }
+
+    #[test]
+    fn test_metrics_render_histogram() {
+        let metrics = Metrics::default();
+        metrics.handled("first", Duration::from_micros(200));
+        metrics.handled("first", Duration::from_secs(2));
+
+        let out = metrics.render(&HashMap::new());
+        let name = "rschat_handler_duration_seconds";
+        assert!(out.contains(&format!(
+            "{name}_bucket{{kind=\"first\",le=\"0.0001\"}} 0\n"
+        )));
+        assert!(out.contains(&format!(
+            "{name}_bucket{{kind=\"first\",le=\"0.00025\"}} 1\n"
+        )));
+        assert!(out.contains(&format!(
+            "{name}_bucket{{kind=\"first\",le=\"+Inf\"}} 2\n"
+        )));
+        assert!(out.contains(&format!("{name}_count{{kind=\"first\"}} 2\n")));
+    }
+}
@@ -0,0 +1,504 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex as SyncMutex};
+
+use bytes::BytesMut;
+use rschat_protocol::ws::deflate::Deflater;
+use rschat_protocol::ws::frame;
+use rschat_protocol::{Outbound, Payload, Protocol, now_millis};
+use serde::Serialize;
+use serde_json::Value;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio::sync::{Mutex, Notify, mpsc};
+use tokio::task::JoinHandle;
+
+use crate::constants::{MAX_OUTBOX_DEPTH, MAX_REPLAY_MESSAGES};
+use crate::metrics::Metrics;
+
+/// Writer queue of a WebSocket connection.
+///
+/// Every frame for a connection goes through its outbox, so frames are
+/// written one at a time in the order they were queued, by a single task
+/// that owns the write side of the socket. Payloads are numbered in that
+/// same order. A peer that lets more than `MAX_OUTBOX_DEPTH` frames pile
+/// up is disconnected with 1008 instead of growing the queue further.
+#[derive(Clone)]
+pub struct Outbox {
+    tx: mpsc::UnboundedSender<Outbound>,
+    stats: Arc<QueueStats>,
+    /// How payloads are encoded for this connection.
+    protocol: Protocol,
+}
+
+#[derive(Default)]
+struct QueueStats {
+    depth: AtomicUsize,
+    high_water: AtomicUsize,
+    /// Set once the queue went over `MAX_OUTBOX_DEPTH`.
+    overflowed: AtomicBool,
+    overflow: Notify,
+    history: SyncMutex<History>,
+}
+
+/// Numbering of the payloads sent to a device, carried over when it
+/// resumes on a new connection.
+#[derive(Default)]
+struct History {
+    last_seq: u64,
+    /// Recent payloads, stamped but not yet encoded, for replay on resume.
+    /// The device may have resumed with another protocol.
+    sent: VecDeque<(u64, Value)>,
+}
+
+/// A payload as written to the wire, with its sequence number and the
+/// server time in milliseconds since the epoch.
+#[derive(Serialize)]
+struct Stamped<'a> {
+    seq: u64,
+    ts: u64,
+    #[serde(flatten)]
+    payload: &'a Payload,
+}
+
+impl Outbox {
+    /// Spawn the writer task for `shared_stream`, encoding payloads with
+    /// `protocol` and compressing messages with `deflater` if
+    /// permessage-deflate was negotiated. The task exits after writing a Close frame, or once
+    /// every `Outbox` handle is dropped.
+    pub fn spawn(
+        shared_stream: Arc<Mutex<TcpStream>>,
+        protocol: Protocol,
+        deflater: Option<Deflater>,
+        metrics: Arc<Metrics>,
+    ) -> (Self, JoinHandle<()>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let stats = Arc::new(QueueStats::default());
+        let writer = tokio::spawn(write_loop(
+            shared_stream,
+            rx,
+            stats.clone(),
+            deflater,
+            metrics,
+        ));
+
+        (
+            Outbox {
+                tx,
+                stats,
+                protocol,
+            },
+            writer,
+        )
+    }
+
+    /// The subprotocol this connection negotiated.
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+
+    pub fn send(&self, msg: Outbound) {
+        // Reserve the slot before queueing, or the writer could take the
+        // frame and decrement the depth before it was counted.
+        let depth = self.stats.depth.fetch_add(1, Ordering::Relaxed);
+        if depth >= MAX_OUTBOX_DEPTH {
+            self.stats.depth.fetch_sub(1, Ordering::Relaxed);
+            // The writer closes the connection ahead of the queued frames.
+            if !self.stats.overflowed.swap(true, Ordering::Relaxed) {
+                self.stats.overflow.notify_one();
+            }
+            return;
+        }
+
+        // Receiver is gone once the connection closed, nothing to do then.
+        if self.tx.send(msg).is_err() {
+            self.stats.depth.fetch_sub(1, Ordering::Relaxed);
+            return;
+        }
+        self.stats
+            .high_water
+            .fetch_max(depth + 1, Ordering::Relaxed);
+    }
+
+    /// Number, timestamp and queue `payload`.
+    pub fn send_payload(&self, payload: &Payload) {
+        let Ok(mut history) = self.stats.history.lock() else {
+            return;
+        };
+
+        let seq = history.last_seq + 1;
+        let stamped = Stamped {
+            seq,
+            ts: now_millis(),
+            payload,
+        };
+        let Ok(value) = serde_json::to_value(&stamped) else {
+            return;
+        };
+        let msg = self.protocol.encode(&value);
+
+        if history.sent.len() == MAX_REPLAY_MESSAGES {
+            history.sent.pop_front();
+        }
+        history.last_seq = seq;
+        history.sent.push_back((seq, value));
+
+        // Queue while holding the history lock, so concurrent senders
+        // can't enqueue out of sequence order.
+        self.send(msg);
+    }
+
+    /// Queue `payload` outside the numbering, for what only concerns this
+    /// connection. It isn't replayed on resume, nor does it take a `seq`
+    /// the resumed numbering would reuse.
+    pub fn send_unnumbered(&self, payload: &Payload) {
+        if let Ok(value) = serde_json::to_value(payload) {
+            self.send(self.protocol.encode(&value));
+        }
+    }
+
+    /// Continue the numbering of `stale`, the previous connection of the
+    /// same device, and resend what it sent after `last_seq`.
+    pub fn resume_from(&self, stale: &Outbox, last_seq: u64) {
+        if self.same_channel(stale) {
+            return;
+        }
+
+        let (Ok(mut history), Ok(mut old)) =
+            (self.stats.history.lock(), stale.stats.history.lock())
+        else {
+            return;
+        };
+
+        *history = std::mem::take(&mut *old);
+
+        for (_, value) in history.sent.iter().filter(|(seq, _)| *seq > last_seq)
+        {
+            self.send(self.protocol.encode(value));
+        }
+    }
+
+    pub fn close(&self, code: u16) {
+        self.send(Outbound::Close(code));
+    }
+
+    /// Whether both handles feed the same connection.
+    pub fn same_channel(&self, other: &Outbox) -> bool {
+        self.tx.same_channel(&other.tx)
+    }
+
+    /// Frames queued but not yet written.
+    pub fn depth(&self) -> usize {
+        self.stats.depth.load(Ordering::Relaxed)
+    }
+
+    /// Whether the queue went over its limit, closing the connection.
+    pub fn overflowed(&self) -> bool {
+        self.stats.overflowed.load(Ordering::Relaxed)
+    }
+
+    /// Largest depth this queue has reached.
+    pub fn high_water(&self) -> usize {
+        self.stats.high_water.load(Ordering::Relaxed)
+    }
+}
+
+async fn write_loop(
+    shared_stream: Arc<Mutex<TcpStream>>,
+    mut rx: mpsc::UnboundedReceiver<Outbound>,
+    stats: Arc<QueueStats>,
+    mut deflater: Option<Deflater>,
+    metrics: Arc<Metrics>,
+) {
+    let mut buf = BytesMut::with_capacity(4096);
+
+    loop {
+        let msg = tokio::select! {
+            biased;
+            _ = stats.overflow.notified() => {
+                Outbound::Close(frame::CLOSE_POLICY_VIOLATION)
+            }
+            msg = rx.recv() => match msg {
+                Some(msg) => {
+                    stats.depth.fetch_sub(1, Ordering::Relaxed);
+                    msg
+                }
+                None => break,
+            },
+        };
+        buf.clear();
+
+        let closing = matches!(msg, Outbound::Close(_));
+        let (opcode, len) = match msg {
+            Outbound::Text(text) => (
+                frame::OPCODE_TEXT,
+                set_message(&mut buf, &mut deflater, frame::OPCODE_TEXT, text),
+            ),
+            Outbound::Binary(data) => (
+                frame::OPCODE_BINARY,
+                set_message(
+                    &mut buf,
+                    &mut deflater,
+                    frame::OPCODE_BINARY,
+                    data,
+                ),
+            ),
+            Outbound::Frame(opcode, data) => {
+                (opcode, frame::set_frame(&mut buf, opcode, &data))
+            }
+            Outbound::Close(code) => {
+                (frame::OPCODE_CLOSE, frame::set_close(&mut buf, code, ""))
+            }
+        };
+
+        let mut stream = shared_stream.lock().await;
+
+        if stream.write_all(&buf[..len]).await.is_err() {
+            break;
+        }
+
+        metrics.frame_out(opcode);
+        metrics.bytes_out(len);
+
+        if closing {
+            let _ = stream.shutdown().await;
+            break;
+        }
+    }
+}
+
+/// Emplace a data frame, compressed if worth it.
+fn set_message(
+    buf: &mut BytesMut,
+    deflater: &mut Option<Deflater>,
+    opcode: u8,
+    data: impl AsRef<[u8]>,
+) -> usize {
+    let data = data.as_ref();
+    match deflater.as_mut().and_then(|d| d.compress(data)) {
+        Some(compressed) => frame::set_compressed(buf, opcode, &compressed),
+        None => frame::set_frame(buf, opcode, data),
+    }
+}
+
+#[cfg(test)]
+mod outbox_tests {
+    use std::sync::Arc;
+
+    use bytes::BytesMut;
+    use rschat_protocol::ws::frame;
+    use rschat_protocol::{Encoding, Envelope, Payload, Protocol, Version};
+    use tokio::io::AsyncReadExt;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::sync::Mutex;
+
+    use crate::constants::MAX_OUTBOX_DEPTH;
+    use crate::outbox::Outbox;
+
+    /// Outbox writing to one end of a local socket, and the other end.
+    async fn pair() -> (Outbox, tokio::task::JoinHandle<()>, TcpStream) {
+        pair_with(Protocol::LEGACY).await
+    }
+
+    async fn pair_with(
+        protocol: Protocol,
+    ) -> (Outbox, tokio::task::JoinHandle<()>, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+
+        let (outbox, writer) = Outbox::spawn(
+            Arc::new(Mutex::new(server)),
+            protocol,
+            None,
+            Arc::default(),
+        );
+        (outbox, writer, client)
+    }
+
+    /// Read text frames until the Close frame.
+    async fn read_payloads(client: &mut TcpStream) -> Vec<serde_json::Value> {
+        let mut buf = BytesMut::new();
+        let mut out = Vec::new();
+
+        loop {
+            match frame::get_frame(&buf, usize::MAX) {
+                Ok((header, data)) => {
+                    let _ = buf.split_to(header.header_len + data.len());
+                    if header.opcode == frame::OPCODE_CLOSE {
+                        return out;
+                    }
+                    out.push(serde_json::from_slice(&data).unwrap());
+                }
+                Err(frame::FrameError::Incomplete) => {
+                    assert!(client.read_buf(&mut buf).await.unwrap() > 0);
+                }
+                Err(err) => panic!("bad frame: {err:?}"),
+            }
+        }
+    }
+
+    fn relay(sender: &str, n: usize) -> Payload {
+        Payload::RelayMessage {
+            sender: sender.to_string(),
+            payload: n.to_string(),
+            group_id: None,
+            envelope: Envelope {
+                message_id: format!("{sender}-{n}"),
+                received_at: 0,
+                sent_at: None,
+            },
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_senders_in_order() {
+        let (outbox, writer, mut client) = pair().await;
+
+        let senders: Vec<_> = ["a", "b", "c", "d"]
+            .into_iter()
+            .map(|sender| {
+                let outbox = outbox.clone();
+                tokio::spawn(async move {
+                    for n in 0..200 {
+                        outbox.send_payload(&relay(sender, n));
+                        if n % 7 == 0 {
+                            tokio::task::yield_now().await;
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        for sender in senders {
+            sender.await.unwrap();
+        }
+        outbox.close(frame::CLOSE_NORMAL);
+        writer.await.unwrap();
+        // Every frame was counted before the writer took it.
+        assert_eq!(outbox.depth(), 0);
+        assert!(outbox.high_water() <= 801);
+
+        let payloads = read_payloads(&mut client).await;
+        assert_eq!(payloads.len(), 800);
+
+        // Sequence numbers follow the wire order without gaps, and each
+        // sender's payloads keep the order they were sent in.
+        let mut next = std::collections::HashMap::new();
+        for (i, payload) in payloads.iter().enumerate() {
+            assert_eq!(payload["seq"], i as u64 + 1);
+            assert!(payload["ts"].as_u64().unwrap() > 0);
+            assert_eq!(payload["kind"], "relay_message");
+
+            let sender = payload["sender"].as_str().unwrap().to_string();
+            let n: usize =
+                payload["payload"].as_str().unwrap().parse().unwrap();
+            let expected = next.entry(sender).or_insert(0);
+            assert_eq!(n, *expected);
+            *expected += 1;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_resume_continues_sequence() {
+        let (stale, stale_writer, mut stale_client) = pair().await;
+        for n in 0..5 {
+            stale.send_payload(&relay("a", n));
+        }
+        stale.close(frame::CLOSE_NORMAL);
+        stale_writer.await.unwrap();
+        assert_eq!(read_payloads(&mut stale_client).await.len(), 5);
+
+        // The device only saw up to 3 before the connection dropped.
+        let (outbox, writer, mut client) = pair().await;
+        outbox.resume_from(&stale, 3);
+        outbox.send_payload(&relay("a", 5));
+        outbox.close(frame::CLOSE_NORMAL);
+        writer.await.unwrap();
+
+        let seqs: Vec<_> = read_payloads(&mut client)
+            .await
+            .iter()
+            .map(|p| p["seq"].as_u64().unwrap())
+            .collect();
+        assert_eq!(seqs, [4, 5, 6]);
+    }
+
+    #[tokio::test]
+    async fn test_unnumbered_before_resume() {
+        let (stale, stale_writer, mut stale_client) = pair().await;
+        stale.send_payload(&relay("a", 0));
+        stale.close(frame::CLOSE_NORMAL);
+        stale_writer.await.unwrap();
+        read_payloads(&mut stale_client).await;
+
+        // Sent before the new connection knows it resumes.
+        let (outbox, writer, mut client) = pair().await;
+        outbox.send_unnumbered(&Payload::Challenge {
+            nonce: "n".to_string(),
+        });
+        outbox.resume_from(&stale, 1);
+        outbox.send_payload(&relay("a", 1));
+        outbox.close(frame::CLOSE_NORMAL);
+        writer.await.unwrap();
+
+        let payloads = read_payloads(&mut client).await;
+        assert_eq!(payloads[0]["kind"], "challenge");
+        assert!(payloads[0]["seq"].is_null());
+        assert_eq!(payloads[1]["seq"], 2);
+    }
+
+    #[tokio::test]
+    async fn test_resume_replays_in_new_protocol() {
+        let (stale, stale_writer, mut stale_client) = pair().await;
+        stale.send_payload(&relay("a", 0));
+        stale.close(frame::CLOSE_NORMAL);
+        stale_writer.await.unwrap();
+        assert!(
+            read_payloads(&mut stale_client).await[0]["envelope"].is_null()
+        );
+
+        let v2 = Protocol {
+            version: Version::V2,
+            encoding: Encoding::Json,
+        };
+        let (outbox, writer, mut client) = pair_with(v2).await;
+        outbox.resume_from(&stale, 0);
+        outbox.close(frame::CLOSE_NORMAL);
+        writer.await.unwrap();
+
+        let payloads = read_payloads(&mut client).await;
+        assert_eq!(payloads[0]["seq"], 1);
+        assert_eq!(payloads[0]["envelope"]["message_id"], "a-0");
+    }
+
+    #[tokio::test]
+    async fn test_overflow_closes_with_policy_violation() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        let stream = Arc::new(Mutex::new(server));
+
+        // Hold the socket so nothing gets written while the queue fills.
+        let held = stream.clone().lock_owned().await;
+        let (outbox, writer) =
+            Outbox::spawn(stream, Protocol::LEGACY, None, Arc::default());
+        for n in 0..MAX_OUTBOX_DEPTH + 10 {
+            outbox.send_payload(&relay("a", n));
+        }
+        assert!(outbox.overflowed());
+        assert_eq!(outbox.depth(), MAX_OUTBOX_DEPTH);
+        drop(held);
+        writer.await.unwrap();
+
+        let mut buf = BytesMut::new();
+        while client.read_buf(&mut buf).await.unwrap() > 0 {}
+        let (header, data) = frame::get_frame(&buf, usize::MAX).unwrap();
+        assert_eq!(header.opcode, frame::OPCODE_CLOSE);
+        assert_eq!(data[..2], frame::CLOSE_POLICY_VIOLATION.to_be_bytes());
+    }
+}
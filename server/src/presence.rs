@@ -0,0 +1,170 @@
+use std::sync::Arc;
+
+use rschat_protocol::{Payload, PresenceStatus};
+use tokio::task::AbortHandle;
+use tokio::time::{Instant, sleep_until};
+use tracing::{Instrument, debug};
+
+use crate::AppState;
+use crate::constants::MAX_STATUS_TEXT_CHARS;
+
+/// Typing sender and DM recipient (`None` for the group chat).
+pub(crate) type TypingKey = (String, Option<String>);
+
+/// An active typing indicator.
+pub(crate) struct Typing {
+    /// When it expires unless refreshed.
+    deadline: Instant,
+    /// Stops it at the deadline. Refreshes only move the deadline, so
+    /// each indicator has a single timer.
+    timer: AbortHandle,
+}
+
+/// Update the presence of `public_key` and broadcast it to everyone else.
+pub async fn set_presence(
+    state: &AppState,
+    public_key: &str,
+    status: PresenceStatus,
+    text: Option<String>,
+) {
+    let text = text
+        .map(|t| t.chars().take(MAX_STATUS_TEXT_CHARS).collect::<String>())
+        .filter(|t| !t.is_empty());
+
+    let mut users = state.users.lock().await;
+
+    match users.get_mut(public_key) {
+        Some(user) => {
+            user.status = status;
+            user.status_text = text.clone();
+        }
+        None => return,
+    }
+
+    let Some(sender) = users.get(public_key) else {
+        return;
+    };
+
+    let msg = Payload::Presence {
+        user_id: public_key.to_string(),
+        status,
+        text,
+    };
+
+    for (other_public_key, user) in users.iter() {
+        if other_public_key != public_key && user.listens_to(sender) {
+            user.send(&msg);
+        }
+    }
+}
+
+/// Start or stop the typing indicator of `sender`. A started indicator
+/// is stopped by the server if the client doesn't refresh it in time.
+/// Indicators for DM recipients who aren't online are ignored.
+pub async fn typing(
+    state: &Arc<AppState>,
+    sender: &str,
+    recipient: Option<String>,
+    typing: bool,
+) {
+    if let Some(recipient) = &recipient
+        && !state.users.lock().await.contains_key(recipient)
+    {
+        debug!("typing to someone offline, ignoring");
+        return;
+    }
+    let key = (sender.to_string(), recipient);
+
+    if !typing {
+        if let Some(stopped) = state.typing.lock().await.remove(&key) {
+            stopped.timer.abort();
+            notify(state, &key, false).await;
+        }
+        return;
+    }
+
+    let deadline = Instant::now() + state.config.typing_timeout;
+    let mut active = state.typing.lock().await;
+    if let Some(current) = active.get_mut(&key) {
+        current.deadline = deadline;
+        return;
+    }
+
+    let timer = tokio::spawn(
+        expire(state.clone(), key.clone(), deadline).in_current_span(),
+    );
+    active.insert(
+        key.clone(),
+        Typing {
+            deadline,
+            timer: timer.abort_handle(),
+        },
+    );
+    drop(active);
+
+    notify(state, &key, true).await;
+}
+
+/// Stop the indicator of `key` once its deadline passes without refresh.
+async fn expire(state: Arc<AppState>, key: TypingKey, mut deadline: Instant) {
+    loop {
+        sleep_until(deadline).await;
+
+        let mut active = state.typing.lock().await;
+        match active.get(&key) {
+            Some(current) if current.deadline > deadline => {
+                deadline = current.deadline;
+            }
+            Some(_) => {
+                active.remove(&key);
+                drop(active);
+                notify(&state, &key, false).await;
+                return;
+            }
+            None => return,
+        }
+    }
+}
+
+/// Forget every typing indicator of a user who left.
+pub async fn clear_typing(state: &AppState, sender: &str) {
+    state.typing.lock().await.retain(|(s, _), typing| {
+        let keep = s != sender;
+        if !keep {
+            typing.timer.abort();
+        }
+        keep
+    });
+}
+
+async fn notify(state: &AppState, key: &TypingKey, typing: bool) {
+    let (sender, recipient) = key;
+    let users = state.users.lock().await;
+
+    let Some(typist) = users.get(sender) else {
+        return;
+    };
+
+    let msg = Payload::UserTyping {
+        user_id: sender.clone(),
+        group_id: recipient.clone(),
+        typing,
+    };
+
+    match recipient {
+        Some(recipient) => {
+            if let Some(user) = users.get(recipient)
+                && user.listens_to(typist)
+            {
+                user.send(&msg);
+            }
+        }
+        None => {
+            for (public_key, user) in users.iter() {
+                if public_key != sender && user.listens_to(typist) {
+                    user.send(&msg);
+                }
+            }
+        }
+    }
+}
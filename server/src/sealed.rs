@@ -0,0 +1,62 @@
+///
+/// Access keys for sealed-sender delivery
+///
+/// A user who accepts sealed messages registers the SHA-256 of a random
+/// access key, and hands the key itself to the peers allowed to use it.
+/// Senders present it as the delivery token of `send_sealed`, over a
+/// connection that never logged in, so the server can deliver without
+/// learning who sent the message. Rotating the key cuts off everyone who
+/// had the old one, and blocking someone drops it, since the server can't
+/// tell a blocked sender by its token.
+///
+use rschat_protocol::Payload;
+
+use crate::AppState;
+
+/// Register the access key hash of `public_key`, or stop accepting sealed
+/// messages with `None`.
+pub async fn set_access_key(
+    state: &AppState,
+    public_key: &str,
+    hash: Option<String>,
+) {
+    let mut keys = state.access_keys.lock().await;
+
+    match hash {
+        Some(hash) => keys.insert(public_key.to_string(), hash.to_lowercase()),
+        None => keys.remove(public_key),
+    };
+
+    state.access_keys.save(&keys).await;
+}
+
+/// Drop the access key of `public_key` and tell its devices, so they
+/// hand a new one to everyone but whoever they just blocked.
+pub async fn revoke(state: &AppState, public_key: &str) {
+    let mut keys = state.access_keys.lock().await;
+    if keys.remove(public_key).is_none() {
+        return;
+    }
+    state.access_keys.save(&keys).await;
+    drop(keys);
+
+    if let Some(user) = state.users.lock().await.get(public_key) {
+        user.send(&Payload::AccessKeyRevoked);
+    }
+}
+
+/// Whether `token` is the access key `recipient` registered.
+pub async fn may_deliver(
+    state: &AppState,
+    recipient: &str,
+    token: &str,
+) -> bool {
+    let hash = rschat_crypto::access_key_hash(token);
+
+    state
+        .access_keys
+        .lock()
+        .await
+        .get(recipient)
+        .is_some_and(|registered| *registered == hash)
+}
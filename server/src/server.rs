@@ -0,0 +1,386 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::AtomicUsize;
+
+use rschat_protocol::Chunk;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpListener;
+use tokio::sync::{Mutex, watch};
+use tokio::task::JoinSet;
+use tokio::time::timeout;
+use tracing::{Instrument, info, info_span, warn};
+
+use crate::authors::Authors;
+use crate::blocklist::Lists;
+use crate::config::Config;
+use crate::contacts::Contacts;
+use crate::handles::Registry;
+use crate::history::UploadLimit;
+use crate::log::Redacted;
+use crate::metrics::Metrics;
+use crate::presence::{Typing, TypingKey};
+use crate::service::{self, Login, OnlineUser};
+use crate::storage::{Backend, FileBackend, KeyedStore, Store};
+
+/// Callbacks into the application embedding the server. They run on the
+/// task of the connection that caused them, so they should return quickly.
+pub trait Hooks: Send + Sync {
+    /// The first device of `public_key` logged in.
+    fn user_joined(&self, _public_key: &str, _name: &str) {}
+
+    /// The last device of `public_key` left.
+    fn user_left(&self, _public_key: &str) {}
+
+    /// A message from `sender` reached `recipient`.
+    fn message_relayed(&self, _sender: &str, _recipient: &str) {}
+}
+
+impl Hooks for () {}
+
+/// Everything one server instance knows. Nothing is shared between
+/// instances, so several can run in one process.
+pub struct AppState {
+    pub(crate) config: Config,
+    pub(crate) metrics: Arc<Metrics>,
+    pub(crate) hooks: Box<dyn Hooks>,
+    /// Embedded in `index.html` and required on upgrade if `config.csrf`.
+    pub(crate) csrf_token: String,
+    pub(crate) users: Mutex<HashMap<String, OnlineUser>>,
+    /// Session each resume token belongs to. Locked after `users`.
+    pub(crate) resume_tokens: Mutex<HashMap<String, Login>>,
+    pub(crate) authors: Mutex<Authors>,
+    pub(crate) blocklists: Store<HashMap<String, Lists>>,
+    pub(crate) contacts: Store<HashMap<String, Contacts>>,
+    pub(crate) handles: Store<Registry>,
+    pub(crate) history: KeyedStore<Vec<Chunk>>,
+    /// Bytes of history held without a backend.
+    pub(crate) history_bytes: AtomicUsize,
+    /// History uploads of each identity in the current window.
+    pub(crate) history_uploads: Mutex<HashMap<String, UploadLimit>>,
+    pub(crate) access_keys: Store<HashMap<String, String>>,
+    /// Active typing indicators.
+    pub(crate) typing: Mutex<HashMap<TypingKey, Typing>>,
+    shutdown: watch::Sender<bool>,
+}
+
+impl AppState {
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// `text` for logs, hidden unless `config.log_payloads`.
+    pub(crate) fn redacted<'a>(&self, text: &'a str) -> Redacted<'a> {
+        Redacted::new(text, self.config.log_payloads)
+    }
+
+    /// Whether open connections were asked to close.
+    pub(crate) fn shutting_down(&self) -> bool {
+        *self.shutdown.borrow()
+    }
+}
+
+#[derive(Default)]
+pub struct ServerBuilder {
+    config: Config,
+    hooks: Option<Box<dyn Hooks>>,
+    storage: Option<Arc<dyn Backend>>,
+}
+
+impl ServerBuilder {
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Where to persist state, or `None` to keep it in memory. Overrides
+    /// the `data_dir` of the config.
+    pub fn data_dir(mut self, data_dir: Option<PathBuf>) -> Self {
+        self.config.data_dir = data_dir;
+        self
+    }
+
+    /// Persist state in `backend` rather than in `data_dir`.
+    pub fn storage(mut self, backend: impl Backend + 'static) -> Self {
+        self.storage = Some(Arc::new(backend));
+        self
+    }
+
+    pub fn hooks(mut self, hooks: impl Hooks + 'static) -> Self {
+        self.hooks = Some(Box::new(hooks));
+        self
+    }
+
+    /// Load persisted state and make the server. It doesn't listen until
+    /// `serve` is called.
+    pub fn build(self) -> Server {
+        let storage = self.storage.or_else(|| {
+            let dir = self.config.data_dir.clone()?;
+            Some(Arc::new(FileBackend::new(dir)) as Arc<dyn Backend>)
+        });
+
+        let state = AppState {
+            metrics: Arc::default(),
+            hooks: self.hooks.unwrap_or_else(|| Box::new(())),
+            csrf_token: service::new_token(),
+            users: Mutex::default(),
+            resume_tokens: Mutex::default(),
+            authors: Mutex::default(),
+            blocklists: Store::open(storage.clone(), "blocklists"),
+            contacts: Store::open(storage.clone(), "contacts"),
+            handles: Store::open(storage.clone(), "handles"),
+            history: KeyedStore::open(storage.clone(), "history"),
+            history_bytes: AtomicUsize::new(0),
+            history_uploads: Mutex::default(),
+            access_keys: Store::open(storage.clone(), "access_keys"),
+            typing: Mutex::default(),
+            shutdown: watch::Sender::new(false),
+            config: self.config,
+        };
+
+        Server {
+            state: Arc::new(state),
+        }
+    }
+}
+
+/// A chat server. Clones are handles to the same instance.
+#[derive(Clone)]
+pub struct Server {
+    state: Arc<AppState>,
+}
+
+impl Server {
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder::default()
+    }
+
+    pub fn state(&self) -> &AppState {
+        &self.state
+    }
+
+    /// Close every WebSocket with 1001 and make `serve` return once they
+    /// drained.
+    pub fn shutdown(&self) {
+        self.state.shutdown.send_replace(true);
+    }
+
+    /// Handle connections from `listener` until `shutdown`, then give open
+    /// connections up to `drain_timeout` to send their Close frame and
+    /// drain their writer queues.
+    pub async fn serve(&self, listener: TcpListener) {
+        let mut connections = JoinSet::new();
+        let mut shutdown = self.state.shutdown.subscribe();
+
+        loop {
+            let (stream, remote) = tokio::select! {
+                _ = shutdown.wait_for(|stop| *stop) => break,
+                Some(_) = connections.join_next() => continue,
+                accepted = listener.accept() => match accepted {
+                    Ok(accepted) => accepted,
+                    Err(_) => continue,
+                },
+            };
+
+            let shared_stream = Arc::new(Mutex::new(stream));
+            let state = self.state.clone();
+
+            let span = info_span!(
+                "conn",
+                %remote,
+                ws_id = tracing::field::Empty,
+                user = tracing::field::Empty,
+            );
+
+            let task = async move {
+                if let Err(err) =
+                    service::request_handler(&state, shared_stream.clone())
+                        .await
+                {
+                    let msg = err.to_string();
+                    warn!(error = %msg, "request failed");
+
+                    let response = format!(
+                        "HTTP/1.1 400 Bad Request\r\n\
+                         Content-Type: text/plain\r\n\
+                         Content-Length: {}\r\n\r\n{}",
+                        msg.len(),
+                        msg
+                    );
+
+                    let mut stream = shared_stream.lock().await;
+                    let _ = stream.write_all(response.as_bytes()).await;
+                }
+            };
+
+            connections.spawn(task.instrument(span));
+        }
+
+        drop(listener);
+
+        info!(connections = connections.len(), "shutting down");
+
+        let drained = timeout(self.state.config.drain_timeout, async {
+            while connections.join_next().await.is_some() {}
+        })
+        .await;
+
+        if drained.is_err() {
+            warn!("drain timed out, dropping remaining connections");
+            connections.shutdown().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod server_tests {
+    use std::sync::Arc;
+
+    use rschat_crypto::KeyPair;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    use crate::config::Config;
+    use crate::handles;
+    use crate::server::{Server, ServerBuilder};
+    use crate::storage::MemoryBackend;
+
+    async fn start() -> (Server, String, tokio::task::JoinHandle<()>) {
+        start_with(Server::builder()).await
+    }
+
+    async fn start_with(
+        builder: ServerBuilder,
+    ) -> (Server, String, tokio::task::JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = builder.build();
+        let serving = server.clone();
+        let task = tokio::spawn(async move { serving.serve(listener).await });
+        (server, addr, task)
+    }
+
+    async fn get(addr: &str, request: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    fn rejected(metrics: &str) -> &str {
+        metrics
+            .lines()
+            .find(|l| {
+                l.starts_with("rschat_handshakes_total{result=\"rejected\"}")
+            })
+            .unwrap()
+            .rsplit(' ')
+            .next()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_instances_are_independent() {
+        let (a, a_addr, a_task) = start().await;
+        let (_b, b_addr, _b_task) = start().await;
+
+        // An upgrade without any WebSocket headers is refused.
+        get(&a_addr, "GET /ws HTTP/1.1\r\nHost: a\r\n\r\n").await;
+
+        let metrics = "GET /metrics HTTP/1.1\r\nHost: a\r\n\r\n";
+        assert_eq!(rejected(&get(&a_addr, metrics).await), "1");
+        assert_eq!(rejected(&get(&b_addr, metrics).await), "0");
+
+        // Stopping one instance leaves the other serving.
+        a.shutdown();
+        a_task.await.unwrap();
+        assert_eq!(rejected(&get(&b_addr, metrics).await), "0");
+    }
+
+    #[tokio::test]
+    async fn test_injected_storage() {
+        let memory = Arc::new(MemoryBackend::default());
+        let keys = KeyPair::generate();
+        let public_key = keys.public_key().to_hex();
+
+        let first = Server::builder().storage(memory.clone()).build();
+        let now = rschat_protocol::now_millis() / 1000;
+        let message = format!("rschat:claim_handle:alice:{public_key}:{now}");
+        let signature = keys.sign(message.as_bytes()).to_hex();
+        handles::claim(first.state(), &public_key, "alice", now, &signature)
+            .await
+            .unwrap();
+
+        // A new instance on the same backend picks the handle up.
+        let (_second, addr, _task) =
+            start_with(Server::builder().storage(memory)).await;
+        let found = get(&addr, "GET /handles/alice HTTP/1.1\r\n\r\n").await;
+        assert!(found.starts_with("HTTP/1.1 200 OK"));
+        assert!(found.contains(&public_key));
+        assert!(!found.contains("devices"));
+    }
+
+    #[tokio::test]
+    async fn test_per_instance_settings() {
+        let dir = std::env::temp_dir()
+            .join(format!("rschat-static-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("hello.html"), "hello").unwrap();
+
+        let (quiet, addr, _task) =
+            start_with(Server::builder().config(Config {
+                static_dir: dir.clone(),
+                ..Config::default()
+            }))
+            .await;
+        let loud = Server::builder()
+            .config(Config {
+                log_payloads: true,
+                ..Config::default()
+            })
+            .build();
+
+        assert_eq!(
+            quiet.state().redacted("hi").to_string(),
+            "<redacted 2 bytes>"
+        );
+        assert_eq!(loud.state().redacted("hi").to_string(), "hi");
+
+        let page = get(&addr, "GET /hello.html HTTP/1.1\r\n\r\n").await;
+        assert!(page.starts_with("HTTP/1.1 200 OK"));
+        assert!(page.ends_with("\r\n\r\nhello"));
+
+        // Nothing outside the static directory is served.
+        let name = dir.file_name().unwrap().to_str().unwrap();
+        let climb = format!("GET /../{name}/hello.html HTTP/1.1\r\n\r\n");
+        assert!(get(&addr, &climb).await.starts_with("HTTP/1.1 404"));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_csrf_token_in_index() {
+        let dir = std::env::temp_dir()
+            .join(format!("rschat-index-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("index.html"), "token={{csrf_token}}").unwrap();
+
+        let (server, addr, _task) =
+            start_with(Server::builder().config(Config {
+                static_dir: dir.clone(),
+                csrf: true,
+                ..Config::default()
+            }))
+            .await;
+        let token = format!("token={}", server.state().csrf_token);
+
+        for path in ["/", "/index.html"] {
+            let request = format!("GET {path} HTTP/1.1\r\n\r\n");
+            let page = get(&addr, &request).await;
+            assert!(page.ends_with(&token), "{path}: {page}");
+        }
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
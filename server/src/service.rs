@@ -1,23 +1,39 @@
+use std::collections::HashMap;
 use std::io;
+use std::path::{Component, Path};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use base64::Engine;
-use base64::engine::general_purpose::STANDARD as B64;
 use bytes::BytesMut;
+use rschat_protocol::ws::deflate::{self, Deflater, Inflater};
+use rschat_protocol::ws::frame;
+use rschat_protocol::ws::message::{self, Reader};
+use rschat_protocol::{
+    Device, Envelope, Outbound, Payload, PresenceStatus, Protocol, User,
+    now_millis,
+};
 use serde::{Deserialize, Serialize};
-use sha1::{Digest, Sha1};
 use tokio::fs;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
 use tokio::sync::Mutex;
 use tokio::task::yield_now;
-use tokio::time::timeout;
+use tokio::time::{sleep, timeout};
+use tracing::{Instrument, Span, debug, info, warn};
 
-use crate::USERS;
+use crate::AppState;
+use crate::authors;
+use crate::blocklist::{self, Lists};
+use crate::config::Roster;
 use crate::constants::*;
+use crate::contacts::{self, Change, ContactError, Contacts};
+use crate::handles::{self, HandleError};
+use crate::handshake::{self, HandshakeError};
+use crate::history::{self, HistoryError};
 use crate::http::header::{self, HttpHeader, HttpVerb};
-use crate::ws::frame;
+use crate::log::key_prefix;
+use crate::outbox::Outbox;
+use crate::{presence, sealed, signature};
 
 #[derive(Serialize, Deserialize)]
 pub struct Message {
@@ -25,135 +41,799 @@ pub struct Message {
     pub payload: String,
 }
 
-fn default_stream() -> Arc<Mutex<TcpStream>> {
-    panic!("shared_stream should never be deserialized");
+/// One connected device of a user.
+#[derive(Clone)]
+pub struct Session {
+    /// Device's own public key, for peers that encrypt per device.
+    pub device_key: Option<String>,
+    pub outbox: Outbox,
+
+    /// Secret the device presents to `resume` this session.
+    pub resume_token: String,
+}
+
+/// Random hex token, unguessable by other clients.
+pub fn new_token() -> String {
+    random_hex::<32>()
+}
+
+/// Compare secrets in time that doesn't depend on where they differ.
+pub(crate) fn same_secret(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |diff, (x, y)| diff | (x ^ y))
+            == 0
+}
+
+fn random_hex<const N: usize>() -> String {
+    let mut bytes = [0u8; N];
+    getrandom::getrandom(&mut bytes).expect("no system randomness");
+    hex::encode(bytes)
+}
+
+/// Envelope of a message received just now, with a random id.
+fn new_envelope(sent_at: Option<u64>) -> Envelope {
+    envelope_with_id(random_hex::<16>(), sent_at)
+}
+
+fn envelope_with_id(message_id: String, sent_at: Option<u64>) -> Envelope {
+    Envelope {
+        message_id,
+        received_at: now_millis(),
+        sent_at,
+    }
 }
 
-#[derive(Serialize, Deserialize, Clone)]
-pub struct User {
+/// A user with at least one device connected, or one that may still
+/// resume.
+pub struct OnlineUser {
     pub id: String,
     pub name: String,
     pub public_key: Option<String>,
+    /// Unique handle claimed by this user's key.
+    pub handle: Option<String>,
+    pub status: PresenceStatus,
+    pub status_text: Option<String>,
+    /// Who this user may see, the server's `roster` setting.
+    pub roster: Roster,
+    /// Connected devices, keyed by device id.
+    pub sessions: HashMap<String, Session>,
+    pub lists: Lists,
+    pub contacts: Contacts,
+}
+
+impl OnlineUser {
+    /// Whether the users may see and message each other: they are
+    /// contacts, unless every user is listed, and neither blocked the other.
+    pub fn can_see(&self, other: &OnlineUser) -> bool {
+        let listed = match self.roster {
+            Roster::Lobby => true,
+            Roster::Contacts => self.contacts.has(&other.id),
+        };
+
+        listed && !self.lists.blocks(&other.id) && !other.lists.blocks(&self.id)
+    }
 
-    #[serde(skip)]
-    #[serde(default = "default_stream")]
-    pub shared_stream: Arc<Mutex<TcpStream>>,
+    /// Whether presence and typing of `other` should reach this user.
+    pub fn listens_to(&self, other: &OnlineUser) -> bool {
+        self.can_see(other) && !self.lists.mutes(&other.id)
+    }
+
+    pub fn devices(&self) -> Vec<Device> {
+        let mut devices: Vec<Device> = self
+            .sessions
+            .iter()
+            .map(|(id, session)| Device {
+                id: id.clone(),
+                public_key: session.device_key.clone(),
+            })
+            .collect();
+        devices.sort_by(|a, b| a.id.cmp(&b.id));
+        devices
+    }
+
+    /// What peers are told about this user in `new_user`.
+    pub fn profile(&self) -> User {
+        User {
+            id: self.id.clone(),
+            name: self.name.clone(),
+            public_key: self.public_key.clone(),
+            handle: self.handle.clone(),
+            status: self.status,
+            status_text: self.status_text.clone(),
+            devices: self.devices(),
+        }
+    }
+
+    /// Queue `msg` for delivery to every device of this user.
+    pub fn send(&self, msg: &Payload) {
+        for session in self.sessions.values() {
+            session.outbox.send_payload(msg);
+        }
+    }
+
+    /// Queue `msg` for one device. Returns false if it is not connected.
+    pub fn send_to_device(&self, device_id: &str, msg: &Payload) -> bool {
+        match self.sessions.get(device_id) {
+            Some(session) => {
+                session.outbox.send_payload(msg);
+                true
+            }
+            None => false,
+        }
+    }
 }
 
-#[derive(Serialize, Deserialize)]
-#[serde(tag = "kind")]
-pub enum Payload {
-    #[serde(rename = "send_message")]
-    SendMessage {
-        recipient: String,
-        payload: String,
-        #[serde(skip_serializing_if = "Option::is_none")]
-        group_id: Option<String>,
-    },
-
-    #[serde(rename = "relay_message")]
-    RelayMessage {
-        sender: String,
-        payload: String,
-        #[serde(skip_serializing_if = "Option::is_none")]
-        group_id: Option<String>,
-    },
-
-    #[serde(rename = "first")]
-    First { public_key: String, name: String },
-
-    #[serde(rename = "new_user")]
-    NewUser { user: User },
-
-    #[serde(rename = "user_left")]
-    UserLeft { user_id: String },
+/// Sent to a connection before closing it, when the same device
+/// registered again from another one.
+const DEVICE_REPLACED: &str = "device connected elsewhere";
+
+/// Identity and device a connection registered with `first`.
+#[derive(Clone)]
+pub(crate) struct Login {
+    public_key: String,
+    device_id: String,
 }
 
 async fn client_request_handler(
+    state: &Arc<AppState>,
     shared_stream: Arc<Mutex<TcpStream>>,
-    mut buf: BytesMut,
+    buf: BytesMut,
     ws_id: String,
+    protocol: Protocol,
+    deflate: Option<deflate::Params>,
 ) -> io::Result<()> {
-    let mut user_public_key: Option<String> = None;
+    Span::current().record("ws_id", ws_id.as_str());
+
+    let mut reader =
+        Reader::new(state.config.max_frame_size, state.config.max_message_size);
+    reader.require_mask();
+    reader.set_metrics(state.metrics.clone());
+    let deflater = deflate.as_ref().map(|params| {
+        reader.set_inflater(Inflater::new(params));
+        Deflater::new(params, state.config.deflate_threshold)
+    });
+
+    let (outbox, mut writer) = Outbox::spawn(
+        shared_stream.clone(),
+        protocol,
+        deflater,
+        state.metrics.clone(),
+    );
+    let mut login: Option<Login> = None;
+
+    let result = client_read_loop(
+        state,
+        &shared_stream,
+        &outbox,
+        reader,
+        buf,
+        &ws_id,
+        &mut login,
+    )
+    .await;
+
+    if let Some(code) = result.as_ref().ok().copied().flatten() {
+        outbox.close(code);
+    }
+
+    if let Some(login) = login {
+        if state.shutting_down() {
+            let mut users = state.users.lock().await;
+            let left = remove_session(state, &mut users, &login, &outbox).await;
+            drop(users);
+            if left == Some(true) {
+                state.hooks.user_left(&login.public_key);
+            }
+        } else if let Ok(Some(_)) = result {
+            user_leave(state, &login, &outbox).await;
+        } else {
+            // The connection dropped without a Close frame, give the device
+            // a chance to resume before telling peers it left.
+            outbox.close(frame::CLOSE_GOING_AWAY);
+
+            let state = state.clone();
+            let outbox = outbox.clone();
+            tokio::spawn(
+                async move {
+                    sleep(state.config.resume_grace).await;
+                    user_leave(&state, &login, &outbox).await;
+                }
+                .in_current_span(),
+            );
+        }
+    }
+
+    // Let the writer drain whatever is still queued for this connection,
+    // unless the peer stopped reading.
+    drop(outbox);
+    if timeout(state.config.drain_timeout, &mut writer)
+        .await
+        .is_err()
+    {
+        writer.abort();
+    }
+
+    result.map(|_| ())
+}
+
+/// Read and handle client messages until the connection should end.
+/// Returns the close code to send, if any.
+async fn client_read_loop(
+    state: &Arc<AppState>,
+    shared_stream: &Arc<Mutex<TcpStream>>,
+    outbox: &Outbox,
+    mut reader: Reader,
+    mut buf: BytesMut,
+    ws_id: &str,
+    login: &mut Option<Login>,
+) -> io::Result<Option<u16>> {
+    buf.clear();
+
+    // `first` proves the key by signing this. Unnumbered, as a `resume`
+    // carries on with the numbering of the old connection.
+    let nonce = new_token();
+    outbox.send_unnumbered(&Payload::Challenge {
+        nonce: nonce.clone(),
+    });
 
     loop {
+        if state.shutting_down() {
+            return Ok(Some(frame::CLOSE_GOING_AWAY));
+        }
+        if outbox.overflowed() {
+            warn!("writer queue full, closing");
+            return Ok(Some(frame::CLOSE_POLICY_VIOLATION));
+        }
+
         let mut stream = shared_stream.lock().await;
 
-        if let Err(_) =
-            timeout(Duration::from_millis(10), stream.readable()).await
+        if timeout(Duration::from_millis(10), stream.readable())
+            .await
+            .is_err()
         {
             drop(stream);
             yield_now().await;
             continue;
         }
 
-        buf.clear();
-
         let len = stream.read_buf(&mut buf).await?;
         drop(stream);
 
         if len < 1 {
-            if let Some(ref public_key) = user_public_key {
-                user_leave(public_key).await;
-            }
-            break Ok(());
+            return Ok(None);
         }
 
-        if let Ok(req_json) = frame::get_text(&buf[..len]) {
-            if req_json.len() < 1 {
-                println!("[error] invalid request from {ws_id}");
-                yield_now().await;
+        state.metrics.bytes_in(len);
+
+        loop {
+            let request = match reader.next(&mut buf) {
+                Ok(Some(message::Message::Text(text))) => text.into_bytes(),
+                Ok(Some(message::Message::Binary(data))) => data,
+                Ok(Some(message::Message::Ping(data))) => {
+                    outbox.send(Outbound::Frame(frame::OPCODE_PONG, data));
+                    continue;
+                }
+                Ok(Some(message::Message::Close(_))) => {
+                    return Ok(Some(frame::CLOSE_NORMAL));
+                }
+                Ok(Some(_)) => continue,
+                Ok(None) => break,
+                Err(err) => {
+                    warn!(?err, "bad frame, closing");
+                    return Ok(Some(err.close_code()));
+                }
+            };
+
+            if request.is_empty() {
+                warn!("empty request");
                 continue;
             }
 
-            let req = match serde_json::from_str(&req_json) {
+            let req: Payload = match outbox.protocol().decode(&request) {
                 Ok(j) => j,
-                Err(_) => {
-                    println!("[error] invalid JSON from {ws_id}");
-                    println!("[error] json = {}", req_json);
-                    yield_now().await;
+                Err(err) => {
+                    let request = String::from_utf8_lossy(&request);
+                    warn!(%err, request = %state.redacted(&request), "invalid request");
                     continue;
                 }
             };
 
-            let shared_stream = shared_stream.clone();
+            let kind = req.kind();
+            let started = Instant::now();
 
             match req {
-                Payload::First { public_key, name } => {
-                    user_public_key = Some(public_key.clone());
-                    let pk = public_key.clone();
-                    tokio::spawn(async move {
-                        user_join(
-                            pk.as_str(),
-                            name.as_str(),
-                            public_key.as_str(),
-                            shared_stream.clone(),
-                        )
-                        .await;
-                        dispatch_all_keys(
-                            public_key.as_str(),
-                            shared_stream.clone(),
-                        )
-                        .await;
+                Payload::First {
+                    public_key,
+                    name,
+                    signature,
+                    device_id,
+                    device_key,
+                } => {
+                    if login.is_some() {
+                        warn!("repeated first, ignoring");
+                        continue;
+                    }
+
+                    let signed = format!("rschat:first:{nonce}:{public_key}");
+                    if !signature::verify(&public_key, &signed, &signature) {
+                        warn!("first with a bad signature, refusing");
+                        outbox.send_payload(&Payload::Error {
+                            request: kind.to_string(),
+                            message: "bad signature".to_string(),
+                        });
+                        continue;
+                    }
+
+                    let device_id: String = device_id
+                        .unwrap_or_else(|| ws_id.to_string())
+                        .chars()
+                        .take(MAX_DEVICE_ID_CHARS)
+                        .collect();
+
+                    Span::current().record("user", key_prefix(&public_key));
+                    info!(name = %state.redacted(&name), %device_id, "user joined");
+
+                    *login = Some(Login {
+                        public_key: public_key.clone(),
+                        device_id: device_id.clone(),
                     });
+                    let is_new = user_join(
+                        state,
+                        &public_key,
+                        &name,
+                        &device_id,
+                        device_key,
+                        outbox.clone(),
+                    )
+                    .await;
+                    send_session_token(state, &public_key, &device_id).await;
+                    dispatch_all_keys(state, &public_key, &device_id, is_new)
+                        .await;
+                    if is_new {
+                        state.hooks.user_joined(&public_key, &name);
+                    }
+                    state.metrics.handled(kind, started.elapsed());
+                }
+                Payload::Resume {
+                    resume_token,
+                    last_seq,
+                } => {
+                    if login.is_some() {
+                        warn!("resume after login, ignoring");
+                        continue;
+                    }
+
+                    match resume(state, &resume_token, last_seq, outbox).await {
+                        Some(resumed) => {
+                            Span::current().record(
+                                "user",
+                                key_prefix(&resumed.public_key),
+                            );
+                            info!(device_id = %resumed.device_id, "user resumed");
+                            *login = Some(resumed);
+                        }
+                        None => outbox.send_payload(&Payload::Error {
+                            request: kind.to_string(),
+                            message: "unknown resume token".to_string(),
+                        }),
+                    }
+                    state.metrics.handled(kind, started.elapsed());
                 }
                 Payload::SendMessage {
                     recipient,
+                    device_id,
                     payload,
                     group_id,
+                    sent_at,
+                    message_id,
                 } => {
-                    if let Some(ref sender_pk) = user_public_key {
-                        let sender = sender_pk.clone();
-                        tokio::spawn(async move {
+                    if let Some(ref login) = *login {
+                        let envelope = match message_id {
+                            Some(id) => envelope_with_id(id, sent_at),
+                            None => new_envelope(sent_at),
+                        };
+
+                        if authors::record(
+                            state,
+                            &envelope.message_id,
+                            &login.public_key,
+                        )
+                        .await
+                        {
                             relay_message(
-                                sender.as_str(),
-                                recipient.as_str(),
-                                payload.as_str(),
+                                state,
+                                &login.public_key,
+                                &recipient,
+                                device_id.as_deref(),
+                                &payload,
+                                group_id,
+                                envelope.clone(),
+                            )
+                            .await;
+                            outbox.send_payload(&Payload::MessageSent {
+                                recipient,
+                                envelope,
+                            });
+                        } else {
+                            outbox.send_payload(&Payload::Error {
+                                request: kind.to_string(),
+                                message: "invalid or taken message id"
+                                    .to_string(),
+                            });
+                        }
+                        state.metrics.handled(kind, started.elapsed());
+                    }
+                }
+                Payload::EditMessage {
+                    recipient,
+                    device_id,
+                    group_id,
+                    target_id,
+                    payload,
+                } => {
+                    if let Some(ref login) = *login {
+                        if authors::sent(state, &target_id, &login.public_key)
+                            .await
+                        {
+                            let msg = Payload::MessageEdited {
+                                sender: login.public_key.clone(),
+                                group_id,
+                                target_id,
+                                payload,
+                                envelope: new_envelope(None),
+                            };
+                            relay(
+                                state,
+                                &login.public_key,
+                                &recipient,
+                                device_id.as_deref(),
+                                &msg,
+                            )
+                            .await;
+                        } else {
+                            outbox.send_payload(&not_sender(kind));
+                        }
+                        state.metrics.handled(kind, started.elapsed());
+                    }
+                }
+                Payload::DeleteMessage {
+                    recipient,
+                    device_id,
+                    group_id,
+                    target_id,
+                } => {
+                    if let Some(ref login) = *login {
+                        if authors::sent(state, &target_id, &login.public_key)
+                            .await
+                        {
+                            let msg = Payload::MessageDeleted {
+                                sender: login.public_key.clone(),
                                 group_id,
+                                target_id,
+                                envelope: new_envelope(None),
+                            };
+                            relay(
+                                state,
+                                &login.public_key,
+                                &recipient,
+                                device_id.as_deref(),
+                                &msg,
                             )
                             .await;
+                        } else {
+                            outbox.send_payload(&not_sender(kind));
+                        }
+                        state.metrics.handled(kind, started.elapsed());
+                    }
+                }
+                Payload::React {
+                    recipient,
+                    device_id,
+                    group_id,
+                    target_id,
+                    payload,
+                } => {
+                    if let Some(ref login) = *login {
+                        let msg = Payload::Reaction {
+                            sender: login.public_key.clone(),
+                            group_id,
+                            target_id,
+                            payload,
+                            envelope: new_envelope(None),
+                        };
+                        relay(
+                            state,
+                            &login.public_key,
+                            &recipient,
+                            device_id.as_deref(),
+                            &msg,
+                        )
+                        .await;
+                        state.metrics.handled(kind, started.elapsed());
+                    }
+                }
+                Payload::SetPresence { status, text } => {
+                    if let Some(ref login) = *login {
+                        presence::set_presence(
+                            state,
+                            &login.public_key,
+                            status,
+                            text,
+                        )
+                        .await;
+                        state.metrics.handled(kind, started.elapsed());
+                    }
+                }
+                Payload::Typing { recipient, typing } => {
+                    if let Some(ref login) = *login {
+                        presence::typing(
+                            state,
+                            &login.public_key,
+                            recipient,
+                            typing,
+                        )
+                        .await;
+                        state.metrics.handled(kind, started.elapsed());
+                    }
+                }
+                Payload::ClaimHandle {
+                    handle,
+                    timestamp,
+                    signature,
+                } => {
+                    if let Some(ref login) = *login {
+                        let key = &login.public_key;
+                        let result = handles::claim(
+                            state, key, &handle, timestamp, &signature,
+                        )
+                        .await
+                        .map(Some);
+                        handle_changed(state, key, kind, result, outbox).await;
+                        state.metrics.handled(kind, started.elapsed());
+                    }
+                }
+                Payload::RenameHandle {
+                    handle,
+                    timestamp,
+                    signature,
+                } => {
+                    if let Some(ref login) = *login {
+                        let key = &login.public_key;
+                        let result = handles::rename(
+                            state, key, &handle, timestamp, &signature,
+                        )
+                        .await
+                        .map(Some);
+                        handle_changed(state, key, kind, result, outbox).await;
+                        state.metrics.handled(kind, started.elapsed());
+                    }
+                }
+                Payload::ReleaseHandle {
+                    timestamp,
+                    signature,
+                } => {
+                    if let Some(ref login) = *login {
+                        let key = &login.public_key;
+                        let result =
+                            handles::release(state, key, timestamp, &signature)
+                                .await
+                                .map(|_| None);
+                        handle_changed(state, key, kind, result, outbox).await;
+                        state.metrics.handled(kind, started.elapsed());
+                    }
+                }
+                Payload::SetBlocked { user_id, blocked } => {
+                    if let Some(ref login) = *login {
+                        match blocklist::set_blocked(
+                            state,
+                            &login.public_key,
+                            &user_id,
+                            blocked,
+                        )
+                        .await
+                        {
+                            Ok(lists) => {
+                                lists_changed(
+                                    state,
+                                    &login.public_key,
+                                    &user_id,
+                                    lists,
+                                )
+                                .await;
+                                if blocked {
+                                    sealed::revoke(state, &login.public_key)
+                                        .await;
+                                }
+                            }
+                            Err(err) => outbox.send_payload(&Payload::Error {
+                                request: kind.to_string(),
+                                message: err.to_string(),
+                            }),
+                        }
+                        state.metrics.handled(kind, started.elapsed());
+                    }
+                }
+                Payload::SetMuted { user_id, muted } => {
+                    if let Some(ref login) = *login {
+                        match blocklist::set_muted(
+                            state,
+                            &login.public_key,
+                            &user_id,
+                            muted,
+                        )
+                        .await
+                        {
+                            Ok(lists) => {
+                                lists_changed(
+                                    state,
+                                    &login.public_key,
+                                    &user_id,
+                                    lists,
+                                )
+                                .await
+                            }
+                            Err(err) => outbox.send_payload(&Payload::Error {
+                                request: kind.to_string(),
+                                message: err.to_string(),
+                            }),
+                        }
+                        state.metrics.handled(kind, started.elapsed());
+                    }
+                }
+                Payload::ContactRequest { user_id, .. } => {
+                    if let Some(ref login) = *login {
+                        if let Err(err) = contacts_changed(
+                            state,
+                            Change::Request,
+                            &login.public_key,
+                            &user_id,
+                        )
+                        .await
+                        {
+                            outbox.send_payload(&Payload::Error {
+                                request: kind.to_string(),
+                                message: err.to_string(),
+                            });
+                        }
+                        state.metrics.handled(kind, started.elapsed());
+                    }
+                }
+                Payload::ContactAccept { user_id } => {
+                    if let Some(ref login) = *login {
+                        if let Err(err) = contacts_changed(
+                            state,
+                            Change::Accept,
+                            &login.public_key,
+                            &user_id,
+                        )
+                        .await
+                        {
+                            outbox.send_payload(&Payload::Error {
+                                request: kind.to_string(),
+                                message: err.to_string(),
+                            });
+                        }
+                        state.metrics.handled(kind, started.elapsed());
+                    }
+                }
+                Payload::ContactRemove { user_id } => {
+                    if let Some(ref login) = *login {
+                        if let Err(err) = contacts_changed(
+                            state,
+                            Change::Remove,
+                            &login.public_key,
+                            &user_id,
+                        )
+                        .await
+                        {
+                            outbox.send_payload(&Payload::Error {
+                                request: kind.to_string(),
+                                message: err.to_string(),
+                            });
+                        }
+                        state.metrics.handled(kind, started.elapsed());
+                    }
+                }
+                Payload::SetAccessKey { access_key_hash } => {
+                    if let Some(ref login) = *login {
+                        sealed::set_access_key(
+                            state,
+                            &login.public_key,
+                            access_key_hash,
+                        )
+                        .await;
+                        state.metrics.handled(kind, started.elapsed());
+                    }
+                }
+                Payload::ShareAccessKey { recipient, payload } => {
+                    if let Some(ref login) = *login {
+                        share_access_key(
+                            state,
+                            &login.public_key,
+                            &recipient,
+                            payload,
+                        )
+                        .await;
+                        state.metrics.handled(kind, started.elapsed());
+                    }
+                }
+                Payload::SendSealed {
+                    recipient,
+                    device_id,
+                    delivery_token,
+                    payload,
+                    message_id,
+                } => {
+                    if login.is_some() {
+                        // It would tell the server who the sender is.
+                        outbox.send_payload(&Payload::Error {
+                            request: kind.to_string(),
+                            message: "send_sealed is refused after first"
+                                .to_string(),
+                        });
+                        continue;
+                    }
+                    let sent = relay_sealed(
+                        state,
+                        &recipient,
+                        device_id.as_deref(),
+                        &delivery_token,
+                        payload,
+                        message_id,
+                    )
+                    .await;
+                    if let Some(envelope) = sent {
+                        outbox.send_payload(&Payload::MessageSent {
+                            recipient,
+                            envelope,
                         });
                     }
+                    state.metrics.handled(kind, started.elapsed());
+                }
+                Payload::UploadHistory { chunk_id, data } => {
+                    if let Some(ref login) = *login {
+                        let uploaded = if history::allow_upload(
+                            state,
+                            &login.public_key,
+                            Instant::now(),
+                        )
+                        .await
+                        {
+                            history::upload(
+                                state,
+                                &login.public_key,
+                                chunk_id,
+                                data,
+                            )
+                            .await
+                        } else {
+                            Err(HistoryError::RateLimited)
+                        };
+                        if let Err(err) = uploaded {
+                            outbox.send_payload(&Payload::Error {
+                                request: kind.to_string(),
+                                message: err.to_string(),
+                            });
+                        }
+                        state.metrics.handled(kind, started.elapsed());
+                    }
+                }
+                Payload::FetchHistory => {
+                    if let Some(ref login) = *login {
+                        let chunks =
+                            history::fetch(state, &login.public_key).await;
+                        outbox.send_payload(&Payload::History { chunks });
+                        state.metrics.handled(kind, started.elapsed());
+                    }
+                }
+                Payload::LookupHandle { handle } => {
+                    let requester = login.as_ref().map(|l| &*l.public_key);
+                    outbox.send_payload(
+                        &lookup_handle(state, &handle, requester).await,
+                    );
+                    state.metrics.handled(kind, started.elapsed());
                 }
                 _ => {}
             }
@@ -163,90 +843,477 @@ async fn client_request_handler(
     }
 }
 
+/// Tell everyone the new handle of `public_key`, or tell the requester why
+/// it could not be changed.
+async fn handle_changed(
+    state: &AppState,
+    public_key: &str,
+    request: &str,
+    result: Result<Option<String>, HandleError>,
+    outbox: &Outbox,
+) {
+    let handle = match result {
+        Ok(handle) => handle,
+        Err(err) => {
+            outbox.send_payload(&Payload::Error {
+                request: request.to_string(),
+                message: err.to_string(),
+            });
+            return;
+        }
+    };
+
+    info!(?handle, "handle changed");
+
+    let mut users = state.users.lock().await;
+
+    if let Some(user) = users.get_mut(public_key) {
+        user.handle = handle.clone();
+    }
+
+    let Some(owner) = users.get(public_key) else {
+        return;
+    };
+
+    let msg = Payload::Handle {
+        user_id: public_key.to_string(),
+        handle,
+    };
+    for user in users.values() {
+        if user.can_see(owner) {
+            user.send(&msg);
+        }
+    }
+}
+
+fn lists_payload(user: &OnlineUser) -> Payload {
+    Payload::Blocklist {
+        blocked: user.lists.blocked.iter().cloned().collect(),
+        muted: user.lists.muted.iter().cloned().collect(),
+    }
+}
+
+/// Apply new lists of `public_key` after it (un)blocked or (un)muted
+/// `other`, and make the two appear to or vanish from each other.
+async fn lists_changed(
+    state: &AppState,
+    public_key: &str,
+    other: &str,
+    lists: Lists,
+) {
+    let mut users = state.users.lock().await;
+    let could_see = visible(&users, public_key, other);
+
+    let Some(user) = users.get_mut(public_key) else {
+        return;
+    };
+    user.lists = lists;
+    user.send(&lists_payload(user));
+
+    announce_visibility(&users, public_key, other, could_see);
+}
+
+fn contacts_payload(user: &OnlineUser) -> Payload {
+    let c = &user.contacts;
+    Payload::ContactList {
+        contacts: c.contacts.iter().cloned().collect(),
+        incoming: c.incoming.keys().cloned().collect(),
+        outgoing: c.outgoing.keys().cloned().collect(),
+    }
+}
+
+/// Apply a contact request, acceptance or removal by `public_key`, and
+/// tell both sides.
+async fn contacts_changed(
+    state: &AppState,
+    change: Change,
+    public_key: &str,
+    other: &str,
+) -> Result<(), ContactError> {
+    // Requests to someone who blocked you go nowhere.
+    if matches!(change, Change::Request)
+        && blocklist::load(state, other).await.blocks(public_key)
+    {
+        return Ok(());
+    }
+
+    let Some((mine, theirs)) =
+        contacts::update(state, change, public_key, other).await?
+    else {
+        return Ok(());
+    };
+
+    let mut users = state.users.lock().await;
+    let could_see = visible(&users, public_key, other);
+    let requested = theirs.incoming.contains_key(public_key);
+
+    let name = users.get_mut(public_key).map(|user| {
+        user.contacts = mine;
+        user.send(&contacts_payload(user));
+        user.name.clone()
+    });
+
+    if let Some(user) = users.get_mut(other) {
+        user.contacts = theirs;
+        user.send(&contacts_payload(user));
+
+        if requested {
+            user.send(&Payload::ContactRequest {
+                user_id: public_key.to_string(),
+                name,
+            });
+        }
+    }
+
+    announce_visibility(&users, public_key, other, could_see);
+    Ok(())
+}
+
+fn visible(users: &HashMap<String, OnlineUser>, a: &str, b: &str) -> bool {
+    match (users.get(a), users.get(b)) {
+        (Some(a), Some(b)) => a.can_see(b),
+        _ => false,
+    }
+}
+
+/// Make `a` and `b` appear to or vanish from each other if whether they
+/// can see each other changed.
+fn announce_visibility(
+    users: &HashMap<String, OnlineUser>,
+    a: &str,
+    b: &str,
+    could_see: bool,
+) {
+    let (Some(user), Some(other_user)) = (users.get(a), users.get(b)) else {
+        return;
+    };
+    let (public_key, other) = (a, b);
+
+    match (could_see, user.can_see(other_user)) {
+        (true, false) => {
+            user.send(&Payload::UserLeft {
+                user_id: other.to_string(),
+            });
+            other_user.send(&Payload::UserLeft {
+                user_id: public_key.to_string(),
+            });
+        }
+        (false, true) => {
+            user.send(&Payload::NewUser {
+                user: other_user.profile(),
+            });
+            other_user.send(&Payload::NewUser {
+                user: user.profile(),
+            });
+        }
+        _ => {}
+    }
+}
+
+/// Owner of `handle`, with its connected devices if `requester` may see
+/// the owner.
+async fn lookup_handle(
+    state: &AppState,
+    handle: &str,
+    requester: Option<&str>,
+) -> Payload {
+    let public_key = handles::resolve(state, handle).await;
+    let devices = match (&public_key, requester) {
+        (Some(key), Some(requester)) => {
+            let users = state.users.lock().await;
+            match (users.get(key), users.get(requester)) {
+                (Some(owner), Some(requester))
+                    if owner.id == requester.id || requester.can_see(owner) =>
+                {
+                    owner.devices()
+                }
+                _ => Vec::new(),
+            }
+        }
+        _ => Vec::new(),
+    };
+
+    Payload::HandleInfo {
+        handle: handle.to_ascii_lowercase(),
+        public_key,
+        devices,
+    }
+}
+
+async fn send_session_token(
+    state: &AppState,
+    public_key: &str,
+    device_id: &str,
+) {
+    let users = state.users.lock().await;
+
+    if let Some(user) = users.get(public_key)
+        && let Some(session) = user.sessions.get(device_id)
+    {
+        session.outbox.send_payload(&Payload::Session {
+            device_id: device_id.to_string(),
+            resume_token: session.resume_token.clone(),
+        });
+    }
+}
+
+/// Reattach the session holding `token` to `outbox`, then send it what it
+/// missed and everyone online.
+async fn resume(
+    state: &AppState,
+    token: &str,
+    last_seq: u64,
+    outbox: &Outbox,
+) -> Option<Login> {
+    let mut users = state.users.lock().await;
+    let mut tokens = state.resume_tokens.lock().await;
+
+    let login = tokens.get(token)?.clone();
+    let session = users
+        .get_mut(&login.public_key)?
+        .sessions
+        .get_mut(&login.device_id)?;
+    // Checked in constant time, not only by the map lookup.
+    if !same_secret(&session.resume_token, token) {
+        return None;
+    }
+
+    let stale = std::mem::replace(&mut session.outbox, outbox.clone());
+    outbox.resume_from(&stale, last_seq);
+    stale.close(frame::CLOSE_NORMAL);
+
+    tokens.remove(token);
+    session.resume_token = new_token();
+    tokens.insert(session.resume_token.clone(), login.clone());
+    drop(tokens);
+
+    outbox.send_payload(&Payload::Session {
+        device_id: login.device_id.clone(),
+        resume_token: session.resume_token.clone(),
+    });
+
+    let user = users.get(&login.public_key)?;
+    for other_user in users.values() {
+        if other_user.id != login.public_key && user.can_see(other_user) {
+            outbox.send_payload(&Payload::NewUser {
+                user: other_user.profile(),
+            });
+        }
+    }
+
+    Some(login)
+}
+
+/// Introduce the newly joined device of `public_key` to everyone online,
+/// and everyone online to it.
 async fn dispatch_all_keys(
+    state: &AppState,
     public_key: &str,
-    shared_stream: Arc<Mutex<TcpStream>>,
+    device_id: &str,
+    is_new: bool,
 ) {
-    let users = USERS.lock().await;
+    let users = state.users.lock().await;
     let user = match users.get(public_key) {
         Some(u) => u,
         None => return,
     };
 
-    let user_data = Payload::NewUser { user: user.clone() };
-    let user_json = match serde_json::to_string(&user_data) {
-        Ok(j) => j,
-        Err(_) => return,
+    let announce = if is_new {
+        Payload::NewUser {
+            user: user.profile(),
+        }
+    } else {
+        Payload::Devices {
+            user_id: public_key.to_string(),
+            devices: user.devices(),
+        }
     };
 
-    let mut buf = BytesMut::with_capacity(4096);
-    buf.reserve(1024);
-
-    let mut stream = shared_stream.lock().await;
+    user.send_to_device(device_id, &lists_payload(user));
+    user.send_to_device(device_id, &contacts_payload(user));
 
-    for (other_public_key, user) in users.iter() {
-        if other_public_key == public_key {
+    for (other_public_key, other_user) in users.iter() {
+        if other_public_key == public_key || !user.can_see(other_user) {
             continue;
         }
 
-        let mut other_user_stream = user.shared_stream.lock().await;
+        other_user.send(&announce);
 
-        buf.clear();
-        let len = frame::set_text(&mut buf, &user_json);
-        let _ = other_user_stream.write_all(&buf[..len]).await;
-
-        drop(other_user_stream);
-
-        let other_user_data = Payload::NewUser { user: user.clone() };
-        let other_user_json = match serde_json::to_string(&other_user_data) {
-            Ok(j) => j,
-            Err(_) => continue,
+        let other_user_data = Payload::NewUser {
+            user: other_user.profile(),
         };
-
-        buf.clear();
-        let len = frame::set_text(&mut buf, &other_user_json);
-        let _ = stream.write_all(&buf[..len]).await;
+        user.send_to_device(device_id, &other_user_data);
     }
 }
 
 async fn relay_message(
+    state: &AppState,
     sender: &str,
     recipient: &str,
+    device_id: Option<&str>,
     payload: &str,
     group_id: Option<String>,
+    envelope: Envelope,
 ) {
-    let users = USERS.lock().await;
+    debug!(
+        recipient = %key_prefix(recipient),
+        message_id = %envelope.message_id,
+        payload = %state.redacted(payload),
+        "relay message"
+    );
+
+    let msg = Payload::RelayMessage {
+        sender: sender.to_string(),
+        payload: payload.to_string(),
+        group_id,
+        envelope,
+    };
 
-    if let Some(user) = users.get(recipient) {
-        let mut stream = user.shared_stream.lock().await;
+    if relay(state, sender, recipient, device_id, &msg).await {
+        state.hooks.message_relayed(sender, recipient);
+    }
+}
 
-        let mut buf = BytesMut::with_capacity(4096);
-        buf.reserve(1024);
+fn not_sender(kind: &str) -> Payload {
+    Payload::Error {
+        request: kind.to_string(),
+        message: "not the sender of this message".to_string(),
+    }
+}
 
-        let msg = Payload::RelayMessage {
-            sender: sender.to_string(),
-            payload: payload.to_string(),
-            group_id,
-        };
+/// Deliver `msg` from `sender` to every device of `recipient`, or only
+/// `device_id`, unless one of them can't see the other. Returns whether it
+/// was delivered.
+async fn relay(
+    state: &AppState,
+    sender: &str,
+    recipient: &str,
+    device_id: Option<&str>,
+    msg: &Payload,
+) -> bool {
+    let users = state.users.lock().await;
+
+    let Some(user) = users.get(recipient) else {
+        state.metrics.message_dropped();
+        return false;
+    };
+
+    if users.get(sender).is_some_and(|s| !s.can_see(user)) {
+        state.metrics.message_blocked();
+        return false;
+    }
+
+    let delivered = match device_id {
+        Some(device_id) => user.send_to_device(device_id, msg),
+        None => {
+            user.send(msg);
+            true
+        }
+    };
+
+    if delivered {
+        state.metrics.message_relayed();
+    } else {
+        state.metrics.message_dropped();
+    }
+    delivered
+}
+
+/// Deliver a sealed message if `token` is the recipient's access key,
+/// the only proof the sender gives. Returns the envelope unless the key
+/// was wrong.
+async fn relay_sealed(
+    state: &AppState,
+    recipient: &str,
+    device_id: Option<&str>,
+    token: &str,
+    payload: String,
+    message_id: Option<String>,
+) -> Option<Envelope> {
+    if !sealed::may_deliver(state, recipient, token).await {
+        state.metrics.message_blocked();
+        return None;
+    }
+
+    // Not recorded as anyone's: sealed messages can't be edited.
+    let envelope = match message_id {
+        Some(id)
+            if (1..=MAX_MESSAGE_ID_CHARS).contains(&id.chars().count()) =>
+        {
+            envelope_with_id(id, None)
+        }
+        _ => new_envelope(None),
+    };
 
-        if let Ok(json) = serde_json::to_string(&msg) {
-            let len = frame::set_text(&mut buf, &json);
-            let _ = stream.write_all(&buf[..len]).await;
+    let users = state.users.lock().await;
+    debug!(
+        recipient = %key_prefix(recipient),
+        message_id = %envelope.message_id,
+        payload = %state.redacted(&payload),
+        "relay sealed message"
+    );
+
+    let msg = Payload::SealedMessage {
+        payload,
+        envelope: envelope.clone(),
+    };
+
+    let delivered = match (users.get(recipient), device_id) {
+        (Some(user), Some(device_id)) => user.send_to_device(device_id, &msg),
+        (Some(user), None) => {
+            user.send(&msg);
+            true
         }
+        (None, _) => false,
+    };
+
+    if delivered {
+        state.metrics.message_relayed();
+    } else {
+        state.metrics.message_dropped();
+    }
+    Some(envelope)
+}
+
+/// Pass an encrypted access key from `sender` on to `recipient`, if they
+/// may message each other.
+async fn share_access_key(
+    state: &AppState,
+    sender: &str,
+    recipient: &str,
+    payload: String,
+) {
+    let users = state.users.lock().await;
+    if !visible(&users, sender, recipient) {
+        return;
+    }
+
+    if let Some(user) = users.get(recipient) {
+        user.send(&Payload::AccessKey {
+            sender: sender.to_string(),
+            payload,
+        });
     }
 }
 
 async fn static_resource_handler(
+    state: &AppState,
     shared_stream: Arc<Mutex<TcpStream>>,
     filename: &str,
 ) -> io::Result<()> {
     let mut stream = shared_stream.lock().await;
 
-    let path = format!("./static/{}", filename);
+    // Relative, or joining would replace the static directory, and
+    // without `..` so it can't climb out of it.
+    let relative = Path::new(filename.trim_start_matches('/'));
+    let inside = relative
+        .components()
+        .all(|part| matches!(part, Component::Normal(_)));
+    let path = state.config.static_dir.join(relative);
 
     match fs::metadata(&path).await {
-        Ok(metadata) if metadata.is_file() => {
-            let mime_type = match path.rsplit('.').next() {
+        Ok(metadata) if inside && metadata.is_file() => {
+            let mime_type = match path.extension().and_then(|e| e.to_str()) {
                 Some("html") => "text/html",
                 Some("css") => "text/css",
                 Some("js") => "application/javascript",
@@ -268,7 +1335,11 @@ async fn static_resource_handler(
                 }
             };
 
-            let body = fs::read(&path).await?;
+            let mut body = fs::read(&path).await?;
+            // `/` and `/index.html` are the same page.
+            if relative == Path::new("index.html") {
+                body = inject_csrf_token(state, &body);
+            }
             let header = format!(
                 "HTTP/1.1 200 OK\r\n\
                  Content-Type: {}; charset=utf-8\r\n\
@@ -303,64 +1374,150 @@ async fn static_resource_handler(
     Ok(())
 }
 
+/// Fill the `{{csrf_token}}` placeholder of `index.html`. Other sites
+/// can't read the page, so only it can present the token on upgrade.
+fn inject_csrf_token(state: &AppState, html: &[u8]) -> Vec<u8> {
+    let token = if state.config.csrf {
+        state.csrf_token.as_str()
+    } else {
+        ""
+    };
+    String::from_utf8_lossy(html)
+        .replace("{{csrf_token}}", token)
+        .into_bytes()
+}
+
 async fn ws_handler(
+    state: &Arc<AppState>,
     shared_stream: Arc<Mutex<TcpStream>>,
     http_header: HttpHeader,
     buf: BytesMut,
 ) -> io::Result<()> {
     let mut stream = shared_stream.lock().await;
 
-    if let Some(val) = http_header.table.get("Upgrade") {
-        if val != "websocket" {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                ERR_WS_CONNECTION,
-            ));
+    let checked = handshake::validate(&http_header).and_then(|accept| {
+        handshake::check_origin(&http_header, &state.config.allowed_origins)?;
+        if state.config.csrf {
+            handshake::check_csrf(&http_header, &state.csrf_token)?;
         }
-    }
 
-    if let Some(val) = http_header.table.get("Sec-WebSocket-Version") {
-        if val != "13" {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                ERR_WS_VERSION,
-            ));
+        // Clients that name no subprotocol predate them and speak v1.
+        let protocol = match http_header.get("Sec-WebSocket-Protocol") {
+            Some(offered) => Some(
+                Protocol::negotiate(offered)
+                    .ok_or(HandshakeError::BadProtocol)?,
+            ),
+            None => None,
+        };
+        Ok((accept, protocol))
+    });
+
+    let (accept, protocol) = match checked {
+        Ok(accept) => accept,
+        Err(err) => {
+            state.metrics.handshake(false);
+            warn!(reason = %err, "handshake rejected");
+            stream.write_all(err.response().as_bytes()).await?;
+            return stream.flush().await;
         }
-    }
+    };
 
-    if let Some(key) = http_header.table.get("Sec-WebSocket-Key") {
-        let combined = format!("{}{}", key, WS_GUID);
+    let deflate = http_header
+        .get("Sec-WebSocket-Extensions")
+        .filter(|_| state.config.deflate)
+        .and_then(deflate::negotiate);
+    let extensions = deflate
+        .as_ref()
+        .map(|params| {
+            format!("Sec-WebSocket-Extensions: {}\r\n", params.response())
+        })
+        .unwrap_or_default();
+    let subprotocol = protocol
+        .map(|p| format!("Sec-WebSocket-Protocol: {}\r\n", p.name()))
+        .unwrap_or_default();
+
+    let response = format!(
+        "HTTP/1.1 101 Switching Protocols\r\n\
+         Upgrade: websocket\r\n\
+         Connection: Upgrade\r\n\
+         Sec-WebSocket-Accept: {}\r\n\
+         {}{}\r\n",
+        accept, subprotocol, extensions
+    );
+    stream.write_all(response.as_bytes()).await?;
+    state.metrics.handshake(true);
+
+    drop(response);
+    drop(http_header);
+    drop(stream);
 
-        let mut hasher = Sha1::new();
-        hasher.update(combined.as_bytes());
-        let hashed = hasher.finalize();
-        let user_id = B64.encode(hashed);
+    client_request_handler(
+        state,
+        shared_stream.clone(),
+        buf,
+        accept,
+        protocol.unwrap_or(Protocol::LEGACY),
+        deflate,
+    )
+    .await
+}
 
-        let response = format!(
-            "HTTP/1.1 101 Switching Protocols\r\n\
-             Upgrade: websocket\r\n\
-             Connection: Upgrade\r\n\
-             Sec-WebSocket-Accept: {}\r\n\r\n",
-            user_id
-        );
-        stream.write_all(response.as_bytes()).await?;
+async fn handle_lookup_handler(
+    state: &AppState,
+    shared_stream: Arc<Mutex<TcpStream>>,
+    handle: &str,
+) -> io::Result<()> {
+    // Anyone may ask, so presence stays out of the answer.
+    let (status, body) = match handles::resolve(state, handle).await {
+        Some(public_key) => (
+            "200 OK",
+            serde_json::json!({
+                "handle": handle.to_ascii_lowercase(),
+                "public_key": public_key,
+            })
+            .to_string(),
+        ),
+        None => ("404 Not Found", String::from("{}")),
+    };
 
-        drop(response);
-        drop(http_header);
-        drop(stream);
+    let header = format!(
+        "HTTP/1.1 {}\r\n\
+         Content-Type: application/json\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\
+         \r\n",
+        status,
+        body.len()
+    );
 
-        client_request_handler(
-            shared_stream.clone(),
-            buf.into(),
-            user_id.into(),
-        )
-        .await?;
-    }
+    let mut stream = shared_stream.lock().await;
+    stream.write_all(header.as_bytes()).await?;
+    stream.write_all(body.as_bytes()).await?;
+    stream.flush().await
+}
 
-    Ok(())
+async fn metrics_handler(
+    state: &AppState,
+    shared_stream: Arc<Mutex<TcpStream>>,
+) -> io::Result<()> {
+    let body = state.metrics.render(&*state.users.lock().await);
+    let header = format!(
+        "HTTP/1.1 200 OK\r\n\
+         Content-Type: text/plain; version=0.0.4; charset=utf-8\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\
+         \r\n",
+        body.len()
+    );
+
+    let mut stream = shared_stream.lock().await;
+    stream.write_all(header.as_bytes()).await?;
+    stream.write_all(body.as_bytes()).await?;
+    stream.flush().await
 }
 
 pub async fn request_handler(
+    state: &Arc<AppState>,
     shared_stream: Arc<Mutex<TcpStream>>,
 ) -> io::Result<()> {
     let mut stream = shared_stream.lock().await;
@@ -380,13 +1537,22 @@ pub async fn request_handler(
 
     match (http_header.verb.clone(), http_header.path.as_str()) {
         (HttpVerb::Get, "/") => {
-            static_resource_handler(shared_stream.clone(), "index.html").await
+            static_resource_handler(state, shared_stream.clone(), "index.html")
+                .await
         }
         (HttpVerb::Get, "/ws") => {
-            ws_handler(shared_stream.clone(), http_header, buf).await
+            ws_handler(state, shared_stream.clone(), http_header, buf).await
+        }
+        (HttpVerb::Get, "/metrics") => {
+            metrics_handler(state, shared_stream.clone()).await
+        }
+        (HttpVerb::Get, path) if path.starts_with("/handles/") => {
+            let handle = &path["/handles/".len()..];
+            handle_lookup_handler(state, shared_stream.clone(), handle).await
         }
         _ => {
             static_resource_handler(
+                state,
                 shared_stream.clone(),
                 http_header.path.as_str(),
             )
@@ -395,41 +1561,202 @@ pub async fn request_handler(
     }
 }
 
+/// Add a session for `device_id`. Returns true if the user was not
+/// connected from any other device.
 async fn user_join(
+    state: &AppState,
     public_key: &str,
     name: &str,
-    public_key_copy: &str,
-    shared_stream: Arc<Mutex<TcpStream>>,
-) {
-    let mut users = USERS.lock().await;
-    let new_user = User {
+    device_id: &str,
+    device_key: Option<String>,
+    outbox: Outbox,
+) -> bool {
+    let handle = handles::handle_of(state, public_key).await;
+    let lists = blocklist::load(state, public_key).await;
+    let contacts = contacts::load(state, public_key).await;
+    let mut users = state.users.lock().await;
+    let session = Session {
+        device_key,
+        outbox,
+        resume_token: new_token(),
+    };
+
+    let mut tokens = state.resume_tokens.lock().await;
+    tokens.insert(
+        session.resume_token.clone(),
+        Login {
+            public_key: public_key.into(),
+            device_id: device_id.into(),
+        },
+    );
+
+    if let Some(user) = users.get_mut(public_key) {
+        user.name = name.into();
+
+        if let Some(stale) = user.sessions.insert(device_id.into(), session) {
+            // Same device connected again, with a signed `first` proving
+            // the key: drop the old connection, telling it why.
+            warn!(%device_id, "device connected again, closing old connection");
+            tokens.remove(&stale.resume_token);
+            stale.outbox.send_payload(&Payload::Error {
+                request: "first".to_string(),
+                message: DEVICE_REPLACED.to_string(),
+            });
+            stale.outbox.close(frame::CLOSE_NORMAL);
+        }
+
+        return false;
+    }
+
+    let new_user = OnlineUser {
         id: public_key.into(),
         name: name.into(),
-        shared_stream: shared_stream.clone(),
-        public_key: Some(public_key_copy.into()),
+        status: PresenceStatus::default(),
+        status_text: None,
+        public_key: Some(public_key.into()),
+        handle,
+        roster: state.config.roster,
+        sessions: HashMap::from([(device_id.to_string(), session)]),
+        lists,
+        contacts,
     };
 
     users.insert(public_key.into(), new_user);
+    true
 }
 
-async fn user_leave(public_key: &str) {
-    let mut users = USERS.lock().await;
-    users.remove(public_key);
+/// Remove the session of `login`, unless another connection has taken it
+/// over since. Returns `Some(true)` if that was the user's last session.
+async fn remove_session(
+    state: &AppState,
+    users: &mut HashMap<String, OnlineUser>,
+    login: &Login,
+    outbox: &Outbox,
+) -> Option<bool> {
+    let user = users.get_mut(&login.public_key)?;
+
+    let owned = user
+        .sessions
+        .get(&login.device_id)
+        .is_some_and(|s| s.outbox.same_channel(outbox));
+    if !owned {
+        return None;
+    }
 
-    let mut buf = BytesMut::with_capacity(4096);
-    buf.reserve(1024);
+    if let Some(session) = user.sessions.remove(&login.device_id) {
+        state
+            .resume_tokens
+            .lock()
+            .await
+            .remove(&session.resume_token);
+    }
 
-    let msg = Payload::UserLeft {
-        user_id: public_key.to_string(),
+    if user.sessions.is_empty() {
+        users.remove(&login.public_key);
+        Some(true)
+    } else {
+        Some(false)
+    }
+}
+
+async fn user_leave(state: &AppState, login: &Login, outbox: &Outbox) {
+    let mut users = state.users.lock().await;
+
+    // Who may see the user, decided before it is removed.
+    let watchers: Vec<String> = match users.get(&login.public_key) {
+        Some(leaver) => users
+            .values()
+            .filter(|u| u.id != leaver.id && u.can_see(leaver))
+            .map(|u| u.id.clone())
+            .collect(),
+        None => return,
+    };
+
+    let msg = match remove_session(state, &mut users, login, outbox).await {
+        Some(true) => Payload::UserLeft {
+            user_id: login.public_key.clone(),
+        },
+        Some(false) => Payload::Devices {
+            user_id: login.public_key.clone(),
+            devices: users
+                .get(&login.public_key)
+                .map(|u| u.devices())
+                .unwrap_or_default(),
+        },
+        None => return,
     };
 
-    for (_, user) in users.iter() {
-        let mut stream = user.shared_stream.lock().await;
+    info!(device_id = %login.device_id, "user left");
 
-        if let Ok(json) = serde_json::to_string(&msg) {
-            buf.clear();
-            let len = frame::set_text(&mut buf, &json);
-            let _ = stream.write_all(&buf[..len]).await;
+    for public_key in watchers {
+        if let Some(user) = users.get(&public_key) {
+            user.send(&msg);
         }
     }
+
+    drop(users);
+
+    if matches!(msg, Payload::UserLeft { .. }) {
+        presence::clear_typing(state, &login.public_key).await;
+        state.hooks.user_left(&login.public_key);
+    }
+}
+
+#[cfg(test)]
+mod service_tests {
+    use std::collections::HashMap;
+
+    use rschat_protocol::PresenceStatus;
+
+    use crate::blocklist::Lists;
+    use crate::config::Roster;
+    use crate::contacts::Contacts;
+    use crate::service::{OnlineUser, same_secret};
+
+    fn user(id: &str, blocked: &[&str], muted: &[&str]) -> OnlineUser {
+        OnlineUser {
+            id: id.to_string(),
+            name: id.to_string(),
+            public_key: Some(id.to_string()),
+            handle: None,
+            status: PresenceStatus::default(),
+            status_text: None,
+            sessions: HashMap::new(),
+            lists: Lists {
+                blocked: blocked.iter().map(|k| k.to_string()).collect(),
+                muted: muted.iter().map(|k| k.to_string()).collect(),
+            },
+            contacts: Contacts::default(),
+            roster: Roster::default(),
+        }
+    }
+
+    #[test]
+    fn test_block_hides_both_ways() {
+        let a = user("A", &["B"], &[]);
+        let b = user("B", &[], &[]);
+        let c = user("C", &[], &[]);
+
+        assert!(!a.can_see(&b));
+        assert!(!b.can_see(&a));
+        assert!(a.can_see(&c) && c.can_see(&b));
+    }
+
+    #[test]
+    fn test_mute_only_silences_muter() {
+        let a = user("A", &[], &["B"]);
+        let b = user("B", &[], &[]);
+
+        assert!(a.can_see(&b));
+        assert!(!a.listens_to(&b));
+        assert!(b.listens_to(&a));
+    }
+
+    #[test]
+    fn test_same_secret() {
+        assert!(same_secret("abcd", "abcd"));
+        assert!(!same_secret("abcd", "abce"));
+        assert!(!same_secret("abcd", "abc"));
+        assert!(same_secret("", ""));
+    }
 }
@@ -0,0 +1,44 @@
+use rschat_crypto::{PublicKey, Signature};
+
+/// Check a hex compact ECDSA signature over the SHA-256 of `message`,
+/// as produced by `sign_message` in crypto-wasm.
+pub fn verify(
+    public_key_hex: &str,
+    message: &str,
+    signature_hex: &str,
+) -> bool {
+    let Ok(public_key) = PublicKey::from_hex(public_key_hex) else {
+        return false;
+    };
+    let Ok(signature) = Signature::from_hex(signature_hex) else {
+        return false;
+    };
+
+    public_key.verify(message.as_bytes(), &signature).is_ok()
+}
+
+#[cfg(test)]
+mod signature_tests {
+    use rschat_crypto::KeyPair;
+
+    use crate::signature::verify;
+
+    fn sign(message: &str) -> (String, String) {
+        let keys = KeyPair::from_secret_bytes(&[7; 32]).unwrap();
+
+        (
+            keys.public_key().to_hex(),
+            keys.sign(message.as_bytes()).to_hex(),
+        )
+    }
+
+    #[test]
+    fn test_verify() {
+        let (public_key, signature) = sign("rschat:claim_handle:alice");
+
+        assert!(verify(&public_key, "rschat:claim_handle:alice", &signature));
+        assert!(!verify(&public_key, "rschat:claim_handle:bob", &signature));
+        assert!(!verify(&public_key, "rschat:claim_handle:alice", "00"));
+        assert!(!verify("04zz", "rschat:claim_handle:alice", &signature));
+    }
+}
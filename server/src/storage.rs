@@ -0,0 +1,400 @@
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex as SyncMutex};
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use sha2::{Digest, Sha256};
+use tokio::sync::{Mutex, MutexGuard};
+use tracing::{error, warn};
+
+/// Where stores persist their state, as blobs named like `contacts` or
+/// `history/<sha256 of key>`. Calls may block; stores make them off the
+/// async runtime.
+pub trait Backend: Send + Sync {
+    /// The blob saved as `name`, if any.
+    fn load(&self, name: &str) -> io::Result<Option<Vec<u8>>>;
+
+    /// Replace the blob `name` with `bytes`.
+    fn save(&self, name: &str, bytes: &[u8]) -> io::Result<()>;
+
+    /// Names of the blobs saved under `dir`, without the `dir/` prefix.
+    fn list(&self, dir: &str) -> io::Result<Vec<String>>;
+}
+
+impl<B: Backend + ?Sized> Backend for Arc<B> {
+    fn load(&self, name: &str) -> io::Result<Option<Vec<u8>>> {
+        (**self).load(name)
+    }
+
+    fn save(&self, name: &str, bytes: &[u8]) -> io::Result<()> {
+        (**self).save(name, bytes)
+    }
+
+    fn list(&self, dir: &str) -> io::Result<Vec<String>> {
+        (**self).list(dir)
+    }
+}
+
+/// Blobs as `<dir>/<name>.json` files.
+pub struct FileBackend {
+    dir: PathBuf,
+}
+
+impl FileBackend {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        FileBackend { dir: dir.into() }
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.dir.join(format!("{name}.json"))
+    }
+}
+
+impl Backend for FileBackend {
+    fn load(&self, name: &str) -> io::Result<Option<Vec<u8>>> {
+        match std::fs::read(self.path(name)) {
+            Ok(bytes) => Ok(Some(bytes)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Written through a temporary file, so a crash never leaves a
+    /// half-written file behind.
+    fn save(&self, name: &str, bytes: &[u8]) -> io::Result<()> {
+        let path = self.path(name);
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let tmp = path.with_extension("json.tmp");
+        std::fs::write(&tmp, bytes)?;
+        std::fs::rename(&tmp, path)
+    }
+
+    fn list(&self, dir: &str) -> io::Result<Vec<String>> {
+        let files = match std::fs::read_dir(self.dir.join(dir)) {
+            Ok(files) => files,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                return Ok(Vec::new());
+            }
+            Err(err) => return Err(err),
+        };
+
+        Ok(files
+            .filter_map(|file| {
+                let name = file.ok()?.file_name().into_string().ok()?;
+                Some(name.strip_suffix(".json")?.to_string())
+            })
+            .collect())
+    }
+}
+
+/// Blobs in memory, e.g. to restart a server in tests without touching
+/// the disk. Share it between instances through an `Arc`.
+#[derive(Default)]
+pub struct MemoryBackend {
+    blobs: SyncMutex<HashMap<String, Vec<u8>>>,
+}
+
+impl MemoryBackend {
+    fn blobs(&self) -> std::sync::MutexGuard<'_, HashMap<String, Vec<u8>>> {
+        self.blobs.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Backend for MemoryBackend {
+    fn load(&self, name: &str) -> io::Result<Option<Vec<u8>>> {
+        Ok(self.blobs().get(name).cloned())
+    }
+
+    fn save(&self, name: &str, bytes: &[u8]) -> io::Result<()> {
+        self.blobs().insert(name.to_string(), bytes.to_vec());
+        Ok(())
+    }
+
+    fn list(&self, dir: &str) -> io::Result<Vec<String>> {
+        let prefix = format!("{dir}/");
+        Ok(self
+            .blobs()
+            .keys()
+            .filter_map(|name| name.strip_prefix(&prefix))
+            .map(str::to_string)
+            .collect())
+    }
+}
+
+/// Write `bytes` as `name` off the async runtime.
+async fn save(
+    backend: &Arc<dyn Backend>,
+    name: String,
+    bytes: Vec<u8>,
+) -> io::Result<()> {
+    let backend = backend.clone();
+    tokio::task::spawn_blocking(move || backend.save(&name, &bytes))
+        .await
+        .map_err(io::Error::other)?
+}
+
+/// Parse a loaded blob, starting empty if it is missing or corrupt.
+fn parse<T: DeserializeOwned + Default>(
+    store: &str,
+    loaded: io::Result<Option<Vec<u8>>>,
+) -> T {
+    match loaded {
+        Ok(Some(bytes)) => {
+            serde_json::from_slice(&bytes).unwrap_or_else(|err| {
+                warn!(store, %err, "corrupt store, starting empty");
+                T::default()
+            })
+        }
+        Ok(None) => T::default(),
+        Err(err) => {
+            error!(store, %err, "failed to load store, starting empty");
+            T::default()
+        }
+    }
+}
+
+/// State persisted as one blob named `name`.
+///
+/// Every change is written through to the backend. Without a backend the
+/// state only lives in memory.
+pub struct Store<T> {
+    name: &'static str,
+    backend: Option<Arc<dyn Backend>>,
+    data: Mutex<T>,
+}
+
+impl<T: Serialize + DeserializeOwned + Default> Store<T> {
+    /// Load `name` from `backend`, starting empty if absent.
+    pub fn open(backend: Option<Arc<dyn Backend>>, name: &'static str) -> Self {
+        let data = match &backend {
+            Some(backend) => parse(name, backend.load(name)),
+            None => T::default(),
+        };
+
+        Store {
+            name,
+            backend,
+            data: Mutex::new(data),
+        }
+    }
+
+    pub async fn lock(&self) -> MutexGuard<'_, T> {
+        self.data.lock().await
+    }
+
+    /// Write `data`, which must be this store's locked contents, through
+    /// to the backend.
+    pub async fn save(&self, data: &T) {
+        let Some(backend) = &self.backend else {
+            return;
+        };
+
+        let json = match serde_json::to_vec(data) {
+            Ok(json) => json,
+            Err(err) => {
+                error!(store = self.name, %err, "failed to serialize store");
+                return;
+            }
+        };
+
+        if let Err(err) = save(backend, self.name.to_string(), json).await {
+            error!(store = self.name, %err, "failed to save store");
+        }
+    }
+}
+
+/// State persisted per key as a blob named `<name>/<sha256 of key>`, each
+/// loaded on first use and dropped from memory by `evict`.
+///
+/// Every key has its own lock, so saving one key's state doesn't hold up
+/// anyone else's. Without a backend the state only lives in memory.
+pub struct KeyedStore<T> {
+    name: &'static str,
+    backend: Option<Arc<dyn Backend>>,
+    entries: Mutex<HashMap<String, Arc<Mutex<T>>>>,
+    /// Blob names of every key saved so far.
+    saved: Mutex<HashSet<String>>,
+}
+
+impl<T: Serialize + DeserializeOwned + Default> KeyedStore<T> {
+    pub fn open(backend: Option<Arc<dyn Backend>>, name: &'static str) -> Self {
+        let saved = match backend.as_ref().map(|b| b.list(name)) {
+            Some(Ok(saved)) => saved.into_iter().collect(),
+            Some(Err(err)) => {
+                error!(store = name, %err, "failed to list store");
+                HashSet::new()
+            }
+            None => HashSet::new(),
+        };
+
+        KeyedStore {
+            name,
+            backend,
+            entries: Mutex::default(),
+            saved: Mutex::new(saved),
+        }
+    }
+
+    fn file(key: &str) -> String {
+        hex::encode(Sha256::digest(key.as_bytes()))
+    }
+
+    fn blob(&self, key: &str) -> String {
+        format!("{}/{}", self.name, Self::file(key))
+    }
+
+    /// Whether state is written to a backend rather than only kept in
+    /// memory.
+    pub fn persistent(&self) -> bool {
+        self.backend.is_some()
+    }
+
+    /// How many keys have saved state.
+    pub async fn saved_keys(&self) -> usize {
+        self.saved.lock().await.len()
+    }
+
+    /// State of `key`, empty if it has none yet.
+    pub async fn entry(&self, key: &str) -> Arc<Mutex<T>> {
+        let mut entries = self.entries.lock().await;
+        if let Some(entry) = entries.get(key) {
+            return entry.clone();
+        }
+
+        let data = match &self.backend {
+            Some(backend) => {
+                let backend = backend.clone();
+                let blob = self.blob(key);
+                let loaded =
+                    tokio::task::spawn_blocking(move || backend.load(&blob))
+                        .await
+                        .unwrap_or_else(|err| Err(io::Error::other(err)));
+                parse(self.name, loaded)
+            }
+            None => T::default(),
+        };
+
+        let entry = Arc::new(Mutex::new(data));
+        entries.insert(key.to_string(), entry.clone());
+        entry
+    }
+
+    /// Write `data`, which must be the locked state of `key`, through to
+    /// the backend.
+    pub async fn save(&self, key: &str, data: &T) {
+        self.saved.lock().await.insert(Self::file(key));
+        let Some(backend) = &self.backend else {
+            return;
+        };
+
+        let result = match serde_json::to_vec(data) {
+            Ok(json) => save(backend, self.blob(key), json).await,
+            Err(err) => Err(err.into()),
+        };
+        if let Err(err) = result {
+            error!(store = self.name, %err, "failed to save entry");
+        }
+    }
+
+    /// Drop the state of `key` from memory unless someone still holds it.
+    /// Without a backend, saved state stays, as it has nowhere else to go.
+    pub async fn evict(&self, key: &str) {
+        let mut entries = self.entries.lock().await;
+        let idle = entries
+            .get(key)
+            .is_some_and(|entry| Arc::strong_count(entry) == 1);
+        let recoverable = self.backend.is_some()
+            || !self.saved.lock().await.contains(&Self::file(key));
+
+        if idle && recoverable {
+            entries.remove(key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod storage_tests {
+    use std::sync::Arc;
+
+    use crate::storage::{Backend, FileBackend, KeyedStore, MemoryBackend};
+
+    fn files(dir: &std::path::Path) -> Option<Arc<dyn Backend>> {
+        Some(Arc::new(FileBackend::new(dir)))
+    }
+
+    #[tokio::test]
+    async fn test_keyed_store_roundtrip() {
+        let dir = std::env::temp_dir()
+            .join(format!("rschat-storage-{}", std::process::id()));
+
+        let store = KeyedStore::<Vec<u32>>::open(files(&dir), "numbers");
+        let entry = store.entry("alice").await;
+        let mut numbers = entry.lock().await;
+        numbers.push(7);
+        store.save("alice", &numbers).await;
+        drop(numbers);
+
+        let reopened = KeyedStore::<Vec<u32>>::open(files(&dir), "numbers");
+        assert_eq!(*reopened.entry("alice").await.lock().await, [7]);
+        assert!(reopened.entry("bob").await.lock().await.is_empty());
+        assert_eq!(std::fs::read_dir(dir.join("numbers")).unwrap().count(), 1);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_keyed_store_evicts_idle_entries() {
+        let dir = std::env::temp_dir()
+            .join(format!("rschat-evict-{}", std::process::id()));
+
+        let store = KeyedStore::<Vec<u32>>::open(files(&dir), "numbers");
+        let entry = store.entry("alice").await;
+        entry.lock().await.push(7);
+        store.save("alice", &*entry.lock().await).await;
+
+        store.evict("alice").await;
+        assert_eq!(store.entries.lock().await.len(), 1, "still in use");
+        drop(entry);
+        store.evict("alice").await;
+        assert!(store.entries.lock().await.is_empty());
+        assert_eq!(*store.entry("alice").await.lock().await, [7]);
+        assert_eq!(store.saved_keys().await, 1);
+        std::fs::remove_dir_all(dir).unwrap();
+
+        // Only memory holds saved state without a data directory.
+        let store = KeyedStore::<Vec<u32>>::open(None, "numbers");
+        let entry = store.entry("alice").await;
+        entry.lock().await.push(7);
+        store.save("alice", &*entry.lock().await).await;
+        drop(entry);
+        store.entry("bob").await;
+        store.evict("alice").await;
+        store.evict("bob").await;
+        let entries = store.entries.lock().await;
+        assert!(entries.contains_key("alice") && !entries.contains_key("bob"));
+    }
+
+    #[tokio::test]
+    async fn test_memory_backend_outlives_store() {
+        let memory: Arc<dyn Backend> = Arc::new(MemoryBackend::default());
+
+        let store = KeyedStore::<Vec<u32>>::open(Some(memory.clone()), "n");
+        let entry = store.entry("alice").await;
+        entry.lock().await.push(7);
+        store.save("alice", &*entry.lock().await).await;
+        drop(entry);
+        // The backend holds it, so memory doesn't have to.
+        store.evict("alice").await;
+        assert!(store.entries.lock().await.is_empty());
+
+        let reopened = KeyedStore::<Vec<u32>>::open(Some(memory.clone()), "n");
+        assert_eq!(reopened.saved_keys().await, 1);
+        assert_eq!(*reopened.entry("alice").await.lock().await, [7]);
+        assert_eq!(memory.list("n").unwrap().len(), 1);
+        assert!(memory.list("other").unwrap().is_empty());
+    }
+}
@@ -0,0 +1,252 @@
+use std::collections::HashMap;
+
+use rschat_client::{Event, KeyPair, Message, Peer};
+use rschat_crypto::Ciphertext;
+use rschat_protocol::Payload;
+
+use crate::store::{ChatId, MessageStore, StoredMessage};
+
+/// A message we sent, stored once the server acknowledged it.
+struct Pending {
+    chat: ChatId,
+    text: String,
+}
+
+pub struct App {
+    pub keys: KeyPair,
+    pub name: String,
+    /// Users online, sorted by name.
+    pub peers: Vec<Peer>,
+    pub selected: ChatId,
+    pub store: MessageStore,
+    pub input: String,
+    /// Last notice, e.g. who joined or a refused request.
+    pub status: String,
+    pub quit: bool,
+    pending: HashMap<String, Pending>,
+}
+
+impl App {
+    pub fn new(keys: KeyPair, name: &str) -> Self {
+        App {
+            keys,
+            name: name.to_string(),
+            peers: Vec::new(),
+            selected: None,
+            store: MessageStore::default(),
+            input: String::new(),
+            status: String::new(),
+            quit: false,
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Chats in list order: the group chat, then a DM per peer.
+    pub fn chats(&self) -> Vec<ChatId> {
+        let dms = self.peers.iter().map(|p| Some(p.public_key.clone()));
+        std::iter::once(None).chain(dms).collect()
+    }
+
+    pub fn select(&mut self, chat: ChatId) {
+        self.store.mark_read(&chat);
+        self.selected = chat;
+    }
+
+    /// Select the chat `step` places down the list, wrapping around.
+    pub fn select_next(&mut self, step: isize) {
+        let chats = self.chats();
+        let at = chats.iter().position(|c| *c == self.selected).unwrap_or(0);
+        let next = (at as isize + step).rem_euclid(chats.len() as isize);
+        self.select(chats[next as usize].clone());
+    }
+
+    /// Name of the user with `public_key`, or the start of the key if
+    /// they are gone.
+    pub fn name_of(&self, public_key: &str) -> String {
+        if public_key == self.keys.public_key().to_hex() {
+            return self.name.clone();
+        }
+        match self.peers.iter().find(|p| p.public_key == public_key) {
+            Some(peer) => peer.name.clone(),
+            None => public_key.chars().take(10).collect(),
+        }
+    }
+
+    /// Remember `text` went out as `message_id`, to store it once the
+    /// server acknowledged it.
+    pub fn sent(&mut self, message_id: String, chat: ChatId, text: String) {
+        self.pending.insert(message_id, Pending { chat, text });
+    }
+
+    /// Apply an event from the server, `roster` being the client's roster
+    /// after it.
+    pub fn on_event(&mut self, event: Event, roster: Vec<Peer>) {
+        match event {
+            Event::UserJoined(peer) => {
+                if !self.peers.iter().any(|p| p.public_key == peer.public_key) {
+                    self.status = format!("{} joined the chat.", peer.name);
+                }
+                self.peers = roster;
+            }
+            Event::UserLeft { public_key } => {
+                self.status =
+                    format!("{} left the chat.", self.name_of(&public_key));
+                self.peers = roster;
+                if self.selected.as_deref() == Some(public_key.as_str()) {
+                    self.select(None);
+                }
+            }
+            Event::Message(msg) => self.on_message(msg),
+            Event::Error { request, message } => {
+                self.status = format!("{request} refused: {message}");
+            }
+            Event::Other(payload) => {
+                self.peers = roster;
+                self.on_payload(payload);
+            }
+        }
+    }
+
+    fn on_message(&mut self, msg: Message) {
+        // DMs are filed under the peer, like the web client does.
+        let chat = msg.direct.then(|| msg.sender.clone());
+        let mut stored = StoredMessage::new(
+            &self.name_of(&msg.sender),
+            &msg.sender,
+            &msg.text,
+            &msg.message_id,
+            msg.received_at,
+        );
+        stored.sealed = msg.sealed;
+        stored.is_unread = self.selected != chat;
+        self.store.append(&chat, stored);
+    }
+
+    fn on_payload(&mut self, payload: Payload) {
+        match payload {
+            // Group messages are acknowledged once per recipient.
+            Payload::MessageSent { envelope, .. } => {
+                let Some(sent) = self.pending.remove(&envelope.message_id)
+                else {
+                    return;
+                };
+                let me = self.keys.public_key().to_hex();
+                let stored = StoredMessage::new(
+                    &self.name,
+                    &me,
+                    &sent.text,
+                    &envelope.message_id,
+                    envelope.received_at,
+                );
+                self.store.append(&sent.chat, stored);
+            }
+            Payload::MessageEdited {
+                target_id, payload, ..
+            } => {
+                let Some(text) = self.decrypt(&payload) else {
+                    return;
+                };
+                self.store.update(&target_id, |m| {
+                    m.text = text;
+                    m.edited = true;
+                    true
+                });
+            }
+            Payload::MessageDeleted { target_id, .. } => {
+                self.store.update(&target_id, |_| false);
+            }
+            Payload::Reaction {
+                sender,
+                target_id,
+                payload,
+                ..
+            } => {
+                let emoji = self.decrypt(&payload).unwrap_or_default();
+                self.store.update(&target_id, |m| {
+                    if emoji.is_empty() {
+                        m.reactions.remove(&sender);
+                    } else {
+                        m.reactions.insert(sender, emoji);
+                    }
+                    true
+                });
+            }
+            _ => {}
+        }
+    }
+
+    fn decrypt(&self, ciphertext: &str) -> Option<String> {
+        let ciphertext = Ciphertext::from_hex(ciphertext).ok()?;
+        String::from_utf8(self.keys.decrypt(&ciphertext).ok()?).ok()
+    }
+}
+
+#[cfg(test)]
+mod app_tests {
+    use rschat_client::{Event, KeyPair, Message, Peer};
+    use rschat_protocol::PresenceStatus;
+
+    use crate::app::App;
+
+    fn peer(name: &str) -> Peer {
+        Peer {
+            public_key: KeyPair::generate().public_key().to_hex(),
+            name: name.to_string(),
+            handle: None,
+            status: PresenceStatus::Online,
+        }
+    }
+
+    fn message(sender: &Peer, id: &str, direct: bool) -> Event {
+        Event::Message(Message {
+            sender: sender.public_key.clone(),
+            text: "hi".to_string(),
+            direct,
+            sealed: false,
+            message_id: id.to_string(),
+            received_at: 1,
+            sent_at: None,
+        })
+    }
+
+    #[test]
+    fn test_unread_markers() {
+        let mut app = App::new(KeyPair::generate(), "me");
+        let bob = peer("bob");
+        let dm = Some(bob.public_key.clone());
+        app.on_event(Event::UserJoined(bob.clone()), vec![bob.clone()]);
+        assert_eq!(app.status, "bob joined the chat.");
+
+        // The group chat is open, so only the DM is unread.
+        app.on_event(message(&bob, "1", false), vec![bob.clone()]);
+        app.on_event(message(&bob, "2", true), vec![bob.clone()]);
+        assert!(!app.store.has_unread(&None));
+        assert!(app.store.has_unread(&dm));
+        assert_eq!(app.store.messages(&dm)[0].sender, "bob");
+
+        app.select_next(1);
+        assert_eq!(app.selected, dm);
+        assert!(!app.store.has_unread(&dm));
+
+        app.on_event(message(&bob, "3", false), vec![bob.clone()]);
+        assert!(app.store.has_unread(&None));
+    }
+
+    #[test]
+    fn test_peer_leaving_closes_dm() {
+        let mut app = App::new(KeyPair::generate(), "me");
+        let bob = peer("bob");
+        app.on_event(Event::UserJoined(bob.clone()), vec![bob.clone()]);
+        app.select(Some(bob.public_key.clone()));
+
+        app.on_event(
+            Event::UserLeft {
+                public_key: bob.public_key.clone(),
+            },
+            vec![],
+        );
+        assert_eq!(app.selected, None);
+        assert_eq!(app.status, "bob left the chat.");
+        assert_eq!(app.chats(), vec![None]);
+    }
+}
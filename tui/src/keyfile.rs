@@ -0,0 +1,206 @@
+//! The identity on disk, its private key encrypted under a passphrase.
+
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::{fmt, str};
+
+use aes_gcm::aead::{Aead, KeyInit};
+use aes_gcm::{Aes256Gcm, Nonce};
+use rschat_crypto::{KeyPair, NONCE_LEN};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+const VERSION: u8 = 1;
+
+/// PBKDF2 rounds for new keyfiles, as OWASP advises for HMAC-SHA256.
+pub const ROUNDS: u32 = 600_000;
+
+#[derive(Debug)]
+pub enum KeyfileError {
+    Io(io::Error),
+    Invalid,
+    UnsupportedVersion,
+    /// Wrong passphrase, or the file was tampered with.
+    WrongPassphrase,
+}
+
+impl fmt::Display for KeyfileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyfileError::Io(err) => write!(f, "{err}"),
+            KeyfileError::Invalid => f.write_str("not a keyfile"),
+            KeyfileError::UnsupportedVersion => {
+                f.write_str("unsupported keyfile version")
+            }
+            KeyfileError::WrongPassphrase => f.write_str("wrong passphrase"),
+        }
+    }
+}
+
+impl From<io::Error> for KeyfileError {
+    fn from(err: io::Error) -> Self {
+        KeyfileError::Io(err)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Keyfile {
+    version: u8,
+    pub name: String,
+    /// Kept across runs, so a new connection replaces the old one.
+    pub device_id: String,
+    pub public_key: String,
+    rounds: u32,
+    salt: String,
+    nonce: String,
+    /// AES-256-GCM of the private key under the passphrase's PBKDF2 key.
+    private_key: String,
+}
+
+impl Keyfile {
+    pub fn seal(
+        name: &str,
+        device_id: &str,
+        keys: &KeyPair,
+        passphrase: &str,
+        rounds: u32,
+    ) -> Self {
+        let salt: [u8; 16] = random();
+        let nonce: [u8; NONCE_LEN] = random();
+        let private_key = cipher(passphrase, &salt, rounds)
+            .encrypt(Nonce::from_slice(&nonce), &keys.secret_bytes()[..])
+            .expect("a private key fits AES-GCM");
+
+        Keyfile {
+            version: VERSION,
+            name: name.to_string(),
+            device_id: device_id.to_string(),
+            public_key: keys.public_key().to_hex(),
+            rounds,
+            salt: hex::encode(salt),
+            nonce: hex::encode(nonce),
+            private_key: hex::encode(private_key),
+        }
+    }
+
+    pub fn open(&self, passphrase: &str) -> Result<KeyPair, KeyfileError> {
+        if self.version != VERSION {
+            return Err(KeyfileError::UnsupportedVersion);
+        }
+
+        let salt =
+            hex::decode(&self.salt).map_err(|_| KeyfileError::Invalid)?;
+        let nonce = hex::decode(&self.nonce)
+            .ok()
+            .filter(|n| n.len() == NONCE_LEN)
+            .ok_or(KeyfileError::Invalid)?;
+        let private_key = hex::decode(&self.private_key)
+            .map_err(|_| KeyfileError::Invalid)?;
+
+        let secret = cipher(passphrase, &salt, self.rounds)
+            .decrypt(Nonce::from_slice(&nonce), &*private_key)
+            .map_err(|_| KeyfileError::WrongPassphrase)?;
+        let keys = KeyPair::from_secret_bytes(&secret)
+            .map_err(|_| KeyfileError::Invalid)?;
+
+        if keys.public_key().to_hex() != self.public_key {
+            return Err(KeyfileError::Invalid);
+        }
+        Ok(keys)
+    }
+
+    pub fn load(path: &Path) -> Result<Self, KeyfileError> {
+        let json = fs::read(path)?;
+        serde_json::from_slice(&json).map_err(|_| KeyfileError::Invalid)
+    }
+
+    /// Write the keyfile, readable by the owner only.
+    pub fn save(&self, path: &Path) -> Result<(), KeyfileError> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+        let json = serde_json::to_vec_pretty(self).map_err(io::Error::from)?;
+        options.open(path)?.write_all(&json)?;
+        Ok(())
+    }
+}
+
+fn cipher(passphrase: &str, salt: &[u8], rounds: u32) -> Aes256Gcm {
+    let mut key = [0u8; 32];
+    pbkdf2::pbkdf2_hmac::<Sha256>(
+        passphrase.as_bytes(),
+        salt,
+        rounds,
+        &mut key,
+    );
+    Aes256Gcm::new(&key.into())
+}
+
+pub fn random<const N: usize>() -> [u8; N] {
+    let mut bytes = [0u8; N];
+    getrandom::getrandom(&mut bytes).expect("no randomness available");
+    bytes
+}
+
+#[cfg(test)]
+mod keyfile_tests {
+    use rschat_crypto::KeyPair;
+
+    use crate::keyfile::{Keyfile, KeyfileError};
+
+    #[test]
+    fn test_keyfile_round_trip() {
+        let keys = KeyPair::generate();
+        let keyfile = Keyfile::seal("alice", "laptop", &keys, "hunter2", 10);
+
+        let json = serde_json::to_string(&keyfile).unwrap();
+        assert!(!json.contains(&keys.secret_hex()));
+
+        let keyfile: Keyfile = serde_json::from_str(&json).unwrap();
+        assert_eq!(keyfile.name, "alice");
+        assert_eq!(keyfile.device_id, "laptop");
+        let opened = keyfile.open("hunter2").unwrap();
+        assert_eq!(opened.public_key(), keys.public_key());
+
+        assert!(matches!(
+            keyfile.open("hunter3"),
+            Err(KeyfileError::WrongPassphrase)
+        ));
+    }
+
+    #[test]
+    fn test_keyfile_save_load() {
+        let dir = std::env::temp_dir().join(format!(
+            "rschat-keyfile-{}",
+            hex::encode(super::random::<8>())
+        ));
+        let path = dir.join("keyfile.json");
+
+        let keys = KeyPair::generate();
+        Keyfile::seal("bob", "tty", &keys, "pw", 10)
+            .save(&path)
+            .unwrap();
+        let loaded = Keyfile::load(&path).unwrap();
+        assert_eq!(loaded.open("pw").unwrap().public_key(), keys.public_key());
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        // Never overwrite an identity.
+        let other = Keyfile::seal("bob", "tty", &keys, "pw", 10);
+        assert!(matches!(other.save(&path), Err(KeyfileError::Io(_))));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
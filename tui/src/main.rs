@@ -0,0 +1,193 @@
+//! Terminal chat client. Reads `RSCHAT_SERVER` (`127.0.0.1:3333` by
+//! default) and the identity in `RSCHAT_KEYFILE`
+//! (`~/.config/rschat/keyfile.json` by default), creating it on first run.
+//! The passphrase is prompted for unless `RSCHAT_PASSPHRASE` is set.
+
+mod app;
+mod keyfile;
+mod store;
+mod ui;
+
+use std::env;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::process::exit;
+
+use crossterm::event::{
+    self, Event as TermEvent, KeyCode, KeyEventKind, KeyModifiers,
+};
+use rschat_client::{Client, Events, KeyPair};
+use tokio::sync::mpsc;
+
+use crate::app::App;
+use crate::keyfile::{Keyfile, ROUNDS};
+
+fn keyfile_path() -> PathBuf {
+    if let Ok(path) = env::var("RSCHAT_KEYFILE") {
+        return PathBuf::from(path);
+    }
+    let home = env::var("HOME").unwrap_or_else(|_| ".".to_string());
+    PathBuf::from(home).join(".config/rschat/keyfile.json")
+}
+
+fn read_passphrase(question: &str) -> io::Result<String> {
+    match env::var("RSCHAT_PASSPHRASE") {
+        Ok(passphrase) => Ok(passphrase),
+        Err(_) => rpassword::prompt_password(question),
+    }
+}
+
+fn prompt(question: &str) -> io::Result<String> {
+    print!("{question}");
+    io::stdout().flush()?;
+    let mut line = String::new();
+    io::stdin().lock().read_line(&mut line)?;
+    Ok(line.trim().to_string())
+}
+
+/// Open the keyfile at `path`, or make a new identity there.
+fn identity(path: &Path) -> Result<(Keyfile, KeyPair), String> {
+    if path.exists() {
+        let keyfile = Keyfile::load(path).map_err(|e| e.to_string())?;
+        let passphrase =
+            read_passphrase("Passphrase: ").map_err(|e| e.to_string())?;
+        let keys = keyfile.open(&passphrase).map_err(|e| e.to_string())?;
+        return Ok((keyfile, keys));
+    }
+
+    println!("No identity in {}, creating one.", path.display());
+    let name = prompt("Name: ").map_err(|e| e.to_string())?;
+    let passphrase =
+        read_passphrase("New passphrase: ").map_err(|e| e.to_string())?;
+    let again =
+        read_passphrase("Repeat passphrase: ").map_err(|e| e.to_string())?;
+    if passphrase != again {
+        return Err("passphrases differ".to_string());
+    }
+
+    let keys = KeyPair::generate();
+    let device_id = hex::encode(keyfile::random::<16>());
+    let keyfile = Keyfile::seal(&name, &device_id, &keys, &passphrase, ROUNDS);
+    keyfile.save(path).map_err(|e| e.to_string())?;
+    Ok((keyfile, keys))
+}
+
+#[tokio::main]
+async fn main() {
+    let path = keyfile_path();
+    let (keyfile, keys) = identity(&path).unwrap_or_else(|err| {
+        eprintln!("{}: {err}", path.display());
+        exit(1);
+    });
+
+    let addr = env::var("RSCHAT_SERVER")
+        .unwrap_or_else(|_| "127.0.0.1:3333".to_string());
+    let connected = Client::builder()
+        .keys(keys.clone())
+        .name(&keyfile.name)
+        .device_id(&keyfile.device_id)
+        .connect(&addr)
+        .await;
+    let (client, events) = connected.unwrap_or_else(|err| {
+        eprintln!("{addr}: {err}");
+        exit(1);
+    });
+
+    let mut terminal = ratatui::init();
+    let result = run(
+        &mut terminal,
+        App::new(keys, &keyfile.name),
+        &client,
+        events,
+    )
+    .await;
+    ratatui::restore();
+    let _ = client.close().await;
+
+    if let Err(err) = result {
+        eprintln!("{err}");
+        exit(1);
+    }
+}
+
+async fn run(
+    terminal: &mut ratatui::DefaultTerminal,
+    mut app: App,
+    client: &Client,
+    mut events: Events,
+) -> io::Result<()> {
+    // crossterm reads block, so they get a thread of their own.
+    let (keys_tx, mut keys) = mpsc::unbounded_channel();
+    std::thread::spawn(move || {
+        while let Ok(event) = event::read() {
+            if keys_tx.send(event).is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut connected = true;
+    while !app.quit {
+        terminal.draw(|frame| ui::draw(frame, &app))?;
+
+        tokio::select! {
+            Some(event) = keys.recv() => on_key(&mut app, client, event).await,
+            event = events.next(), if connected => match event {
+                Some(event) => app.on_event(event, client.roster()),
+                None => {
+                    connected = false;
+                    app.status = "Disconnected from the server.".to_string();
+                }
+            },
+        }
+    }
+    Ok(())
+}
+
+async fn on_key(app: &mut App, client: &Client, event: TermEvent) {
+    let TermEvent::Key(key) = event else {
+        return;
+    };
+    if key.kind != KeyEventKind::Press {
+        return;
+    }
+
+    match key.code {
+        KeyCode::Esc => app.quit = true,
+        KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+            app.quit = true
+        }
+        KeyCode::Tab => app.select_next(1),
+        KeyCode::BackTab => app.select_next(-1),
+        KeyCode::Backspace => {
+            app.input.pop();
+        }
+        KeyCode::Char(c) => app.input.push(c),
+        KeyCode::Enter => send(app, client).await,
+        _ => {}
+    }
+}
+
+async fn send(app: &mut App, client: &Client) {
+    let text = app.input.trim().to_string();
+    if text.is_empty() {
+        return;
+    }
+    if app.selected.is_none() && app.peers.is_empty() {
+        app.status = "Nobody else is here yet.".to_string();
+        return;
+    }
+
+    let chat = app.selected.clone();
+    let sent = match &chat {
+        Some(peer) => client.send_direct(peer, &text).await,
+        None => client.send_group(&text).await,
+    };
+    match sent {
+        Ok(message_id) => {
+            app.sent(message_id, chat, text);
+            app.input.clear();
+        }
+        Err(err) => app.status = format!("Not sent: {err}"),
+    }
+}
@@ -0,0 +1,186 @@
+//! Messages by chat, kept the way the web client's `MessageStore` keeps
+//! them, so both show the same conversation.
+
+use std::collections::{BTreeMap, HashMap};
+
+/// Messages kept per chat, the oldest dropped first.
+pub const MAX_MESSAGES: usize = 100;
+
+/// The group chat, or the DM with a peer's public key.
+pub type ChatId = Option<String>;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct StoredMessage {
+    /// Name of the sender when the message arrived.
+    pub sender: String,
+    pub sender_key: String,
+    pub text: String,
+    /// Server message id, the same on every device.
+    pub message_id: String,
+    /// When the server received it, in ms since the epoch.
+    pub timestamp: u64,
+    pub sealed: bool,
+    pub is_unread: bool,
+    pub edited: bool,
+    /// Emoji of each reacting user, by public key.
+    pub reactions: BTreeMap<String, String>,
+}
+
+impl StoredMessage {
+    pub fn new(
+        sender: &str,
+        sender_key: &str,
+        text: &str,
+        message_id: &str,
+        timestamp: u64,
+    ) -> Self {
+        StoredMessage {
+            sender: sender.to_string(),
+            sender_key: sender_key.to_string(),
+            text: text.to_string(),
+            message_id: message_id.to_string(),
+            timestamp,
+            sealed: false,
+            is_unread: false,
+            edited: false,
+            reactions: BTreeMap::new(),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct MessageStore {
+    chats: HashMap<ChatId, Vec<StoredMessage>>,
+}
+
+impl MessageStore {
+    /// Add `message` to `chat` in server time order, then message id so
+    /// every peer gets the same order. Returns false if a message with
+    /// the same id is stored already.
+    pub fn append(&mut self, chat: &ChatId, message: StoredMessage) -> bool {
+        let messages = self.chats.entry(chat.clone()).or_default();
+        if messages.iter().any(|m| m.message_id == message.message_id) {
+            return false;
+        }
+
+        if messages.len() >= MAX_MESSAGES {
+            messages.remove(0);
+        }
+
+        let at = messages.partition_point(|m| {
+            (m.timestamp, &m.message_id)
+                <= (message.timestamp, &message.message_id)
+        });
+        messages.insert(at, message);
+        true
+    }
+
+    /// Apply `change` to the message with this id, deleting it if
+    /// `change` returns false. Returns false if there is no such message.
+    pub fn update(
+        &mut self,
+        message_id: &str,
+        change: impl FnOnce(&mut StoredMessage) -> bool,
+    ) -> bool {
+        for messages in self.chats.values_mut() {
+            if let Some(i) =
+                messages.iter().position(|m| m.message_id == message_id)
+            {
+                if !change(&mut messages[i]) {
+                    messages.remove(i);
+                }
+                return true;
+            }
+        }
+        false
+    }
+
+    pub fn messages(&self, chat: &ChatId) -> &[StoredMessage] {
+        self.chats.get(chat).map(Vec::as_slice).unwrap_or_default()
+    }
+
+    pub fn mark_read(&mut self, chat: &ChatId) {
+        if let Some(messages) = self.chats.get_mut(chat) {
+            messages.iter_mut().for_each(|m| m.is_unread = false);
+        }
+    }
+
+    pub fn has_unread(&self, chat: &ChatId) -> bool {
+        self.messages(chat).iter().any(|m| m.is_unread)
+    }
+}
+
+#[cfg(test)]
+mod store_tests {
+    use crate::store::{MAX_MESSAGES, MessageStore, StoredMessage};
+
+    fn message(id: &str, timestamp: u64) -> StoredMessage {
+        StoredMessage::new("alice", "04aa", id, id, timestamp)
+    }
+
+    #[test]
+    fn test_append_orders_and_dedups() {
+        let mut store = MessageStore::default();
+        let dm = Some("04bb".to_string());
+
+        assert!(store.append(&None, message("b", 2)));
+        assert!(store.append(&None, message("c", 1)));
+        assert!(store.append(&None, message("a", 2)));
+        assert!(!store.append(&None, message("a", 5)));
+        assert!(store.append(&dm, message("d", 0)));
+
+        let ids: Vec<&str> = store
+            .messages(&None)
+            .iter()
+            .map(|m| m.message_id.as_str())
+            .collect();
+        assert_eq!(ids, ["c", "a", "b"]);
+        assert_eq!(store.messages(&dm).len(), 1);
+    }
+
+    #[test]
+    fn test_append_drops_oldest() {
+        let mut store = MessageStore::default();
+        for i in 0..=MAX_MESSAGES as u64 {
+            store.append(&None, message(&format!("{i:03}"), i));
+        }
+
+        let messages = store.messages(&None);
+        assert_eq!(messages.len(), MAX_MESSAGES);
+        assert_eq!(messages[0].message_id, "001");
+    }
+
+    #[test]
+    fn test_unread() {
+        let mut store = MessageStore::default();
+        let dm = Some("04bb".to_string());
+
+        store.append(&None, message("a", 1));
+        let mut unread = message("b", 2);
+        unread.is_unread = true;
+        store.append(&dm, unread);
+
+        assert!(!store.has_unread(&None));
+        assert!(store.has_unread(&dm));
+        store.mark_read(&dm);
+        assert!(!store.has_unread(&dm));
+    }
+
+    #[test]
+    fn test_update() {
+        let mut store = MessageStore::default();
+        store.append(&None, message("a", 1));
+        store.append(&None, message("b", 2));
+
+        assert!(store.update("a", |m| {
+            m.text = "edited".to_string();
+            m.edited = true;
+            true
+        }));
+        assert!(store.messages(&None)[0].edited);
+
+        assert!(store.update("b", |_| false));
+        assert_eq!(store.messages(&None).len(), 1);
+        assert!(!store.update("b", |_| true));
+    }
+}
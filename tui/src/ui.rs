@@ -0,0 +1,149 @@
+use ratatui::Frame;
+use ratatui::layout::{Constraint, Layout, Position};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, List, ListItem, ListState, Paragraph, Wrap};
+use rschat_protocol::PresenceStatus;
+
+use crate::app::App;
+use crate::store::StoredMessage;
+
+pub fn draw(frame: &mut Frame, app: &App) {
+    let [sidebar, main] =
+        Layout::horizontal([Constraint::Length(28), Constraint::Min(20)])
+            .areas(frame.area());
+    let [messages, input, status] = Layout::vertical([
+        Constraint::Min(3),
+        Constraint::Length(3),
+        Constraint::Length(1),
+    ])
+    .areas(main);
+
+    draw_chats(frame, app, sidebar);
+    draw_messages(frame, app, messages);
+
+    frame.render_widget(
+        Paragraph::new(app.input.as_str()).block(
+            Block::bordered()
+                .title("Enter to send, Tab to switch chats, Esc to quit"),
+        ),
+        input,
+    );
+    frame.set_cursor_position(Position::new(
+        input.x + 1 + app.input.chars().count() as u16,
+        input.y + 1,
+    ));
+
+    frame.render_widget(
+        Paragraph::new(app.status.as_str())
+            .style(Style::default().fg(Color::DarkGray)),
+        status,
+    );
+}
+
+fn draw_chats(frame: &mut Frame, app: &App, area: ratatui::layout::Rect) {
+    let chats = app.chats();
+    let items: Vec<ListItem> = chats
+        .iter()
+        .map(|chat| {
+            let unread = if app.store.has_unread(chat) {
+                "● "
+            } else {
+                "  "
+            };
+            let mut spans =
+                vec![Span::styled(unread, Style::default().fg(Color::Red))];
+
+            match chat {
+                None => spans.push(Span::raw("# Group chat")),
+                Some(key) => {
+                    let peer = app.peers.iter().find(|p| &p.public_key == key);
+                    let (name, handle, status) = match peer {
+                        Some(p) => {
+                            (p.name.as_str(), p.handle.as_deref(), p.status)
+                        }
+                        None => (key.as_str(), None, PresenceStatus::Online),
+                    };
+                    let color = match status {
+                        PresenceStatus::Online => Color::Green,
+                        PresenceStatus::Away => Color::Yellow,
+                        PresenceStatus::Busy => Color::Red,
+                    };
+                    spans.push(Span::styled("• ", Style::default().fg(color)));
+                    spans.push(Span::raw(name.to_string()));
+                    if let Some(handle) = handle {
+                        spans.push(Span::styled(
+                            format!(" @{handle}"),
+                            Style::default().fg(Color::DarkGray),
+                        ));
+                    }
+                }
+            }
+            ListItem::new(Line::from(spans))
+        })
+        .collect();
+
+    let mut state = ListState::default()
+        .with_selected(chats.iter().position(|c| *c == app.selected));
+    frame.render_stateful_widget(
+        List::new(items)
+            .block(Block::bordered().title(format!("Chats — {}", app.name)))
+            .highlight_style(Style::default().add_modifier(Modifier::REVERSED)),
+        area,
+        &mut state,
+    );
+}
+
+fn draw_messages(frame: &mut Frame, app: &App, area: ratatui::layout::Rect) {
+    let title = match &app.selected {
+        None => "Group chat".to_string(),
+        Some(key) => app.name_of(key),
+    };
+
+    let lines: Vec<Line> =
+        app.store.messages(&app.selected).iter().map(line).collect();
+
+    // Keep the newest messages in view.
+    let height = area.height.saturating_sub(2) as usize;
+    let scroll = lines.len().saturating_sub(height) as u16;
+
+    frame.render_widget(
+        Paragraph::new(lines)
+            .block(Block::bordered().title(title))
+            .wrap(Wrap { trim: false })
+            .scroll((scroll, 0)),
+        area,
+    );
+}
+
+fn line(message: &StoredMessage) -> Line<'_> {
+    let mut spans = vec![
+        Span::styled(
+            format!("{} ", clock(message.timestamp)),
+            Style::default().fg(Color::DarkGray),
+        ),
+        Span::styled(
+            format!("{}: ", message.sender),
+            Style::default().add_modifier(Modifier::BOLD),
+        ),
+        Span::raw(message.text.as_str()),
+    ];
+    if message.edited {
+        spans.push(Span::styled(
+            " (edited)",
+            Style::default().fg(Color::DarkGray),
+        ));
+    }
+    if !message.reactions.is_empty() {
+        let emoji: String =
+            message.reactions.values().map(String::as_str).collect();
+        spans.push(Span::raw(format!(" {emoji}")));
+    }
+    Line::from(spans)
+}
+
+/// `HH:MM` in UTC of a time in ms since the epoch.
+fn clock(ms: u64) -> String {
+    let minutes = ms / 60_000 % (24 * 60);
+    format!("{:02}:{:02}", minutes / 60, minutes % 60)
+}